@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use crate::database::backend::{blocking, Backend};
+use crate::database::registry::{Capability, Datasets};
+use crate::models::cohort::{Cohort, SetOp};
+use mongodb::bson::{doc, Document};
+use rocket::{http::{uri::Origin, Status}, serde::json::Json, State};
+
+// the saved cohorts of a dataset, with their size instead of their subjects
+#[get("/datasets/<dataset>/cohorts")]
+pub async fn list_cohorts(db: &State<Datasets>, dataset: &str) -> Result<Json<Vec<Document>>, Status> {
+    let backend = db.backend(dataset, Capability::Cohorts)?;
+    let dataset = dataset.to_string();
+    let cohorts = blocking(move || Ok(backend.cohorts(&dataset)?)).await?;
+    Ok(Json(cohorts.iter().map(|cohort| cohort.summary(false)).collect()))
+}
+
+#[get("/datasets/<dataset>/cohorts/<name>")]
+pub async fn get_cohort(db: &State<Datasets>, dataset: &str, name: &str) -> Result<Json<Document>, Status> {
+    let backend = db.backend(dataset, Capability::Cohorts)?;
+    let (dataset, name) = (dataset.to_string(), name.to_string());
+    match blocking(move || Ok(backend.cohort(&dataset, &name)?)).await? {
+        Some(cohort) => Ok(Json(cohort.summary(true))),
+        None => Err(Status::NotFound),
+    }
+}
+
+#[delete("/datasets/<dataset>/cohorts/<name>")]
+pub async fn delete_cohort(db: &State<Datasets>, dataset: &str, name: &str) -> Result<Json<Document>, Status> {
+    let backend = db.backend(dataset, Capability::Cohorts)?;
+    let (dataset, deleted) = (dataset.to_string(), name.to_string());
+    if blocking(move || Ok(backend.delete_cohort(&dataset, &deleted)?)).await? {
+        Ok(Json(doc! {"deleted": name}))
+    } else {
+        Err(Status::NotFound)
+    }
+}
+
+// a new cohort of an operation over saved cohorts: union, intersection, difference (the first
+// without the others) or symmetric_difference, over two or more comma separated cohorts
+#[post("/datasets/<dataset>/cohorts/<name>?<operation>&<cohorts>")]
+pub async fn derive_cohort(db: &State<Datasets>, dataset: &str, name: &str, operation: &str, cohorts: &str, uri: &Origin<'_>) -> Result<Json<Document>, Status> {
+    let Some(operation) = SetOp::parse(operation) else {
+        println!("Error: unknown cohort operation {}", operation);
+        return Err(Status::BadRequest);
+    };
+    let inputs: Vec<String> = cohorts.split(',').map(str::trim).filter(|input| !input.is_empty()).map(str::to_string).collect();
+    if inputs.len() < 2 || name.trim().is_empty() {
+        println!("Error: a cohort operation takes a name and two or more cohorts");
+        return Err(Status::BadRequest);
+    }
+    let backend = db.backend(dataset, Capability::Cohorts)?;
+    let (name, dataset, query) = (name.to_string(), dataset.to_string(), uri.to_string());
+    let cohort = blocking(move || {
+        let mut cohorts = Vec::new();
+        for input in &inputs {
+            match backend.cohort(&dataset, input)? {
+                Some(cohort) => cohorts.push(cohort),
+                None => {
+                    println!("Error: no cohort named {}", input);
+                    return Err(Status::NotFound);
+                }
+            }
+        }
+        let cohort = Cohort::derive(&name, &dataset, &query, operation, &cohorts);
+        backend.save_cohort(&cohort)?;
+        Ok(cohort)
+    }).await?;
+    Ok(Json(cohort.summary(false)))
+}
+
+// The cohort and save parameters of a query: the saved cohort its subjects are restricted to,
+// and the name its resulting subjects are saved under, the request being the defining query.
+// Both are looked up on the blocking pool with the query, see subjects and save.
+pub struct CohortParams {
+    backend: Option<Arc<dyn Backend>>,
+    cohort: Option<String>,
+    save: Option<String>,
+    dataset: String,
+    query: String,
+}
+
+impl CohortParams {
+    pub fn new(db: &Datasets, dataset: &str, cohort: Option<&str>, save: Option<&str>, uri: &Origin<'_>) -> Result<Self, Status> {
+        if save.is_some_and(|name| name.trim().is_empty()) {
+            println!("Error: empty cohort name");
+            return Err(Status::BadRequest);
+        }
+        let backend = match cohort.or(save) {
+            Some(_) => Some(db.backend(dataset, Capability::Cohorts)?),
+            None => None,
+        };
+        Ok(CohortParams {
+            backend,
+            cohort: cohort.map(str::to_string),
+            save: save.map(str::to_string),
+            dataset: dataset.to_string(),
+            query: uri.to_string(),
+        })
+    }
+
+    // neither parameter, for queries run outside the routes
+    pub fn none() -> Self {
+        CohortParams { backend: None, cohort: None, save: None, dataset: String::new(), query: String::new() }
+    }
+
+    // no cohort to restrict to nor to save
+    pub fn is_none(&self) -> bool {
+        self.backend.is_none()
+    }
+
+    pub fn saving(&self) -> bool {
+        self.save.is_some()
+    }
+
+    // the subjects of the cohort, 404 without it; 409 before running the query if the name to
+    // save under is taken
+    pub fn subjects(&self) -> Result<Option<Vec<String>>, Status> {
+        let Some(backend) = &self.backend else {
+            return Ok(None);
+        };
+        if let Some(name) = &self.save {
+            if backend.cohort(&self.dataset, name)?.is_some() {
+                println!("Error: a cohort named {} exists", name);
+                return Err(Status::Conflict);
+            }
+        }
+        match &self.cohort {
+            Some(name) => match backend.cohort(&self.dataset, name)? {
+                Some(cohort) => Ok(Some(cohort.subjects)),
+                None => {
+                    println!("Error: no cohort named {}", name);
+                    Err(Status::NotFound)
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn save(&self, subjects: Vec<String>) -> Result<(), Status> {
+        if let (Some(backend), Some(name)) = (&self.backend, &self.save) {
+            backend.save_cohort(&Cohort::new(name, &self.dataset, &self.query, subjects))?;
+        }
+        Ok(())
+    }
+}
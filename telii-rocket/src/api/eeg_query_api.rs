@@ -1,446 +1,542 @@
-use std::{result, vec};
-
-use crate::{models::event::Event, database::mongodb::EegMongoRepo};
-use mongodb::{bson::{doc, Document,Bson}, Collection, results::{self, InsertOneResult}};
-use rocket::{http::Status, serde::json::{self, Json}, State};
+use crate::api::cohort_api::CohortParams;
+use crate::database::backend::{blocking, Backend};
+use crate::database::registry::{Capability, Datasets, EEG_DATASET};
+use crate::database::pipeline::{construct_query_cond, construct_tel_cond};
+use crate::tel::allen::{AllenRelation, Anchor, GapBound, GapConstraint, encode_pattern};
+use crate::tel::exp::TelExp;
+use crate::tel::parser::{parse_formula, parse_query, parse_duration};
+use crate::tel::query::{Absence, TelQuery};
+use crate::tel::eval::{evaluate, evaluate_query, TelMatch};
+use mongodb::bson::{doc, DateTime, Document, Bson};
+use rocket::{http::{uri::Origin, Status}, serde::json::Json, State};
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::collections::BTreeSet;
 use maplit::hashmap;
 
-#[derive(Clone)]
-pub struct TelExp {
-	pub operator: String,
-	pub t: String,
-	pub event: String,
-	pub events: Vec<String>,
-	pub delta: f32,
-	pub s: String,
-	pub e: String,
-}
-
-impl TelExp {
-	// Constructor
-	pub fn init(operator: &str, t: &str, event: &str, events: Option<Vec<&str>>, delta: Option<f32>, s: Option<&str>, e: Option<&str> ) -> Self {
-		let events = events.unwrap_or(vec![]).iter().map(|&s| s.to_string()).collect();
-		let delta = delta.unwrap_or(0.0);
-		let s = s.unwrap_or("");
-		let e = e.unwrap_or("");
-		TelExp {
-			operator: operator.to_string(),
-			t: t.to_string(),
-			event: event.to_string(),
-			events: events,
-			delta: delta,
-			s: s.to_string(),
-			e: e.to_string(),
-		}
-	}
+// explain: return the pipeline and mongodb's query plan instead of running the query
+// cohort: only the subjects of this saved cohort, save: save the subjects found as a cohort
+#[get("/eeg_allen_query?<relation>&<event_id_list1>&<event_id_list2>&<explain>&<cohort>&<save>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn eeg_allen_query(db: &State<Datasets>, relation: &str, event_id_list1: &str, event_id_list2: &str, explain: Option<bool>, cohort: Option<&str>, save: Option<&str>, uri: &Origin<'_>) -> Result<Json<Document>, Status> {
+	dataset_eeg_allen_query(db, EEG_DATASET, relation, event_id_list1, event_id_list2, explain, cohort, save, uri).await
+}
 
-	// Method
-	pub fn print(&self) {
-			println!("operator: {}, t: {}, event: {}, events: {:?}, delta: {}, s: {}, e: {}", self.operator, self.t, self.event, self.events, self.delta, self.s, self.e);
-	}
-	pub fn latex(&self) -> String {
-		let exp = self.operator.split("_").collect::<Vec<&str>>();
-		let mut op_str = "";
-			if exp[0] == "box" {
-				op_str = " \\Box ";
-			} else if exp[0] == "diamond" {
-				op_str = " \\Diamond ";
-			}
-			let mut t_str = self.t.clone();
-			if self.delta != 0.0 {
-				t_str = format!("{}+{}", self.t, self.delta);
-			}
+#[get("/datasets/<dataset>/eeg_allen_query?<relation>&<event_id_list1>&<event_id_list2>&<explain>&<cohort>&<save>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn dataset_eeg_allen_query(db: &State<Datasets>, dataset: &str, relation: &str, event_id_list1: &str, event_id_list2: &str, explain: Option<bool>, cohort: Option<&str>, save: Option<&str>, uri: &Origin<'_>) -> Result<Json<Document>, Status> {
+	let cohorts = CohortParams::new(db, dataset, cohort, save, uri)?;
+	run_allen_query(db, dataset, relation, event_id_list1, event_id_list2, explain, cohorts).await
+}
 
-			let mut latex_str2 = String::new();
-			if exp[1] == "t"{
-				if exp.contains(&"neg"){
-					latex_str2 = format!("{}_{{{}}} \\neg {}", op_str, t_str, self.event);
-				}else{
-					latex_str2 = format!("{}_{{{}}} {}", op_str, t_str, self.event);
-				}
-
-			}else if exp.last().map(|&s| s) == Some("t"){
-				if exp.contains(&"neg"){
-					latex_str2 = format!("({} \\neg {})_{{{}}}", op_str, self.event, t_str);
-				}else{
-					latex_str2 = format!("({} {})_{{{}}}", op_str, self.event, t_str);
-				}
-			}
-			latex_str2
-		}
+// eeg_allen_query on a dataset, also run by the query pages
+pub async fn run_allen_query(db: &Datasets, dataset: &str, relation: &str, event_id_list1: &str, event_id_list2: &str, explain: Option<bool>, cohorts: CohortParams) -> Result<Json<Document>, Status> {
+	let db = db.backend(dataset, Capability::Timeline)?;
+	let (relation, event_id_list1, event_id_list2) = (relation.to_string(), event_id_list1.to_string(), event_id_list2.to_string());
+	blocking(move || allen_query(db.as_ref(), &relation, &event_id_list1, &event_id_list2, explain, &cohorts)).await
 }
 
-#[get("/eeg_allen_query?<relation>&<event_id_list1>&<event_id_list2>")]
-pub fn eeg_allen_query(db: &State<EegMongoRepo>, relation: &str, event_id_list1: &str, event_id_list2: &str) -> Result<Json<Document>, Status> {
-	// valid operations: before, after, overlap, contain, start, end
-	let relation = relation.to_lowercase();
-	if vec!["before", "after", "overlap", "contain", "start", "end", "meet", "equal"].contains(&&*relation) == false{
-		return Err(Status::NotFound);
-	}
+fn allen_query(db: &dyn Backend, relation: &str, event_id_list1: &str, event_id_list2: &str, explain: Option<bool>, cohorts: &CohortParams) -> Result<Json<Document>, Status> {
+	// valid operations: the 13 Allen relations, see AllenRelation::parse; a before bounded in
+	// time is a gap query, see GapQueryParams::before_within
+	let relation = match AllenRelation::parse(relation) {
+		Some(val) => val,
+		None => return Err(Status::NotFound),
+	};
 	let event_id_list1: Vec<i32> = event_id_list1.split(',')
 			.filter_map(|s| s.parse().ok())
 			.collect();
 	let event_id_list2: Vec<i32> = event_id_list2.split(',')
 			.filter_map(|s| s.parse().ok())
 			.collect();
-	
 
-	// let mut events = HashMap::new();
-	// events.insert("e1", event_id_list1);
-	// events.insert("e2", event_id_list2);
 	let events = hashmap!{
     "e1" => event_id_list1,
     "e2" => event_id_list2,
 	};
-	let mut ts = hashmap!{
-		"t" => "e1",
+	let (t_group, exps) = relation.encode("t", "e1", "e2");
+	let ts = hashmap!{
+		"t" => t_group,
 	};
-	let mut exps = Vec::new();
-	if relation == "before"{
-		exps = vec![
-			TelExp::init("box_t_phi", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_t_neg_phi", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("diamond_neg_phi_t", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_neg_phi_t", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_phi_t", "t", "e2", Some(vec!["e1","e2"]), Some(60.0*1000.0), None, None)
-		];
-	}else if relation == "contain"{
-		ts = hashmap!{
-			"t" => "e2",
-		};
-		exps = vec![
-			TelExp::init("box_t_phi", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("diamond_t_neg_phi", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_phi_t", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("diamond_neg_phi_t", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None)
-		];
-	}else if relation == "start"{
-		ts = hashmap!{
-			"t" => "e2",
-		};
-		exps = vec![
-			TelExp::init("box_t_phi", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_t_phi", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_phi_t", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("diamond_neg_phi_t", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None)
-		];
-	}else if relation == "end"{
-		ts = hashmap!{
-			"t" => "e2",
-		};
-		exps = vec![
-			TelExp::init("box_t_phi", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("diamond_t_neg_phi", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_phi_t", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_phi_t", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None)
-		];
-	}else if relation == "overlap"{
-		exps = vec![
-			TelExp::init("box_t_phi", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("diamond_t_phi", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("diamond_t_neg_phi", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_phi_t", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("diamond_neg_phi_t", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None)
-		];
-	}else if relation == "meet"{
-		exps = vec![
-			TelExp::init("box_t_phi", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_t_neg_phi", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_neg_phi_t", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_phi_t", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None)
-		];
-	}else if relation == "equal"{
-		exps = vec![
-			TelExp::init("box_t_phi", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_t_neg_phi", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_neg_phi_t", "t", "e1", Some(vec!["e1","e2"]), Some(0.0), None, None),
-			TelExp::init("box_phi_t", "t", "e2", Some(vec!["e1","e2"]), Some(0.0), None, None)
-		];
+
+	let explain = explain.unwrap_or(false);
+	if explain && cohorts.saving() {
+		println!("Error: an explained query has no subjects to save");
+		return Err(Status::BadRequest);
 	}
+	let subjects = cohorts.subjects()?;
+	let response = run_tel_query(db, events, ts, exps, explain, subjects.as_deref())?;
+	if cohorts.saving() {
+		cohorts.save(result_subjects(&response))?;
+	}
+	Ok(Json(response))
+}
 
+// the subjectids of the results of run_tel_query or run_bool_query
+fn result_subjects(response: &Document) -> Vec<String> {
+	response.get_array("results").map(|results| results.iter()
+		.filter_map(|result| result.as_document()?.get_document("_id").ok()?.get_str("subjectid").ok())
+		.map(str::to_string)
+		.collect()).unwrap_or_default()
+}
 
-	let pipeline = construct_query(events, ts.clone(), exps.clone());
-	let mut tel_cond = doc!{};
-	for _step in pipeline.clone(){
-		if _step.contains_key("$addFields"){
-			// println!("{:?}", _step);
-			tel_cond = _step.get_document("$addFields").unwrap().clone();
-			tel_cond = tel_cond.get_document("tel_cond").unwrap().clone();
-			if let Some(Bson::Document(tel_cond_doc)) = tel_cond.get_array("$cond").unwrap().first() {
-				tel_cond = tel_cond_doc.clone();
-			}
+// TEL query over a free-text formula, e.g.
+// exists t in e1: box[t] e1 and box[t] not e2 and (diamond not e2)[t+60s]
+// input: formula: TEL formula, events: event groups as "e1:53,79;e2:941"
+// output: same document as eeg_allen_query
+#[get("/eeg_tel_query?<formula>&<events>&<explain>")]
+pub async fn eeg_tel_query(db: &State<Datasets>, formula: &str, events: &str, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	dataset_eeg_tel_query(db, EEG_DATASET, formula, events, explain).await
+}
+
+#[get("/datasets/<dataset>/eeg_tel_query?<formula>&<events>&<explain>")]
+pub async fn dataset_eeg_tel_query(db: &State<Datasets>, dataset: &str, formula: &str, events: &str, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	let db = db.backend(dataset, Capability::Timeline)?;
+	let (formula, events) = (formula.to_string(), events.to_string());
+	blocking(move || tel_query(db.as_ref(), &formula, &events, explain)).await
+}
+
+fn tel_query(db: &dyn Backend, formula: &str, events: &str, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	let formula = match parse_formula(formula) {
+		Ok(val) => val,
+		Err(e) => {
+			println!("Error parsing TEL formula: {}", e);
+			return Err(Status::BadRequest);
 		}
-	}
-	let mut results = Vec::new();
-
-	let mut cursor = db.timeline_col.aggregate(pipeline, None).unwrap();
-  while let Some(result) = cursor.next() {
-    match result {
-      Ok(document) => {
-        // println!("document: {:?}", document);
-        results.push(document);
-      }
-      Err(e) => {
-        println!("Error getting result");
-        return Err(Status::InternalServerError);
-      }
-    }
-  }
-	let api_result = doc!{"exp_latex": construct_exps_latex(exps.clone(),ts.clone()), "tel_cond": tel_cond,"results": results.clone()};
-	match results.len() {
-		_ => Ok(Json(api_result)),
-		// 0 => Err(Status::NotFound),
-	}
+	};
+	let events = match parse_event_groups(events) {
+		Some(val) => val,
+		None => return Err(Status::BadRequest),
+	};
+	run_tel_query(db, events, formula.ts(), formula.exps.clone(), explain.unwrap_or(false), None)
+		.map(Json)
 }
 
-pub fn construct_exps_latex(exps:Vec<TelExp>,ts:HashMap<&str,&str>) -> String {
-	let mut t_set = HashSet::new();
-	for (_k,_v) in ts.iter() {
-		t_set.insert(format!("{} \\in {}", _k, _v));
-	}
-	let mut op_set = HashSet::new();
-	for exp in exps {
-		let exp_latex = exp.latex();
-		op_set.insert(exp_latex.clone());
-	}
-	format!("$\\exists {}, {}$", t_set.into_iter().collect::<Vec<String>>().join(", "), op_set.into_iter().collect::<Vec<String>>().join(" \\land "))
-}
-pub fn construct_query(events: HashMap<&str,Vec<i32>>,ts:HashMap<&str,&str>,exps:Vec<TelExp>) -> Vec<Document> {
-	// get all event ids from values of events
-	let mut event_ids: HashSet<i32> = HashSet::new();
-	let mut group_stmt = doc!{"_id": "$subjectid"};
-	let mut filter_none_time_stmt = doc!{ "_id":1};
-	let mut filter = Vec::<Document>::new();
-
-	for (_k,_v) in events.iter() {
-		let event_name = _k.to_string(); // Clone the value of event_name
-
-		event_ids.extend(_v);
-		group_stmt.insert(*_k, doc!{ "$addToSet": { "$cond": [ { "$in": [ "$e", _v ] }, "$times", None::<i32> ] } });
-		filter_none_time_stmt.insert(event_name.clone(), doc!{"$setDifference": [ format!("${}", event_name), [None::<i32>]]}); 
-		filter.push(doc!{"$gt": [ {"$size": format!("${}", event_name)}, 0]});
-	}
-	let event_ids: Vec<i32> = event_ids.into_iter().collect();
-
-	// get tel conditions
-	let tel_cond_stmt = construct_tel_cond(exps);
-	// print!("{:?}", tel_cond_stmt);
-
-	let mut mongo_stmt = vec![
-		doc!{"$match": {"e": {"$in": event_ids}}},
-		doc!{"$project": {"_id": 0, "subjectid": 1, "e": 1, "times": 1}},
-		doc!{"$unwind": "$times"},
-		doc!{"$group": group_stmt},
-		doc!{"$project": filter_none_time_stmt},
-		doc!{"$match": { "$expr": { "$and": filter } }}
-	];
-	let mut project_stmt = doc!{"_id": 1};
-	for (_k,_v) in ts.iter() {
-		project_stmt.insert(_k.to_string(), format!("${}", _v));
+// subjects satisfying a boolean combination of Allen relations and TEL formulas, e.g.
+// e1 before e2 and not (e1 overlaps e3 or {exists t in e3: box[t] e3 and box[t] not e1})
+// input: query: see tel::parser, events: event groups as "e1:53,79;e2:941"
+// output: exp_latex, tel_cond and the matching subjects as {_id: {subjectid}}
+#[get("/eeg_bool_query?<query>&<events>&<explain>")]
+pub async fn eeg_bool_query(db: &State<Datasets>, query: &str, events: &str, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	dataset_eeg_bool_query(db, EEG_DATASET, query, events, explain).await
+}
+
+#[get("/datasets/<dataset>/eeg_bool_query?<query>&<events>&<explain>")]
+pub async fn dataset_eeg_bool_query(db: &State<Datasets>, dataset: &str, query: &str, events: &str, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	let db = db.backend(dataset, Capability::Timeline)?;
+	let (query, events) = (query.to_string(), events.to_string());
+	blocking(move || bool_query(db.as_ref(), &query, &events, explain)).await
+}
+
+fn bool_query(db: &dyn Backend, query: &str, events: &str, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	let query = match parse_query(query) {
+		Ok(val) => val,
+		Err(e) => {
+			println!("Error parsing TEL query: {}", e);
+			return Err(Status::BadRequest);
+		}
+	};
+	let events = match parse_event_groups(events) {
+		Some(val) => val,
+		None => return Err(Status::BadRequest),
+	};
+	run_bool_query(db, events, &query, explain.unwrap_or(false)).map(Json)
+}
+
+// cross-check of the mongo pipeline against the native TEL evaluator on a few subjects
+// input: formula and events as in eeg_tel_query, subjects: comma separated subject ids
+// output: number of matches of both and the matches only one of them found
+#[get("/eeg_tel_crosscheck?<formula>&<events>&<subjects>")]
+pub async fn eeg_tel_crosscheck(db: &State<Datasets>, formula: &str, events: &str, subjects: &str) -> Result<Json<Document>, Status> {
+	dataset_eeg_tel_crosscheck(db, EEG_DATASET, formula, events, subjects).await
+}
+
+#[get("/datasets/<dataset>/eeg_tel_crosscheck?<formula>&<events>&<subjects>")]
+pub async fn dataset_eeg_tel_crosscheck(db: &State<Datasets>, dataset: &str, formula: &str, events: &str, subjects: &str) -> Result<Json<Document>, Status> {
+	let db = db.backend(dataset, Capability::Timeline)?;
+	let (formula, events, subjects) = (formula.to_string(), events.to_string(), subjects.to_string());
+	blocking(move || tel_crosscheck(db.as_ref(), &formula, &events, &subjects)).await
+}
+
+fn tel_crosscheck(db: &dyn Backend, formula: &str, events: &str, subjects: &str) -> Result<Json<Document>, Status> {
+	let formula = match parse_formula(formula) {
+		Ok(val) => val,
+		Err(e) => {
+			println!("Error parsing TEL formula: {}", e);
+			return Err(Status::BadRequest);
+		}
+	};
+	let events = match parse_event_groups(events) {
+		Some(val) => val,
+		None => return Err(Status::BadRequest),
+	};
+	let subjects: Vec<String> = subjects.split(',')
+			.map(|s| s.trim().to_string())
+			.filter(|s| !s.is_empty())
+			.collect();
+	let ts = formula.ts();
+
+	let event_ids: Vec<i32> = events.values().flatten().copied().collect();
+	let timelines = db.timelines(Some(&subjects), &event_ids)?;
+	let native: BTreeSet<TelMatch> = match evaluate(&timelines, &events, &ts, &formula.exps) {
+		Ok(val) => val.into_iter().collect(),
+		Err(e) => {
+			println!("Invalid TEL expression: {}", e);
+			return Err(Status::BadRequest);
+		}
+	};
+
+	let mongo: BTreeSet<TelMatch> = db.tel_matches(&events, &ts, &formula.exps, Some(&subjects))?.into_iter().collect();
+	let only_native: Vec<Document> = native.difference(&mongo).map(match_document).collect();
+	let only_mongo: Vec<Document> = mongo.difference(&native).map(match_document).collect();
+	Ok(Json(doc!{"native": native.len() as i64, "mongo": mongo.len() as i64, "only_native": only_native, "only_mongo": only_mongo}))
+}
+
+// cross-check of eeg_bool_query against the native evaluator, as eeg_tel_crosscheck
+// output: number of subjects of both and the subjects only one of them found
+#[get("/eeg_bool_crosscheck?<query>&<events>&<subjects>")]
+pub async fn eeg_bool_crosscheck(db: &State<Datasets>, query: &str, events: &str, subjects: &str) -> Result<Json<Document>, Status> {
+	dataset_eeg_bool_crosscheck(db, EEG_DATASET, query, events, subjects).await
+}
+
+#[get("/datasets/<dataset>/eeg_bool_crosscheck?<query>&<events>&<subjects>")]
+pub async fn dataset_eeg_bool_crosscheck(db: &State<Datasets>, dataset: &str, query: &str, events: &str, subjects: &str) -> Result<Json<Document>, Status> {
+	let db = db.backend(dataset, Capability::Timeline)?;
+	let (query, events, subjects) = (query.to_string(), events.to_string(), subjects.to_string());
+	blocking(move || bool_crosscheck(db.as_ref(), &query, &events, &subjects)).await
+}
+
+fn bool_crosscheck(db: &dyn Backend, query: &str, events: &str, subjects: &str) -> Result<Json<Document>, Status> {
+	let query = match parse_query(query) {
+		Ok(val) => val,
+		Err(e) => {
+			println!("Error parsing TEL query: {}", e);
+			return Err(Status::BadRequest);
+		}
+	};
+	let events = match parse_event_groups(events) {
+		Some(val) => val,
+		None => return Err(Status::BadRequest),
+	};
+	let subjects: Vec<String> = subjects.split(',')
+			.map(|s| s.trim().to_string())
+			.filter(|s| !s.is_empty())
+			.collect();
+
+	let event_ids: Vec<i32> = events.values().flatten().copied().collect();
+	let timelines = db.timelines(Some(&subjects), &event_ids)?;
+	let native: BTreeSet<String> = match evaluate_query(&timelines, &events, &query) {
+		Ok(val) => val.into_iter().collect(),
+		Err(e) => {
+			println!("Invalid TEL expression: {}", e);
+			return Err(Status::BadRequest);
+		}
+	};
+
+	let mongo: BTreeSet<String> = db.query_subjects(&events, &query, Some(&subjects))?.into_iter().collect();
+	let only_native: Vec<&String> = native.difference(&mongo).collect();
+	let only_mongo: Vec<&String> = mongo.difference(&native).collect();
+	Ok(Json(doc!{"native": native.len() as i64, "mongo": mongo.len() as i64, "only_native": only_native, "only_mongo": only_mongo}))
+}
+
+// a native match in the shape of the _id of the pipeline results
+fn match_document(tel_match: &TelMatch) -> Document {
+	let mut document = doc!{"subjectid": &tel_match.subjectid};
+	for (group, (start, end)) in &tel_match.intervals {
+		document.insert(format!("min_{}", group), DateTime::from_millis(*start));
+		document.insert(format!("max_{}", group), DateTime::from_millis(*end));
 	}
-	for _k in events.keys() {
-		mongo_stmt.push(doc!{"$unwind": format!("${}", _k)});
-		project_stmt.insert(format!("min_{}", _k),doc!{"$arrayElemAt": [format!("${}", _k), 0]});
-		project_stmt.insert(format!("max_{}", _k),doc!{"$arrayElemAt": [format!("${}", _k), -1]});
+	document
+}
+
+// temporal pattern over any number of event groups, e.g. seizure before clonic phase meets suppression
+// input: events: event groups as "seizure:53;clonic:214;suppression:941",
+//   relations: pairwise Allen relations as "seizure:before:clonic;clonic:meets:suppression"
+// output: same document as eeg_allen_query, with min_/max_ of every group
+#[get("/eeg_pattern_query?<events>&<relations>&<explain>")]
+pub async fn eeg_pattern_query(db: &State<Datasets>, events: &str, relations: &str, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	dataset_eeg_pattern_query(db, EEG_DATASET, events, relations, explain).await
+}
+
+#[get("/datasets/<dataset>/eeg_pattern_query?<events>&<relations>&<explain>")]
+pub async fn dataset_eeg_pattern_query(db: &State<Datasets>, dataset: &str, events: &str, relations: &str, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	let db = db.backend(dataset, Capability::Timeline)?;
+	let (events, relations) = (events.to_string(), relations.to_string());
+	blocking(move || pattern_query(db.as_ref(), &events, &relations, explain)).await
+}
+
+fn pattern_query(db: &dyn Backend, events: &str, relations: &str, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	let events = match parse_event_groups(events) {
+		Some(val) => val,
+		None => return Err(Status::BadRequest),
+	};
+	let relations = match parse_relations(relations) {
+		Some(val) if !val.is_empty() => val,
+		_ => return Err(Status::BadRequest),
+	};
+	let (t_groups, exps) = encode_pattern(&relations);
+	// generated time variables must not shadow the fields of an event group
+	if t_groups.iter().any(|(t, _)| events.contains_key(t.as_str())) {
+		println!("Event group names must not collide with time variables t1..t{}", t_groups.len());
+		return Err(Status::BadRequest);
 	}
-	mongo_stmt.push(doc!{"$project": project_stmt});
-	for _k in ts.keys() {
-		mongo_stmt.push( doc!{"$unwind": format!("${}", _k)} );
+	let ts: HashMap<&str, &str> = t_groups.iter().map(|(t, group)| (t.as_str(), *group)).collect();
+	run_tel_query(db, events, ts, exps, explain.unwrap_or(false), None)
+		.map(Json)
+}
+
+// absence query: event list1 not followed by event list2, optionally within a window, e.g.
+// diagnosed and not treated within 90 days after
+// input: event list1, event list2, window: duration such as "90d", from: anchor of event list1, default end
+// output: same document as eeg_bool_query
+#[get("/eeg_absence_query?<event_id_list1>&<event_id_list2>&<window>&<from>&<explain>")]
+pub async fn eeg_absence_query(db: &State<Datasets>, event_id_list1: &str, event_id_list2: &str, window: Option<&str>, from: Option<&str>, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	dataset_eeg_absence_query(db, EEG_DATASET, event_id_list1, event_id_list2, window, from, explain).await
+}
+
+#[get("/datasets/<dataset>/eeg_absence_query?<event_id_list1>&<event_id_list2>&<window>&<from>&<explain>")]
+pub async fn dataset_eeg_absence_query(db: &State<Datasets>, dataset: &str, event_id_list1: &str, event_id_list2: &str, window: Option<&str>, from: Option<&str>, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	let db = db.backend(dataset, Capability::Timeline)?;
+	let (event_id_list1, event_id_list2, window, from) = (event_id_list1.to_string(), event_id_list2.to_string(), window.map(str::to_string), from.map(str::to_string));
+	blocking(move || absence_query(db.as_ref(), &event_id_list1, &event_id_list2, window.as_deref(), from.as_deref(), explain)).await
+}
+
+fn absence_query(db: &dyn Backend, event_id_list1: &str, event_id_list2: &str, window: Option<&str>, from: Option<&str>, explain: Option<bool>) -> Result<Json<Document>, Status> {
+	let mut absence = Absence::new("e1", "e2", None);
+	if let Some(window) = window {
+		absence.window = Some(parse_duration(window).ok_or(Status::BadRequest)?);
 	}
-	mongo_stmt.push(doc!{"$addFields": {"tel_cond": tel_cond_stmt}});
-	mongo_stmt.push(doc!{"$match": {"tel_cond": true}});
-	let mut group_fields = doc!{"subjectid": "$_id"};
-	for _k in events.keys() {
-		group_fields.insert(format!("min_{}", _k), format!("$min_{}", _k));
-		group_fields.insert(format!("max_{}", _k), format!("$max_{}", _k));
+	if let Some(from) = from {
+		absence.from = Anchor::parse(from).ok_or(Status::BadRequest)?;
 	}
-	mongo_stmt.push(doc!{"$group": {"_id": group_fields}});
-
+	let event_id_list1: Vec<i32> = event_id_list1.split(',')
+			.filter_map(|s| s.parse().ok())
+			.collect();
+	let event_id_list2: Vec<i32> = event_id_list2.split(',')
+			.filter_map(|s| s.parse().ok())
+			.collect();
 
-	return mongo_stmt;
+	let events = hashmap!{
+    "e1" => event_id_list1,
+    "e2" => event_id_list2,
+	};
+	run_bool_query(db, events, &TelQuery::Absence(absence), explain.unwrap_or(false)).map(Json)
+}
 
+#[derive(FromForm)]
+pub struct GapQueryParams {
+	event_id_list1: String,
+	event_id_list2: String,
+	// durations such as "5s" or "10min", see parse_duration
+	min_gap: Option<String>,
+	max_gap: Option<String>,
+	// bounds are closed unless marked open
+	min_open: Option<bool>,
+	max_open: Option<bool>,
+	// anchors of event list1 and event list2 the gap is measured between, default end and start
+	from: Option<String>,
+	to: Option<String>,
+	// optional Allen relation that must hold as well
+	relation: Option<String>,
+	explain: Option<bool>,
 }
 
-pub fn construct_tel_cond(exps:Vec<TelExp>) -> Document {
-	let mut and_stmt: Vec<Document> = Vec::new();
-	for exp in exps {
-		let mut mongo_exp = doc!{};
-		if exp.operator == "box_t_phi" {
-			mongo_exp = box_t_phi(exp);
-		} else if exp.operator == "box_t_neg_phi" {
-			mongo_exp = box_t_neg_phi(exp);
-		} else if exp.operator == "box_phi_t" {
-			mongo_exp = box_phi_t(exp);
-		} else if exp.operator == "box_neg_phi_t" {
-			mongo_exp = box_neg_phi_t(exp);
-		} else if exp.operator == "diamond_t_phi" {
-			mongo_exp = diamond_t_phi(exp);
-		} else if exp.operator == "diamond_t_neg_phi" {
-			mongo_exp = diamond_t_neg_phi(exp);
-		} else if exp.operator == "diamond_phi_t" {
-			mongo_exp = diamond_phi_t(exp);
-		} else if exp.operator == "diamond_neg_phi_t" {
-			mongo_exp = diamond_neg_phi_t(exp);
+impl GapQueryParams {
+	// event list2 starting at most max_gap after event list1 ends, strictly before
+	pub fn before_within(event_id_list1: &str, event_id_list2: &str, max_gap: &str) -> Self {
+		GapQueryParams {
+			event_id_list1: event_id_list1.to_string(),
+			event_id_list2: event_id_list2.to_string(),
+			min_gap: None,
+			max_gap: Some(max_gap.to_string()),
+			min_open: None,
+			max_open: None,
+			from: None,
+			to: None,
+			relation: Some(AllenRelation::Before.name().to_string()),
+			explain: None,
 		}
-		
-		and_stmt.push(mongo_exp);
 	}
-	let mongo_stmt = doc!{ "$cond": [{"$and": and_stmt}, true, false] };
+}
 
-	return mongo_stmt;
+// relative temporal query with a time gap: event list2 starts between min_gap and max_gap after event list1 ends
+// input: event list1, event list2, min_gap and/or max_gap, see GapQueryParams
+// output: same document as eeg_allen_query
+#[get("/eeg_gap_query?<params..>")]
+pub async fn eeg_gap_query(db: &State<Datasets>, params: GapQueryParams) -> Result<Json<Document>, Status> {
+	dataset_eeg_gap_query(db, EEG_DATASET, params).await
+}
 
+#[get("/datasets/<dataset>/eeg_gap_query?<params..>")]
+pub async fn dataset_eeg_gap_query(db: &State<Datasets>, dataset: &str, params: GapQueryParams) -> Result<Json<Document>, Status> {
+	let db = db.backend(dataset, Capability::Timeline)?;
+	blocking(move || gap_query(db.as_ref(), params)).await
 }
 
-pub fn box_t_phi(exp: TelExp) -> Document {
-	let t = exp.t;
-	let event = exp.event;
-	let delta = exp.delta;
-	let s = exp.s;
+fn gap_query(db: &dyn Backend, params: GapQueryParams) -> Result<Json<Document>, Status> {
+	let bound = |value: &Option<String>, open: Option<bool>| match value {
+		Some(value) => parse_duration(value).map(|value| Some(GapBound { value, open: open.unwrap_or(false) })).ok_or(Status::BadRequest),
+		None => Ok(None),
+	};
+	let anchor = |value: &Option<String>, default: Anchor| match value {
+		Some(value) => Anchor::parse(value).ok_or(Status::BadRequest),
+		None => Ok(default),
+	};
+	let gap = GapConstraint {
+		a: "e1",
+		from: anchor(&params.from, Anchor::End)?,
+		b: "e2",
+		to: anchor(&params.to, Anchor::Start)?,
+		min: bound(&params.min_gap, params.min_open)?,
+		max: bound(&params.max_gap, params.max_open)?,
+	};
+	if gap.min.is_none() && gap.max.is_none() {
+		return Err(Status::BadRequest);
+	}
+	let event_id_list1: Vec<i32> = params.event_id_list1.split(',')
+			.filter_map(|s| s.parse().ok())
+			.collect();
+	let event_id_list2: Vec<i32> = params.event_id_list2.split(',')
+			.filter_map(|s| s.parse().ok())
+			.collect();
 
-	let mut mongo_stmt = doc!{};
-	if s != "" {
-		mongo_stmt = doc!{ "$and": [ { "$gte": [ format!("${}", s), format!("$min_{}", event) ]}, { "$lte": [ { "$add": [ format!("${}", t), delta ] }, format!("$max_{}", event) ] } ] };
-	} else {
-		let mut s_vec = vec![];
-		for x in exp.events {
-			s_vec.push(format!("$min_{}", x));
-		}
-		mongo_stmt = doc!{ "$and": [ { "$gte": [ { "$min": s_vec }, format!("$min_{}", event) ]}, { "$lte": [ { "$add": [ format!("${}", t), delta ] }, format!("$max_{}", event) ] } ] };
+	let events = hashmap!{
+    "e1" => event_id_list1,
+    "e2" => event_id_list2,
+	};
+	let (bindings, mut exps) = gap.encode("t", "u");
+	let mut ts: HashMap<&str, &str> = bindings.into_iter().collect();
+	if let Some(relation) = &params.relation {
+		let relation = AllenRelation::parse(relation).ok_or(Status::NotFound)?;
+		let (t_group, relation_exps) = relation.encode("r", "e1", "e2");
+		ts.insert("r", t_group);
+		exps.extend(relation_exps);
 	}
-	return mongo_stmt;
-}
-
-pub fn box_t_neg_phi(exp: TelExp) -> Document {
-	let t = exp.t;
-	let event = exp.event;
-	let delta = exp.delta;
-	let s = exp.s;
-
-	let mut mongo_stmt = doc!{};
-	if s != "" {
-		mongo_stmt = doc!{ "$or": [ { "$gte": [ format!("$min_{}", event), { "$add": [ format!("${}", t) , delta ] }] }, { "$lte": [ format!("$max_{}", event), format!("${}", s)] } ] };
-	} else {
-		let mut s_vec = vec![];
-		for x in exp.events {
-			s_vec.push(format!("$min_{}", x));
+	run_tel_query(db, events, ts, exps, params.explain.unwrap_or(false), None)
+		.map(Json)
+}
+
+// parse event groups of the form "e1:53,79;e2:941"
+pub fn parse_event_groups(events: &str) -> Option<HashMap<&str, Vec<i32>>> {
+	let mut groups = HashMap::new();
+	for group in events.split(';').filter(|g| !g.trim().is_empty()) {
+		let (name, ids) = group.split_once(':')?;
+		let name = name.trim();
+		// group names become field names of the pipeline
+		if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+			return None;
 		}
-		mongo_stmt = doc!{ "$or": [ { "$gte": [ format!("$min_{}", event), { "$add": [ format!("${}", t) , delta ] }] }, { "$lte": [ format!("$max_{}", event), { "$min": s_vec }] } ] };
+		let ids: Vec<i32> = ids.split(',')
+				.filter_map(|s| s.trim().parse().ok())
+				.collect();
+		groups.insert(name, ids);
 	}
-	return mongo_stmt;
-}
-
-pub fn box_phi_t(exp: TelExp) -> Document {
-	let t = exp.t;
-	let event = exp.event;
-	let delta = exp.delta;
-	let e = exp.e;
-
-	let mut mongo_stmt = doc!{};
-	if e != "" {
-		mongo_stmt = doc!{ "$and": [ { "$gte": [ { "$add": [ format!("${}", t), delta ] }, format!("$min_{}", event) ] }, { "$gte": [ format!("$max_{}", event), format!("${}", e) ] } ] };
-	} else {
-		let mut e_vec = vec![];
-		for x in exp.events {
-			e_vec.push(format!("$max_{}", x));
+	Some(groups)
+}
+
+// parse pairwise relations of the form "e1:before:e2;e2:meets:e3"
+pub fn parse_relations(relations: &str) -> Option<Vec<(&str, AllenRelation, &str)>> {
+	let mut parsed = Vec::new();
+	for relation in relations.split(';').filter(|r| !r.trim().is_empty()) {
+		let parts: Vec<&str> = relation.split(':').map(|p| p.trim()).collect();
+		if parts.len() != 3 {
+			return None;
 		}
-		mongo_stmt = doc!{ "$and": [ { "$gte": [ { "$add": [ format!("${}", t), delta ] }, format!("$min_{}", event) ] }, { "$gte": [ format!("$max_{}", event), { "$max": e_vec } ] } ] };
+		parsed.push((parts[0], AllenRelation::parse(parts[1])?, parts[2]));
 	}
-	return mongo_stmt;
-}
-
-pub fn box_neg_phi_t(exp: TelExp) -> Document {
-	let t = exp.t;
-	let event = exp.event;
-	let delta = exp.delta;
-	let e = exp.e;
-
-	let mut mongo_stmt = doc!{};
-	if e != "" {
-		mongo_stmt = doc!{ "$or": [ { "$gte": [ format!("$min_{}", event), format!("${}", e) ] }, { "$lte": [ format!("$max_{}", event), { "$add": [ format!("${}", t), delta ] }] } ] };
-	} else {
-		let mut e_vec = vec![];
-		for x in exp.events {
-			e_vec.push(format!("$max_{}", x));
+	Some(parsed)
+}
+
+// validate the expressions and collect the api response from the backend, of the given
+// subjects only if any; with explain the pipeline and its query plan are returned instead
+// of the results
+pub fn run_tel_query(db: &dyn Backend, events: HashMap<&str,Vec<i32>>, ts: HashMap<&str,&str>, exps: Vec<TelExp>, explain: bool, subjects: Option<&[String]>) -> Result<Document, Status> {
+	for exp in &exps {
+		if let Err(e) = exp.validate(&events, &ts) {
+			println!("Invalid TEL expression: {}", e);
+			return Err(Status::BadRequest);
 		}
-		mongo_stmt = doc!{ "$or": [ { "$gte": [ format!("$min_{}", event), { "$max": e_vec } ] }, { "$lte": [ format!("$max_{}", event), { "$add": [ format!("${}", t), delta ] }] } ] };
 	}
-	return mongo_stmt;
-}
-
-pub fn diamond_t_phi(exp: TelExp) -> Document {
-	let t = exp.t;
-	let event = exp.event;
-	let delta = exp.delta;
-	let s = exp.s;
-
-	let mut mongo_stmt = doc!{};
-	if s != "" {
-		mongo_stmt = doc!{ "$and": [ { "$gt": [ { "$add": [ format!("${}", t), delta ] },  format!("$min_{}", event) ] }, { "$gt": [ format!("$max_{}", event), format!("${}", s)  ] } ] };
-	} else {
-		let mut s_vec = vec![];
-		for x in exp.events {
-			s_vec.push(format!("$min_{}", x));
-		}
-		mongo_stmt = doc!{ "$and": [ { "$gt": [ { "$add": [ format!("${}", t), delta ] },  format!("$min_{}", event) ] }, { "$gt": [ format!("$max_{}", event), { "$min": s_vec } ] } ] };
+
+	let mut response = doc!{"exp_latex": construct_exps_latex(exps.clone(), ts.clone())};
+	if let Some(Bson::Document(tel_cond)) = construct_tel_cond(exps.clone()).get_array("$cond").unwrap().first() {
+		response.insert("tel_cond", tel_cond.clone());
 	}
-	return mongo_stmt;
-}
-
-pub fn diamond_t_neg_phi(exp: TelExp) -> Document {
-	let t = exp.t;
-	let event = exp.event;
-	let delta = exp.delta;
-	let s = exp.s;
-
-	let mut mongo_stmt = doc!{};
-	if s != "" {
-		mongo_stmt = doc!{ "$or": [ { "$gt": [ format!("$min_{}", event), format!("${}", s) ] }, { "$lt": [ format!("$max_{}", event), { "$add": [ format!("${}", t), delta ] } ] } ] };
-	} else {
-		let mut s_vec = vec![];
-		for x in exp.events {
-			s_vec.push(format!("$min_{}", x));
-		}
-		mongo_stmt = doc!{ "$or": [ { "$gt": [ format!("$min_{}", event), { "$min": s_vec } ] }, { "$lt": [ format!("$max_{}", event), { "$add": [ format!("${}", t), delta ] } ] } ] };
+	if explain {
+		response.extend(db.explain_matches(&events, &ts, &exps, subjects)?);
+		return Ok(response);
 	}
-	return mongo_stmt;
-}
-
-pub fn diamond_phi_t(exp: TelExp) -> Document {
-	let t = exp.t;
-	let event = exp.event;
-	let delta = exp.delta;
-	let e = exp.e;
-
-	let mut mongo_stmt = doc!{};
-	if e != "" {
-		mongo_stmt = doc!{ "$and": [ { "$gt": [ format!("${}", e), format!("$min_{}", event)] }, { "$gt": [ format!("$max_{}", event),  { "$add": [ format!("${}", t), delta ] } ] } ] };
-	} else {
-		let mut e_vec = vec![];
-		for x in exp.events {
-			e_vec.push(format!("$max_{}", x));
+	let results: Vec<Document> = db.tel_matches(&events, &ts, &exps, subjects)?.iter()
+		.map(|tel_match| doc!{"_id": match_document(tel_match)})
+		.collect();
+	response.insert("results", results);
+	Ok(response)
+}
+
+// as run_tel_query for a query tree, the results being the subjects as {_id: {subjectid}}
+pub fn run_bool_query(db: &dyn Backend, events: HashMap<&str,Vec<i32>>, query: &TelQuery, explain: bool) -> Result<Document, Status> {
+	for formula in query.formulas() {
+		let ts = formula.ts();
+		for exp in &formula.exps {
+			if let Err(e) = exp.validate(&events, &ts) {
+				println!("Invalid TEL expression: {}", e);
+				return Err(Status::BadRequest);
+			}
 		}
-		mongo_stmt = doc!{ "$and": [ { "$gt": [ { "$max": e_vec }, format!("$min_{}", event)] }, { "$gt": [ format!("$max_{}", event),  { "$add": [ format!("${}", t), delta ] } ] } ] };
 	}
-	return mongo_stmt;
-}
-
-pub fn diamond_neg_phi_t(exp: TelExp) -> Document {
-	let t = exp.t;
-	let event = exp.event;
-	let delta = exp.delta;
-	let e = exp.e;
-
-	let mut mongo_stmt = doc!{};
-	if e != "" {
-		mongo_stmt = doc!{ "$and": [{ "$gt": [ format!("${}", e), format!("${}", t)]}, { "$or": [ { "$gt": [ format!("$min_{}", event),  { "$add": [ format!("${}", t), delta ] } ] }, { "$lt": [ format!("$max_{}", event), format!("${}", e) ] }] }]};
-	} else {
-		let mut e_vec = vec![];
-		for x in exp.events {
-			e_vec.push(format!("$max_{}", x));
+	if let Some(group) = query.groups().into_iter().find(|group| !events.contains_key(group)) {
+		println!("Unknown event group: {}", group);
+		return Err(Status::BadRequest);
+	}
+
+	let mut response = doc!{"exp_latex": construct_query_latex(query), "tel_cond": construct_query_cond(query)};
+	if explain {
+		response.extend(db.explain_subjects(&events, query)?);
+		return Ok(response);
+	}
+	let results: Vec<Document> = db.query_subjects(&events, query, None)?.into_iter()
+		.map(|subjectid| doc!{"_id": {"subjectid": subjectid}})
+		.collect();
+	response.insert("results", results);
+	Ok(response)
+}
+
+pub fn construct_exps_latex(exps:Vec<TelExp>,ts:HashMap<&str,&str>) -> String {
+	format!("${}$", exps_latex(exps, ts))
+}
+
+fn exps_latex(exps:Vec<TelExp>,ts:HashMap<&str,&str>) -> String {
+	let mut t_set = HashSet::new();
+	for (_k,_v) in ts.iter() {
+		t_set.insert(format!("{} \\in {}", _k, _v));
+	}
+	let mut op_set = HashSet::new();
+	for exp in exps {
+		let exp_latex = exp.latex();
+		op_set.insert(exp_latex.clone());
+	}
+	format!("\\exists {}, {}", t_set.into_iter().collect::<Vec<String>>().join(", "), op_set.into_iter().collect::<Vec<String>>().join(" \\land "))
+}
+pub fn construct_query_latex(query: &TelQuery) -> String {
+	fn latex(query: &TelQuery) -> String {
+		match query {
+			TelQuery::Formula(formula) => format!("({})", exps_latex(formula.exps.clone(), formula.ts())),
+			TelQuery::Absence(absence) => {
+				let anchor = match absence.from {
+					Anchor::Start => "start",
+					Anchor::End => "end",
+				};
+				let window = match absence.window {
+					Some(window) => format!(" \\le {}(u) + {}", anchor, window),
+					None => String::new(),
+				};
+				format!("(\\exists u \\in {}, \\neg \\exists v \\in {}, {}(u) \\le start(v){})", absence.a, absence.b, anchor, window)
+			}
+			TelQuery::And(queries) => format!("({})", queries.iter().map(latex).collect::<Vec<String>>().join(" \\land ")),
+			TelQuery::Or(queries) => format!("({})", queries.iter().map(latex).collect::<Vec<String>>().join(" \\lor ")),
+			TelQuery::Not(query) => format!("\\neg {}", latex(query)),
 		}
-		mongo_stmt = doc!{ "$and": [{ "$gt": [ { "$max": e_vec.clone() }, format!("${}", t)]}, { "$or": [ { "$gt": [ format!("$min_{}", event),  { "$add": [ format!("${}", t), delta ] } ] }, { "$lt": [ format!("$max_{}", event), { "$max": e_vec.clone() } ] }] }]};
 	}
-	return mongo_stmt;
-}
\ No newline at end of file
+	format!("${}$", latex(query))
+}
+
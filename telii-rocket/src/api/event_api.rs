@@ -1,46 +1,44 @@
-use crate::{models::event::Event, database::mongodb::MongoRepo};
-use mongodb::results::InsertOneResult;
+use crate::{models::event::Event, database::backend::blocking, database::registry::{Capability, Datasets, TELII_DATASET}};
 use rocket::{http::Status, serde::json::Json, State};
-use mongodb::{options::ClientOptions, Client, bson::doc, options::FindOptions};
-use mongodb::bson::Regex;
 
 #[get("/event/<path>")]
-pub fn get_event(db: &State<MongoRepo>, path: &str) -> Result<Json<Event>, Status> {
-    let id = path;
-    if id.is_empty() {
-        return Err(Status::BadRequest);
+pub async fn get_event(db: &State<Datasets>, path: &str) -> Result<Json<Event>, Status> {
+    dataset_get_event(db, TELII_DATASET, path).await
+}
+
+#[get("/datasets/<dataset>/event/<path>")]
+pub async fn dataset_get_event(db: &State<Datasets>, dataset: &str, path: &str) -> Result<Json<Event>, Status> {
+    let id = match path.parse::<i32>() {
+        Ok(id) => id,
+        Err(_) => return Err(Status::BadRequest),
     };
-    let event_detail = db.get_event(&id);
-    match event_detail {
-        Ok(event) => Ok(Json(event)),
-        Err(_) => Err(Status::InternalServerError),
+    let events = db.backend(dataset, Capability::Events)?;
+    match blocking(move || Ok(events.get_event(id)?)).await? {
+        Some(event) => Ok(Json(event)),
+        None => Err(Status::NotFound),
     }
 }
 
 #[get("/corpus_search?<term>")]
-pub fn corpus_search(db: &State<MongoRepo>, term: &str) -> Result<Json<Vec<String>>, Status> {
-    let filter = doc![
-        "value": {
-            "$regex": Regex {
-                pattern: String::from(term),
-                options: String::from("i"),
-            }
-        }
-    ];
-    let find_options = FindOptions::builder().projection(doc! {"_id": 0}).build();
+pub async fn corpus_search(db: &State<Datasets>, term: &str) -> Result<Json<Vec<String>>, Status> {
+    dataset_corpus_search(db, TELII_DATASET, term).await
+}
 
-    let mut cursor = db.corpus_col.find(filter, find_options).unwrap();
-    let mut results: Vec<String> = Vec::new();
-    while let Some(result) = cursor.next() {
-        match result {
-            Ok(document) => {
-                let document_string = document.to_string();
-                // println!("{}", document_string);
-                results.push(document_string);
-            }
-            Err(e) => return Err(Status::InternalServerError),
-        }
-    }
+#[get("/datasets/<dataset>/corpus_search?<term>")]
+pub async fn dataset_corpus_search(db: &State<Datasets>, dataset: &str, term: &str) -> Result<Json<Vec<String>>, Status> {
+    let corpus = db.backend(dataset, Capability::Corpus)?;
+    let term = term.to_string();
+    Ok(Json(blocking(move || Ok(corpus.search_corpus(&term)?)).await?))
+}
+
+// the datasets and their capabilities
+#[get("/datasets")]
+pub fn list_datasets(db: &State<Datasets>) -> Json<Vec<DatasetInfo>> {
+    Json(db.iter().map(|(name, dataset)| DatasetInfo { name: name.clone(), capabilities: dataset.capabilities.iter().copied().collect() }).collect())
+}
 
-    Ok((Json(results)))
-}
\ No newline at end of file
+#[derive(Debug, serde::Serialize)]
+pub struct DatasetInfo {
+    name: String,
+    capabilities: Vec<Capability>,
+}
@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+
+use crate::database::backend::{blocking, Check};
+use crate::database::registry::{Dataset, Datasets};
+use mongodb::bson::{doc, Document};
+use rocket::{http::Status, serde::json::Json, State};
+
+// {<dataset>: {ready, errors, warnings}}
+fn status_document(checks: &BTreeMap<String, Check>) -> Document {
+    checks.iter()
+        .map(|(name, check)| (name.clone(), doc! {"ready": check.ready(), "errors": &check.errors, "warnings": &check.warnings}.into()))
+        .collect()
+}
+
+// liveness: the server is up, with the status of each dataset from its last check
+#[get("/health")]
+pub fn health(db: &State<Datasets>) -> Json<Document> {
+    Json(doc! {"status": "ok", "datasets": status_document(&db.checks())})
+}
+
+// readiness: checks every dataset again, 503 unless all of them are ready
+#[get("/ready")]
+pub async fn ready(db: &State<Datasets>) -> (Status, Json<Document>) {
+    let datasets: Vec<(String, Dataset)> = db.iter().map(|(name, dataset)| (name.clone(), dataset.clone())).collect();
+    let checks = blocking(move || Ok(datasets.into_iter().map(|(name, dataset)| (name, dataset.check())).collect())).await;
+    let checks: BTreeMap<String, Check> = match checks {
+        Ok(checks) => checks,
+        Err(status) => return (status, Json(doc! {"ready": false})),
+    };
+    db.record(checks.clone());
+    let ready = checks.values().all(Check::ready);
+    let status = if ready { Status::Ok } else { Status::ServiceUnavailable };
+    (status, Json(doc! {"ready": ready, "datasets": status_document(&checks)}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::backend::MemoryBackend;
+    use crate::database::registry::Capability;
+    use std::collections::BTreeSet;
+
+    #[rocket::async_test]
+    async fn reports_unavailable_datasets() {
+        let mut datasets = Datasets::new();
+        datasets.insert("eeg", BTreeSet::from([Capability::Timeline]), Box::new(MemoryBackend::new(Default::default())));
+        datasets.insert_dataset("telii", Dataset { capabilities: BTreeSet::new(), backend: Err("MONGOURI is not set".to_string()) });
+        let db = <&State<Datasets>>::from(&datasets);
+
+        let (status, report) = ready(db).await;
+        assert_eq!(status, Status::ServiceUnavailable);
+        assert_eq!(report.0.get_document("datasets").unwrap(), &doc! {
+            "eeg": {"ready": true, "errors": [], "warnings": []},
+            "telii": {"ready": false, "errors": ["MONGOURI is not set"], "warnings": []},
+        });
+        assert_eq!(health(db).0.get_document("datasets").unwrap(), report.0.get_document("datasets").unwrap());
+        assert!(datasets.backend("eeg", Capability::Timeline).is_ok());
+    }
+}
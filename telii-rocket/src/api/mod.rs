@@ -1,3 +1,5 @@
 pub mod event_api;
 pub mod query_api;
-pub mod eeg_query_api;
\ No newline at end of file
+pub mod eeg_query_api;
+pub mod health_api;
+pub mod cohort_api;
\ No newline at end of file
@@ -1,15 +1,289 @@
-use std::result;
+use crate::api::cohort_api::CohortParams;
+use crate::api::eeg_query_api::parse_event_groups;
+use crate::database::backend::{blocking, sorted_after, Backend, BackendError, Neighbors, Subjects};
+use crate::database::registry::{Capability, Datasets, TELII_DATASET};
+use crate::database::subjects::SubjectDictionary;
+use crate::models::cohort::SetOp;
+use crate::tel::cooccurrence::Cooccurrence;
+use crate::tel::elii::EliiExpr;
+use crate::tel::parser::parse_elii;
+use crate::tel::allen::{Anchor, GapBound, GapConstraint};
+use crate::tel::query::{Absence, TelQuery};
+use rocket::{http::{uri::Origin, ContentType, Status}, serde::json::{serde_json, Json}, Request, State};
+use rocket::futures::{future, stream::{self, BoxStream, StreamExt}};
+use rocket::response::{self, stream::ByteStream, Responder};
+use rocket::tokio::sync::mpsc;
+use crate::database::builder::subject_hash;
+use roaring::RoaringBitmap;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use maplit::hashmap;
+use mongodb::bson::{doc, Document};
 
-use crate::{models::event::Event, database::mongodb::MongoRepo};
-use mongodb::{bson::{doc, Document,Bson}, Collection, results::{self, InsertOneResult}};
-use rocket::{http::Status, serde::json::Json, State};
-use std::collections::HashSet;
+// what a patient query responds with, per its mode parameter: the patients (list, the default),
+// their number (count) or their number with a sample of n of them (sample, 10 unless given);
+// a list with limit or after is a page of the patients in name order, after the given one,
+// stream sends the patients after the given one as NDJSON, one JSON string per line
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+  List,
+  Count,
+  Sample(usize),
+  Page { limit: usize, after: Option<String> },
+  Stream { after: Option<String> },
+}
 
-// non-temporal query using elii: event list1 and event list2
-// input: event list1: vec of event ids, event list2: vec of event ids
-// output: vec of pt ids
-#[get("/elii?<event_id_list1>&<event_id_list2>")]
-pub fn elii(db: &State<MongoRepo>, event_id_list1: &str, event_id_list2: &str) -> Result<Json<Vec<String>>, Status> {
+// the patients of a page unless given a limit
+const PAGE_SIZE: usize = 1000;
+
+impl Mode {
+  pub fn parse(mode: Option<&str>, n: Option<usize>, limit: Option<usize>, after: Option<&str>) -> Result<Mode, Status> {
+    let after = after.map(str::to_string);
+    match mode.unwrap_or("list") {
+      "list" if limit.is_none() && after.is_none() => Ok(Mode::List),
+      "list" => match limit.unwrap_or(PAGE_SIZE) {
+        0 => {
+          println!("Error: a page of no patients");
+          Err(Status::BadRequest)
+        }
+        limit => Ok(Mode::Page { limit, after }),
+      },
+      "stream" => Ok(Mode::Stream { after }),
+      "count" => Ok(Mode::Count),
+      "sample" => Ok(Mode::Sample(n.unwrap_or(10))),
+      other => {
+        println!("Error: unknown mode {}", other);
+        Err(Status::BadRequest)
+      }
+    }
+  }
+
+  // pages and streams read the patients in name order, see ordered_patients
+  fn ordered(&self) -> bool {
+    matches!(self, Mode::Page { .. } | Mode::Stream { .. })
+  }
+
+  fn after(&self) -> Option<String> {
+    match self {
+      Mode::Page { after, .. } | Mode::Stream { after } => after.clone(),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Patients {
+  List(Vec<String>),
+  Count { count: u64 },
+  Sample { count: u64, sample: Vec<String> },
+  // next is the after of the next page, none on the last one
+  Page { patients: Vec<String>, next: Option<String> },
+}
+
+impl Patients {
+  pub fn new(mode: &Mode, ptids: Vec<String>) -> Self {
+    match mode {
+      Mode::List | Mode::Stream { .. } => Patients::List(ptids),
+      Mode::Page { limit, after } => {
+        let ptids = sorted_after(ptids, after.as_deref()).filter_map(Result::ok);
+        Patients::page(*limit, ptids)
+      }
+      Mode::Count => Patients::Count { count: ptids.len() as u64 },
+      Mode::Sample(n) => {
+        let count = ptids.len() as u64;
+        // the same patients for the same query, spread over the ids
+        let mut ptids: Vec<(u64, String)> = ptids.into_iter().map(|ptid| (subject_hash(&ptid), ptid)).collect();
+        ptids.sort();
+        let mut sample: Vec<String> = ptids.into_iter().take(*n).map(|(_, ptid)| ptid).collect();
+        sample.sort();
+        Patients::Sample { count, sample }
+      }
+    }
+  }
+
+  // the first limit of the patients, which are in name order
+  fn page(limit: usize, ptids: impl Iterator<Item = String>) -> Self {
+    let mut patients: Vec<String> = ptids.take(limit + 1).collect();
+    let next = match patients.len() > limit {
+      true => {
+        patients.truncate(limit);
+        patients.last().cloned()
+      }
+      false => None,
+    };
+    Patients::Page { patients, next }
+  }
+
+  // counts a bitmap without decoding it
+  fn from_bitmap(mode: &Mode, bitmap: &RoaringBitmap, dictionary: &SubjectDictionary) -> Self {
+    match mode {
+      Mode::Count => Patients::Count { count: bitmap.len() },
+      _ => Patients::new(mode, dictionary.decode(bitmap)),
+    }
+  }
+}
+
+// the patients of a bitmap over the dictionary, only those of the cohort if any, saved as a
+// cohort if asked to
+fn bitmap_patients(mode: &Mode, cohorts: &CohortParams, subjects: Option<&[String]>, mut bitmap: RoaringBitmap, dictionary: &SubjectDictionary) -> Result<Patients, Status> {
+  if let Some(subjects) = subjects {
+    bitmap &= dictionary.encode(subjects);
+  }
+  if cohorts.saving() {
+    cohorts.save(dictionary.decode(&bitmap))?;
+  }
+  Ok(Patients::from_bitmap(mode, &bitmap, dictionary))
+}
+
+// as bitmap_patients for a list of patients
+fn list_patients(mode: &Mode, cohorts: &CohortParams, subjects: Option<&[String]>, mut ptids: Vec<String>) -> Result<Patients, Status> {
+  if let Some(subjects) = subjects {
+    let subjects: HashSet<&String> = subjects.iter().collect();
+    ptids.retain(|ptid| subjects.contains(ptid));
+  }
+  if cohorts.saving() {
+    cohorts.save(ptids.clone())?;
+  }
+  Ok(Patients::new(mode, ptids))
+}
+
+pub enum PatientsResponse {
+  Json(Json<Patients>),
+  Stream(ByteStream<BoxStream<'static, Vec<u8>>>),
+}
+
+impl<'r> Responder<'r, 'r> for PatientsResponse {
+  fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+    match self {
+      PatientsResponse::Json(patients) => patients.respond_to(request),
+      PatientsResponse::Stream(lines) => {
+        let mut response = lines.respond_to(request)?;
+        response.set_header(ContentType::new("application", "x-ndjson"));
+        Ok(response)
+      }
+    }
+  }
+}
+
+impl From<Patients> for PatientsResponse {
+  fn from(patients: Patients) -> Self {
+    PatientsResponse::Json(Json(patients))
+  }
+}
+
+// hands the patients an ordered query reads to the page or stream, false once it has enough
+type Emit<'a> = &'a mut dyn FnMut(String) -> bool;
+
+fn emit_all(subjects: Subjects<'_>, emit: Emit<'_>) -> Result<(), Status> {
+  for subject in subjects {
+    if !emit(subject?) {
+      break;
+    }
+  }
+  Ok(())
+}
+
+// a page or stream of the patients a query reads in name order after the after of the mode,
+// only those of the cohort if any; the query is given the cohort subjects too
+async fn ordered_patients<F>(mode: Mode, cohorts: CohortParams, query: F) -> Result<PatientsResponse, Status>
+where F: FnOnce(Option<&[String]>, Emit<'_>) -> Result<(), Status> + Send + 'static {
+  if cohorts.saving() {
+    println!("Error: only a list of all the patients is saved as a cohort, not a page or stream");
+    return Err(Status::BadRequest);
+  }
+  let restricted = move |emit: Emit<'_>| match cohorts.subjects()? {
+    Some(subjects) => {
+      let cohort: HashSet<&String> = subjects.iter().collect();
+      query(Some(&subjects), &mut |subject| !cohort.contains(&subject) || emit(subject))
+    }
+    None => query(None, emit),
+  };
+  match mode {
+    Mode::Page { limit, .. } => {
+      let page = blocking(move || {
+        let mut patients = Vec::new();
+        restricted(&mut |subject| {
+          patients.push(subject);
+          patients.len() <= limit
+        })?;
+        Ok(Patients::page(limit, patients.into_iter()))
+      }).await?;
+      Ok(page.into())
+    }
+    _ => ndjson(restricted).await,
+  }
+}
+
+// the patients as NDJSON, sent as the query reads them on the blocking pool, which stops once the
+// client is gone; an error before the first patient is the status of the response, one after it
+// ends the stream
+async fn ndjson<F>(query: F) -> Result<PatientsResponse, Status>
+where F: FnOnce(Emit<'_>) -> Result<(), Status> + Send + 'static {
+  let (sender, mut receiver) = mpsc::channel::<Result<String, Status>>(256);
+  rocket::tokio::task::spawn_blocking(move || {
+    if let Err(status) = query(&mut |subject| sender.blocking_send(Ok(subject)).is_ok()) {
+      let _ = sender.blocking_send(Err(status));
+    }
+  });
+  let first = receiver.recv().await;
+  if let Some(Err(status)) = first {
+    return Err(status);
+  }
+  let rest = stream::unfold(receiver, |mut receiver| async move {
+    receiver.recv().await.map(|subject| (subject, receiver))
+  });
+  let lines = stream::iter(first).chain(rest)
+    .take_while(|subject| future::ready(subject.is_ok()))
+    .map(|subject| {
+      let mut line = serde_json::to_vec(&subject.unwrap_or_default()).unwrap_or_default();
+      line.push(b'\n');
+      line
+    });
+  Ok(PatientsResponse::Stream(ByteStream(lines.boxed())))
+}
+
+// non-temporal query using elii: event list1 and event list2, or a boolean expression over
+// events such as (250 OR 251) AND NOT 300 AND atleast(2, [10,11,12]), see parse_elii
+// input: event list1: vec of event ids, event list2: vec of event ids, or expr: expression,
+//        events: optional named groups of the expression, as "g1:1,2;g2:3"
+//        mode: list, count, sample or stream, n: size of the sample, limit and after: a page of
+//        limit patients after the given one, see Mode
+//        cohort: only the patients of this saved cohort, save: save the patients found as a cohort
+// output: vec of pt ids, {count}, {count, sample}, {patients, next} or NDJSON pt ids
+#[get("/elii?<event_id_list1>&<event_id_list2>&<expr>&<events>&<mode>&<n>&<limit>&<after>&<cohort>&<save>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn elii(db: &State<Datasets>, event_id_list1: Option<&str>, event_id_list2: Option<&str>, expr: Option<&str>, events: Option<&str>, mode: Option<&str>, n: Option<usize>, limit: Option<usize>, after: Option<&str>, cohort: Option<&str>, save: Option<&str>, uri: &Origin<'_>) -> Result<PatientsResponse, Status> {
+  dataset_elii(db, TELII_DATASET, event_id_list1, event_id_list2, expr, events, mode, n, limit, after, cohort, save, uri).await
+}
+
+#[get("/datasets/<dataset>/elii?<event_id_list1>&<event_id_list2>&<expr>&<events>&<mode>&<n>&<limit>&<after>&<cohort>&<save>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn dataset_elii(db: &State<Datasets>, dataset: &str, event_id_list1: Option<&str>, event_id_list2: Option<&str>, expr: Option<&str>, events: Option<&str>, mode: Option<&str>, n: Option<usize>, limit: Option<usize>, after: Option<&str>, cohort: Option<&str>, save: Option<&str>, uri: &Origin<'_>) -> Result<PatientsResponse, Status> {
+  let mode = Mode::parse(mode, n, limit, after)?;
+  let elii = db.backend(dataset, Capability::Elii)?;
+  let cohorts = CohortParams::new(db, dataset, cohort, save, uri)?;
+  if let Some(expr) = expr {
+    let expr = parse_elii_expr(expr, events.unwrap_or_default())?;
+    if mode.ordered() {
+      let after = mode.after();
+      return ordered_patients(mode, cohorts, move |subjects, emit| {
+        let (bitmap, dictionary) = elii_expr_bitmap(elii.as_ref(), &expr, subjects)?;
+        emit_all(sorted_after(dictionary.decode(&bitmap), after.as_deref()), emit)
+      }).await;
+    }
+    let patients = blocking(move || {
+      let subjects = cohorts.subjects()?;
+      let (bitmap, dictionary) = elii_expr_bitmap(elii.as_ref(), &expr, subjects.as_deref())?;
+      bitmap_patients(&mode, &cohorts, subjects.as_deref(), bitmap, &dictionary)
+    }).await?;
+    return Ok(patients.into());
+  }
+  let (event_id_list1, event_id_list2) = match (event_id_list1, event_id_list2) {
+    (Some(list1), Some(list2)) => (list1, list2),
+    _ => return Err(Status::BadRequest),
+  };
   let event_id_list1: Vec<i32> = event_id_list1.split(',')
       .filter_map(|s| s.parse().ok())
       .collect();
@@ -17,229 +291,609 @@ pub fn elii(db: &State<MongoRepo>, event_id_list1: &str, event_id_list2: &str) -
       .filter_map(|s| s.parse().ok())
       .collect();
 
-  let pipeline1 = vec![
-    doc! {"$match": {"id": {"$in": event_id_list1}}}
-  ];
-  let mut cursor = db.elii_col.aggregate(pipeline1, None).unwrap();
-  let mut ptid_list1: Vec<String> = Vec::new();
-  while let Some(result) = cursor.next() {
-    match result {
-      Ok(document) => {
-        // println!("document: {:?}", document);
-        // let ptid = document.get_str("_id").unwrap();
-        // results.push(ptid.to_string());
-        let new_ptid_list = document.get_array("ptid_list").unwrap();
-        for ptid in new_ptid_list {
-          ptid_list1.push(ptid.as_str().unwrap().to_string());
-        }
-      }
-      Err(e) => {
-        println!("Error getting ptid list");
-        return Err(Status::InternalServerError);
+  if mode.ordered() {
+    let after = mode.after();
+    return ordered_patients(mode, cohorts, move |_, emit| {
+      emit_all(elii.elii_ordered(&event_id_list1, &event_id_list2, after.as_deref())?, emit)
+    }).await;
+  }
+  let patients = blocking(move || {
+    let subjects = cohorts.subjects()?;
+    // intersect the subject bitmaps, or the subject sets without a subject dictionary
+    let bitmap = elii.elii_bitmap(&event_id_list1)
+      .and_then(|bitmap1| Ok(bitmap1 & elii.elii_bitmap(&event_id_list2)?))
+      .and_then(|bitmap| Ok((bitmap, elii.subject_dictionary()?)));
+    match bitmap {
+      Ok((bitmap, dictionary)) => bitmap_patients(&mode, &cohorts, subjects.as_deref(), bitmap, &dictionary),
+      Err(BackendError::Unsupported(_)) => {
+        let ptid_set1 = elii.elii_subjects(&event_id_list1)?;
+        let ptid_set2 = elii.elii_subjects(&event_id_list2)?;
+
+        let ptids = SetOp::Intersection.apply(&[ptid_set1, ptid_set2]).into_iter().collect();
+        list_patients(&mode, &cohorts, subjects.as_deref(), ptids)
       }
+      Err(e) => Err(e.into()),
     }
-  }
-  // println!("ptid_list1: {:?}", ptid_list1);
-
-  let pipeline2 = vec![
-    doc! {"$match": {"id": {"$in": event_id_list2}}}
-  ];
-  cursor = db.elii_col.aggregate(pipeline2, None).unwrap();
-  let mut ptid_list2: Vec<String> = Vec::new();
-  while let Some(result) = cursor.next() {
-    match result {
-      Ok(document) => {
-        // println!("document: {:?}", document);
-        // let ptid = document.get_str("_id").unwrap();
-        // results.push(ptid.to_string());
-        let new_ptid_list = document.get_array("ptid_list").unwrap();
-        for ptid in new_ptid_list {
-          ptid_list2.push(ptid.as_str().unwrap().to_string());
-        }
+  }).await?;
+
+  Ok(patients.into())
+}
+
+fn parse_elii_expr(expr: &str, events: &str) -> Result<EliiExpr, Status> {
+  let groups = parse_event_groups(events).ok_or(Status::BadRequest)?;
+  let resolved = parse_elii(expr)
+    .map_err(|e| e.to_string())
+    .and_then(|expr| expr.resolve(&groups));
+  resolved.map_err(|e| {
+    println!("Error parsing elii expression: {}", e);
+    Status::BadRequest
+  })
+}
+
+type Postings = HashMap<i32, RoaringBitmap>;
+
+// the postings of the events as bitmaps over the subject dictionary, and whether it is the
+// dataset's; without one a dictionary is made of the given subjects and those with any of the
+// events
+fn event_postings(elii: &dyn Backend, events: &[i32], subjects: Option<&[String]>) -> Result<(Postings, Arc<SubjectDictionary>, bool), Status> {
+  match dictionary_postings(elii, events) {
+    Ok((postings, dictionary)) => Ok((postings, dictionary, true)),
+    Err(BackendError::Unsupported(_)) => {
+      let mut postings = HashMap::new();
+      let mut dictionary = SubjectDictionary::new();
+      for subject in subjects.into_iter().flatten() {
+        dictionary.insert(subject);
       }
-      Err(e) => {
-        println!("Error getting ptid list");
-        return Err(Status::InternalServerError);
+      for event in events {
+        let mut subjects: Vec<String> = elii.elii_subjects(&[*event])?.into_iter().collect();
+        subjects.sort();
+        postings.insert(*event, subjects.iter().map(|subject| dictionary.insert(subject)).collect());
       }
+      Ok((postings, Arc::new(dictionary), false))
     }
+    Err(e) => Err(e.into()),
   }
-  // println!("ptid_list2: {:?}", ptid_list2);
+}
 
-  // Convert the Vec<String> to HashSet<String>
-  let ptid_set1: HashSet<_> = ptid_list1.into_iter().collect();
-  let ptid_set2: HashSet<_> = ptid_list2.into_iter().collect();
+fn dictionary_postings(elii: &dyn Backend, events: &[i32]) -> Result<(Postings, Arc<SubjectDictionary>), BackendError> {
+  let mut postings = HashMap::new();
+  for event in events {
+    postings.insert(*event, elii.elii_bitmap(&[*event])?);
+  }
+  // after the bitmaps, so the dictionary knows all of their subjects
+  Ok((postings, elii.subject_dictionary()?))
+}
 
-  // Find the intersection
-  let ptid_list: Vec<_> = ptid_set1.intersection(&ptid_set2).cloned().collect();
+// the expression over the event postings, NOT ranging over all subjects of the dictionary; one
+// made without the dataset's is enough unless the expression matches subjects with none of the
+// events and there is no cohort
+fn elii_expr_bitmap(elii: &dyn Backend, expr: &EliiExpr, subjects: Option<&[String]>) -> Result<(RoaringBitmap, Arc<SubjectDictionary>), Status> {
+  let events: Vec<i32> = expr.events().into_iter().collect();
+  let (postings, dictionary, complete) = event_postings(elii, &events, subjects)?;
+  if !complete && expr.matches_without_events() && subjects.is_none() {
+    println!("Error evaluating elii expression: it matches subjects without any of its events, which needs a subject dictionary or a cohort");
+    return Err(Status::BadRequest);
+  }
+  let universe: RoaringBitmap = (0..dictionary.len() as u32).collect();
+  Ok((expr.eval(&postings, &universe), dictionary))
+}
+
+// the events of a co-occurrence matrix, at most
+const MAX_MATRIX_EVENTS: usize = 500;
 
-  let results = ptid_list;
-  match results.len() {
-    _ => Ok(Json(results)),
-    // 0 => Err(Status::NotFound),
+pub enum MatrixResponse {
+  Json(Json<Cooccurrence>),
+  Csv(String),
+}
+
+impl<'r> Responder<'r, 'static> for MatrixResponse {
+  fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+    match self {
+      MatrixResponse::Json(matrix) => matrix.respond_to(request),
+      MatrixResponse::Csv(csv) => (ContentType::CSV, csv).respond_to(request),
+    }
   }
 }
 
+// patient co-occurrence of events, each pair counted by intersecting their elii postings, with
+// lift, jaccard and odds ratio, see Cooccurrence
+// input: event_ids: vec of event ids, format: json (the default) or csv, a row per pair
+// output: {events, subjects, counts, lift, jaccard, odds_ratio}, matrices in the order of the events
+#[get("/elii_cooccurrence?<event_ids>&<format>")]
+pub async fn elii_cooccurrence(db: &State<Datasets>, event_ids: &str, format: Option<&str>) -> Result<MatrixResponse, Status> {
+  dataset_elii_cooccurrence(db, TELII_DATASET, event_ids, format).await
+}
 
+#[get("/datasets/<dataset>/elii_cooccurrence?<event_ids>&<format>")]
+pub async fn dataset_elii_cooccurrence(db: &State<Datasets>, dataset: &str, event_ids: &str, format: Option<&str>) -> Result<MatrixResponse, Status> {
+  let csv = match format.unwrap_or("json") {
+    "json" => false,
+    "csv" => true,
+    other => {
+      println!("Error: unknown format {}", other);
+      return Err(Status::BadRequest);
+    }
+  };
+  let mut events: Vec<i32> = Vec::new();
+  for event in parse_ids(event_ids) {
+    if !events.contains(&event) {
+      events.push(event);
+    }
+  }
+  if events.is_empty() || events.len() > MAX_MATRIX_EVENTS {
+    println!("Error: a co-occurrence matrix takes 1 to {} events", MAX_MATRIX_EVENTS);
+    return Err(Status::BadRequest);
+  }
+  let elii = db.backend(dataset, Capability::Elii)?;
+  let matrix = blocking(move || {
+    let (mut postings, dictionary, complete) = event_postings(elii.as_ref(), &events, None)?;
+    let postings: Vec<RoaringBitmap> = events.iter().map(|event| postings.remove(event).unwrap_or_default()).collect();
+    let subjects = complete.then_some(dictionary.len() as u64);
+    Ok(Cooccurrence::new(events, &postings, subjects))
+  }).await?;
+  match csv {
+    true => Ok(MatrixResponse::Csv(matrix.to_csv())),
+    false => Ok(MatrixResponse::Json(Json(matrix))),
+  }
+}
 
 // relative temporal query: event list1 before event list2
 // input: event list1: vec of event ids, event list2: vec of event ids
+//        mode: list, count, sample or stream, n: size of the sample, limit and after: a page of
+//        limit patients after the given one, see Mode
+//        cohort: only the patients of this saved cohort, save: save the patients found as a cohort
+// output: vec of pt ids, {count}, {count, sample}, {patients, next} or NDJSON pt ids
+#[get("/rtq_telii?<event_id_list1>&<event_id_list2>&<category>&<mode>&<n>&<limit>&<after>&<cohort>&<save>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn rtq_telii(db: &State<Datasets>, event_id_list1: &str, event_id_list2: &str, category: Option<String>, mode: Option<&str>, n: Option<usize>, limit: Option<usize>, after: Option<&str>, cohort: Option<&str>, save: Option<&str>, uri: &Origin<'_>) -> Result<PatientsResponse, Status> {
+  dataset_rtq_telii(db, TELII_DATASET, event_id_list1, event_id_list2, category, mode, n, limit, after, cohort, save, uri).await
+}
+
+#[get("/datasets/<dataset>/rtq_telii?<event_id_list1>&<event_id_list2>&<category>&<mode>&<n>&<limit>&<after>&<cohort>&<save>")]
+#[allow(unused_variables, clippy::too_many_arguments)]
+pub async fn dataset_rtq_telii(db: &State<Datasets>, dataset: &str, event_id_list1: &str, event_id_list2: &str, category: Option<String>, mode: Option<&str>, n: Option<usize>, limit: Option<usize>, after: Option<&str>, cohort: Option<&str>, save: Option<&str>, uri: &Origin<'_>) -> Result<PatientsResponse, Status> {
+  let mode = Mode::parse(mode, n, limit, after)?;
+  let cohorts = CohortParams::new(db, dataset, cohort, save, uri)?;
+  if mode.ordered() {
+    let telii = db.backend(dataset, Capability::Telii)?;
+    let (event_id_list1, event_id_list2, after) = (parse_ids(event_id_list1), parse_ids(event_id_list2), mode.after());
+    return ordered_patients(mode, cohorts, move |_, emit| {
+      emit_all(telii.telii_ordered(&event_id_list1, &event_id_list2, after.as_deref())?, emit)
+    }).await;
+  }
+  if let (Mode::Count, true) = (&mode, cohorts.is_none()) {
+    let telii = db.backend(dataset, Capability::Telii)?;
+    let (event_id_list1, event_id_list2) = (parse_ids(event_id_list1), parse_ids(event_id_list2));
+    let count = blocking(move || Ok(telii.telii_count(&event_id_list1, &event_id_list2)?)).await?;
+    return Ok(Patients::Count { count }.into());
+  }
+  let ptids = telii_subjects(db, dataset, event_id_list1, event_id_list2).await?;
+  Ok(blocking(move || list_patients(&mode, &cohorts, cohorts.subjects()?.as_deref(), ptids)).await?.into())
+}
+
+fn parse_ids(event_id_list: &str) -> Vec<i32> {
+  event_id_list.split(',')
+      .filter_map(|s| s.parse().ok())
+      .collect()
+}
+
+// patients with an event of list1 before one of list2, from the telii pairs
+pub async fn telii_subjects(db: &Datasets, dataset: &str, event_id_list1: &str, event_id_list2: &str) -> Result<Vec<String>, Status> {
+  let telii = db.backend(dataset, Capability::Telii)?;
+  let event_id_list1: Vec<i32> = event_id_list1.split(',')
+      .filter_map(|s| s.parse().ok())
+      .collect();
+  let event_id_list2: Vec<i32> = event_id_list2.split(',')
+      .filter_map(|s| s.parse().ok())
+      .collect();
+
+  let ptid_set = blocking(move || Ok(telii.telii_before(&event_id_list1, &event_id_list2)?)).await?;
+  Ok(ptid_set.into_iter().collect())
+}
+
+// temporal neighbors of an event from telii: the k events most often before it or after it,
+// ranked by their number of patients
+// input: event_id: i32, direction: before or after, k: number of events (10 unless given),
+//        min_support: least number of patients of an event (1 unless given)
+// output: vec of {event_id, count}, most patients first
+#[get("/telii_neighbors?<event_id>&<direction>&<k>&<min_support>")]
+pub async fn telii_neighbors(db: &State<Datasets>, event_id: i32, direction: &str, k: Option<usize>, min_support: Option<u64>) -> Result<Json<Vec<Document>>, Status> {
+  dataset_telii_neighbors(db, TELII_DATASET, event_id, direction, k, min_support).await
+}
+
+#[get("/datasets/<dataset>/telii_neighbors?<event_id>&<direction>&<k>&<min_support>")]
+pub async fn dataset_telii_neighbors(db: &State<Datasets>, dataset: &str, event_id: i32, direction: &str, k: Option<usize>, min_support: Option<u64>) -> Result<Json<Vec<Document>>, Status> {
+  let neighbors = match direction {
+    "before" => Neighbors::Predecessors,
+    "after" => Neighbors::Successors,
+    other => {
+      println!("Error: unknown direction {}, expected before or after", other);
+      return Err(Status::BadRequest);
+    }
+  };
+  let (k, min_support) = (k.unwrap_or(10), min_support.unwrap_or(1));
+  if k == 0 {
+    println!("Error: k of no events");
+    return Err(Status::BadRequest);
+  }
+  let telii = db.backend(dataset, Capability::Telii)?;
+  let ranked = blocking(move || Ok(telii.telii_neighbors(event_id, neighbors, k, min_support)?)).await?;
+  Ok(Json(ranked.into_iter().map(|(event_id, count)| doc!{"event_id": event_id, "count": count as i64}).collect()))
+}
+
+// relative temporal query with time interval: event list1 before event list2
+// input: event list1: vec of event ids, event list2: vec of event ids, gt: i32 time interval greater than in days, lt: i32 time interval less than in days, 0 <= gt < lt
 // output: vec of pt ids
-#[get("/rtq_telii?<event_id_list1>&<event_id_list2>&<category>")]
-pub fn rtq_telii(db: &State<MongoRepo>, event_id_list1: &str, event_id_list2: &str, category: Option<String>) -> Result<Json<Vec<String>>, Status> {
-  let return_type = "list";
+#[get("/rtqti_telii?<event_id_list1>&<event_id_list2>&<gt>&<lt>")]
+pub async fn rtqti_telii(db: &State<Datasets>, event_id_list1: &str, event_id_list2: &str, gt: i32, lt: i32) -> Result<Json<Vec<String>>, Status> {
+  dataset_rtqti_telii(db, TELII_DATASET, event_id_list1, event_id_list2, gt, lt).await
+}
+
+#[get("/datasets/<dataset>/rtqti_telii?<event_id_list1>&<event_id_list2>&<gt>&<lt>")]
+pub async fn dataset_rtqti_telii(db: &State<Datasets>, dataset: &str, event_id_list1: &str, event_id_list2: &str, gt: i32, lt: i32) -> Result<Json<Vec<String>>, Status> {
+  // a gap of more than gt >= 0 days has event list2 start strictly after event list1, as the
+  // telii pairs the patients are taken from
+  if gt < 0 || lt <= gt {
+    println!("Error: a time interval of more than {} and less than {} days", gt, lt);
+    return Err(Status::BadRequest);
+  }
+  let timelines = db.backend(dataset, Capability::Timeline)?;
+  // patients with event list1 before event list2 from the telii pairs
+  let ptid_list = telii_subjects(db, dataset, event_id_list1, event_id_list2).await?;
+  if ptid_list.is_empty() {
+    return Ok(Json(ptid_list));
+  }
+
+  // check the time interval on the timelines of these patients only:
+  // event list2 starts more than gt and less than lt days after event list1 ends
+  let day_ms = 24.0 * 60.0 * 60.0 * 1000.0;
+  let gap = GapConstraint {
+    a: "e1",
+    from: Anchor::End,
+    b: "e2",
+    to: Anchor::Start,
+    min: Some(GapBound { value: f64::from(gt) * day_ms, open: true }),
+    max: Some(GapBound { value: f64::from(lt) * day_ms, open: true }),
+  };
   let event_id_list1: Vec<i32> = event_id_list1.split(',')
       .filter_map(|s| s.parse().ok())
       .collect();
   let event_id_list2: Vec<i32> = event_id_list2.split(',')
       .filter_map(|s| s.parse().ok())
       .collect();
-  
-  let mut or_stmt: Vec<Document> = Vec::new();
-  for event_id2 in &event_id_list2 {
-    let mut _tmp_event_id1s: Vec<i32> = Vec::new();
-    for event_id1 in &event_id_list1 {
-      if event_id1 < event_id2 {
-        _tmp_event_id1s.push(*event_id1);
+  let events = hashmap!{
+    "e1" => event_id_list1,
+    "e2" => event_id_list2,
+  };
+  let (bindings, exps) = gap.encode("t", "u");
+  let matches = blocking(move || Ok(timelines.tel_matches(&events, &bindings.into_iter().collect(), &exps, Some(&ptid_list))?)).await?;
+  let results: BTreeSet<String> = matches.into_iter().map(|m| m.subjectid).collect();
+  Ok(Json(results.into_iter().collect()))
+}
+
+// absence query: event list1 not followed by event list2, optionally within days after; as the
+// TEL absence "e1 without e2", a patient qualifies if some occurrence of list1 is not followed
+// input: event list1: vec of event ids, event list2: vec of event ids, days: i32 window in days
+// output: vec of pt ids
+#[get("/rtq_absence_telii?<event_id_list1>&<event_id_list2>&<days>")]
+pub async fn rtq_absence_telii(db: &State<Datasets>, event_id_list1: &str, event_id_list2: &str, days: Option<i32>) -> Result<Json<Vec<String>>, Status> {
+  dataset_rtq_absence_telii(db, TELII_DATASET, event_id_list1, event_id_list2, days).await
+}
+
+#[get("/datasets/<dataset>/rtq_absence_telii?<event_id_list1>&<event_id_list2>&<days>")]
+pub async fn dataset_rtq_absence_telii(db: &State<Datasets>, dataset: &str, event_id_list1: &str, event_id_list2: &str, days: Option<i32>) -> Result<Json<Vec<String>>, Status> {
+  let ids1: Vec<i32> = event_id_list1.split(',')
+      .filter_map(|s| s.parse().ok())
+      .collect();
+  let ids2: Vec<i32> = event_id_list2.split(',')
+      .filter_map(|s| s.parse().ok())
+      .collect();
+  // patients with event list1 but not list2 qualify, those with both are checked on their
+  // timelines: a TELII pair needs a strictly earlier start, so it misses list2 on the same date
+  let elii = db.backend(dataset, Capability::Elii)?;
+  let (event_ids1, event_ids2) = (ids1.clone(), ids2.clone());
+  let (mut results, candidates): (Vec<String>, Vec<String>) = blocking(move || {
+    let bitmaps = elii.elii_bitmap(&event_ids1)
+      .and_then(|bitmap| Ok((bitmap, elii.elii_bitmap(&event_ids2)?, elii.subject_dictionary()?)));
+    match bitmaps {
+      Ok((bitmap1, bitmap2, dictionary)) => Ok((dictionary.decode(&(&bitmap1 - &bitmap2)), dictionary.decode(&(bitmap1 & bitmap2)))),
+      Err(BackendError::Unsupported(_)) => {
+        let ptid_set = elii.elii_subjects(&event_ids1)?;
+        let both: HashSet<String> = elii.elii_subjects(&event_ids2)?;
+        Ok((ptid_set.difference(&both).cloned().collect(), ptid_set.intersection(&both).cloned().collect()))
       }
+      Err(e) => Err(e.into()),
     }
-    if _tmp_event_id1s.len() > 0 {
-      let _stmt = doc! {"e": event_id2,"b": { "$in": _tmp_event_id1s}};
-      or_stmt.push(_stmt);
+  }).await?;
+  // patients with both qualify if some occurrence of list1 is not followed, or event list2 only
+  // comes before it or later than the window
+  if candidates.is_empty() {
+    return Ok(Json(results));
+  }
+  let day_ms = 24.0 * 60.0 * 60.0 * 1000.0;
+  let absence = Absence::new("e1", "e2", days.map(|days| f64::from(days) * day_ms));
+  let events = hashmap!{
+    "e1" => ids1,
+    "e2" => ids2,
+  };
+  let timelines = db.backend(dataset, Capability::Timeline)?;
+  results.extend(blocking(move || Ok(timelines.query_subjects(&events, &TelQuery::Absence(absence), Some(&candidates))?)).await?);
+  Ok(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::eeg_query_api::eeg_absence_query;
+  use crate::database::backend::MemoryBackend;
+  use crate::database::registry::EEG_DATASET;
+  use crate::tel::eval::Timelines;
+
+  fn timelines() -> Timelines {
+    let day = 24 * 60 * 60 * 1000;
+    hashmap!{
+      "p1".to_string() => hashmap!{ 53 => vec![(0, 0)], 941 => vec![(10 * day, 10 * day)] },
+      "p2".to_string() => hashmap!{ 53 => vec![(0, 0)], 941 => vec![(100 * day, 100 * day)] },
+      "p3".to_string() => hashmap!{ 53 => vec![(0, 0)] },
+      "p4".to_string() => hashmap!{ 941 => vec![(0, 0)], 53 => vec![(5 * day, 5 * day)] },
     }
   }
-  for event_id1 in &event_id_list1 {
-    let mut _tmp_event_id2s: Vec<i32> = Vec::new();
-    for event_id2 in &event_id_list2 {
-      if event_id2 < event_id1 {
-        _tmp_event_id2s.push(*event_id2);
-      }
+
+  fn datasets() -> Datasets {
+    datasets_of(timelines(), &[TELII_DATASET, "study"])
+  }
+
+  // the same timelines in each of the datasets, with every capability they serve
+  fn datasets_of(timelines: Timelines, names: &[&str]) -> Datasets {
+    let mut datasets = Datasets::new();
+    let capabilities = BTreeSet::from([Capability::Elii, Capability::Telii, Capability::Timeline, Capability::Cohorts]);
+    for name in names {
+      datasets.insert(name, capabilities.clone(), Box::new(MemoryBackend::new(timelines.clone())));
     }
-    if _tmp_event_id2s.len() > 0 {
-      let _stmt = doc! {"e": event_id1,"a": { "$in": _tmp_event_id2s}};
-      or_stmt.push(_stmt);
-    }
-  }
-
-  let node_query = doc! {"$or": or_stmt};
-  let mut pipeline = vec![
-    doc! {"$match": node_query},
-    // doc! {"$group": {"_id": "$pg", "ptid_list": {"$addToSet": "$PTID"}}}
-    // doc! {"$group": {"_id": "$pg", "ptid_list": {"$sum": 1}}}
-  ];
-  if return_type == "list" {
-    // push group statement
-    pipeline.push(doc! {"$group": {"_id": "$pg", "ptid_list": {"$addToSet": "$PTID"}}});
-  }else if return_type == "num"{
-    pipeline.push(doc! {"$group": {"_id": "$PTID"}});
-    pipeline.push(doc! {"$group": {"_id": Bson::Null, "n": {"$sum": 1}}});
-  }
-  let mut cursor = db.telii_col.aggregate(pipeline, None).unwrap();
-  let mut ptid_list: Vec<String> = Vec::new();
-  while let Some(result) = cursor.next() {
-    match result {
-      Ok(document) => {
-        // println!("document: {:?}", document);
-        if return_type == "list" {
-          let new_ptid_list = document.get_array("ptid_list").unwrap();
-          for ptid in new_ptid_list {
-            ptid_list.push(ptid.as_str().unwrap().to_string());
-          }
-        }else if return_type == "num" {
-          let n = document.get_i32("n").unwrap();
-          ptid_list.push(n.to_string());
-        }
-      }
-      Err(e) => {
-        println!("Error getting ptid list");
-        return Err(Status::InternalServerError);
-      }
+    datasets
+  }
+
+  // the parameters of a patient query, none unless given
+  #[derive(Default)]
+  struct Query<'a> {
+    dataset: Option<&'a str>,
+    list1: Option<&'a str>,
+    list2: Option<&'a str>,
+    expr: Option<&'a str>,
+    events: Option<&'a str>,
+    mode: Option<&'a str>,
+    n: Option<usize>,
+    limit: Option<usize>,
+    after: Option<&'a str>,
+    cohort: Option<&'a str>,
+    save: Option<&'a str>,
+    uri: Option<&'a str>,
+  }
+
+  impl<'a> Query<'a> {
+    fn lists(list1: &'a str, list2: &'a str) -> Self {
+      Query { list1: Some(list1), list2: Some(list2), ..Query::default() }
+    }
+
+    fn expr(expr: &'a str) -> Self {
+      Query { expr: Some(expr), ..Query::default() }
+    }
+
+    fn uri(&self) -> Origin<'a> {
+      Origin::parse(self.uri.unwrap_or("/elii")).unwrap()
+    }
+
+    async fn elii(&self, db: &State<Datasets>) -> Result<PatientsResponse, Status> {
+      dataset_elii(db, self.dataset.unwrap_or(TELII_DATASET), self.list1, self.list2, self.expr, self.events,
+        self.mode, self.n, self.limit, self.after, self.cohort, self.save, &self.uri()).await
+    }
+
+    async fn rtq_telii(&self, db: &State<Datasets>) -> Result<PatientsResponse, Status> {
+      dataset_rtq_telii(db, self.dataset.unwrap_or(TELII_DATASET), self.list1.unwrap_or_default(), self.list2.unwrap_or_default(), None,
+        self.mode, self.n, self.limit, self.after, self.cohort, self.save, &self.uri()).await
     }
   }
 
-  let results = ptid_list;
-
-
-
-
-
-  // let mut before_nodes: Vec<i32> = Vec::new();
-  // let mut after_nodes: Vec<i32> = Vec::new();
-  // while let Some(result) = cursor.next() {
-  //   match result {
-  //     Ok(document) => {
-  //       // println!("document: {:?}", document);
-  //       let bflag = document.get_i32("_id").unwrap();
-  //       if bflag == 1 {
-  //         let node_list = document.get_array("node_list").unwrap();
-  //         for node in node_list {
-  //           before_nodes.push(node.as_i32().unwrap());
-  //         }
-  //       } else {
-  //         let node_list = document.get_array("node_list").unwrap();
-  //         for node in node_list {
-  //           after_nodes.push(node.as_i32().unwrap());
-  //         }
-  //       }
-  //     }
-  //     Err(e) => {
-  //       println!("Error getting node_list");
-  //       return Err(Status::InternalServerError);
-  //     }
-  //   }
-  // }
-  // println!("before_nodes: {:?}", before_nodes);
-  // println!("after_nodes: {:?}", after_nodes);
-
-  // // ap_stmt2 = [
-  // //     { "$match" : { "$or": [{ "event": {"$in": event_id_list1},"before_nodes": { "$in": before_node_list } }, { "event": {"$in": event_id_list1},"after_nodes": { "$in": after_node_list } }] }},
-  // //     { "$project": { "_id":0, 'PTID':1}},
-  // //     { "$group": {
-  // //       "_id":"$PTID"
-  // //       }
-  // //     }
-  // //   ]
-  // let pipeline2 = vec![
-  //   doc! {"$match": {"$or": [{ "event": {"$in": &event_id_list1},"before_nodes": { "$in": &before_nodes } }, { "event": {"$in": &event_id_list1},"after_nodes": { "$in": &after_nodes } }]}},
-  //   doc! {"$project": {"_id":0, "PTID":1}},
-  //   doc! {"$group": {"_id": "$PTID"}}
-  // ];
-  // let mut cursor2 = db.data_col.aggregate(pipeline2, None).unwrap();
-  // let mut results: Vec<String> = Vec::new();
-  // while let Some(result) = cursor2.next() {
-  //   match result {
-  //     Ok(document) => {
-  //       // println!("document: {:?}", document);
-  //       let ptid = document.get_str("_id").unwrap();
-  //       results.push(ptid.to_string());
-  //     }
-  //     Err(e) => {
-  //       println!("Error getting ptid");
-  //       return Err(Status::InternalServerError);
-  //     }
-  //   }
-  // }
-  match results.len() {
-    _ => Ok(Json(results)),
-    // 0 => Err(Status::NotFound),
+  fn strings(ptids: &[&str]) -> Vec<String> {
+    ptids.iter().map(|p| p.to_string()).collect()
   }
-}
 
-// relative temporal query with time interval: event list1 before event list2
-// input: event list1: vec of event ids, event list2: vec of event ids, gt: i32 time interval greater than in days, lt: i32 time interval less than in days
-// output: vec of pt ids
-// #[get("/rtqti_telii?<event_id_list1>&<event_id_list2>&<gt>&<lt>")]
-// pub fn rtqti_telii(db: &State<MongoRepo>, event_id_list1: &str, event_id_list2: &str, gt: i32, lt: i32) -> Result<Json<Vec<String>>, Status> {
-//   let results = vec![];
-//   // let ptid_list_json = rtq_telii(db, event_id_list1, event_id_list2);
-//   // // covert ptid_list_json to Vec<String>
-//   // let ptid_list: Vec<String> = match ptid_list_json {
-//   //   Ok(val) => val.0,
-//   //   Err(_) => vec![],
-//   // };
-//   // println!("ptid_list: {:?}", ptid_list.len());
-
-//   match results.len() {
-//     _ => Ok(Json(results)),
-//     // 0 => Err(Status::NotFound),
-//   }
-// }
+  fn sorted(ptids: Result<Json<Vec<String>>, Status>) -> Vec<String> {
+    let mut ptids = ptids.unwrap().0;
+    ptids.sort();
+    ptids
+  }
+
+  fn json(response: Result<PatientsResponse, Status>) -> Result<Patients, Status> {
+    match response? {
+      PatientsResponse::Json(patients) => Ok(patients.0),
+      PatientsResponse::Stream(_) => panic!("expected json, found a stream"),
+    }
+  }
+
+  // the patients of a list response, sorted
+  fn list(response: Result<PatientsResponse, Status>) -> Vec<String> {
+    match json(response).unwrap() {
+      Patients::List(ptids) => sorted(Ok(Json(ptids))),
+      patients => panic!("expected a list, found {:?}", patients),
+    }
+  }
+
+  #[test]
+  fn modes_parse_from_the_parameters() {
+    assert_eq!(Mode::parse(None, None, None, None), Ok(Mode::List));
+    assert_eq!(Mode::parse(Some("count"), Some(3), None, None), Ok(Mode::Count));
+    assert_eq!(Mode::parse(Some("sample"), None, None, None), Ok(Mode::Sample(10)));
+    assert_eq!(Mode::parse(Some("sample"), Some(2), None, None), Ok(Mode::Sample(2)));
+    assert_eq!(Mode::parse(None, None, Some(5), None), Ok(Mode::Page { limit: 5, after: None }));
+    assert_eq!(Mode::parse(Some("list"), None, None, Some("p1")), Ok(Mode::Page { limit: PAGE_SIZE, after: Some("p1".to_string()) }));
+    assert_eq!(Mode::parse(Some("stream"), None, None, Some("p1")), Ok(Mode::Stream { after: Some("p1".to_string()) }));
+    assert_eq!(Mode::parse(None, None, Some(0), None), Err(Status::BadRequest));
+    assert_eq!(Mode::parse(Some("all"), None, None, None), Err(Status::BadRequest));
+  }
+
+  #[test]
+  fn patients_take_the_shape_of_their_mode() {
+    let ptids = || strings(&["p3", "p1", "p4", "p2"]);
+    let page = |limit: usize, after: Option<&str>| Patients::new(&Mode::Page { limit, after: after.map(str::to_string) }, ptids());
+    let expected = |patients: &[&str], next: Option<&str>| Patients::Page { patients: strings(patients), next: next.map(str::to_string) };
+
+    assert_eq!(Patients::new(&Mode::List, ptids()), Patients::List(ptids()));
+    assert_eq!(Patients::new(&Mode::Count, ptids()), Patients::Count { count: 4 });
+    // pages are in name order, the last one without a next
+    assert_eq!(page(2, None), expected(&["p1", "p2"], Some("p2")));
+    assert_eq!(page(2, Some("p2")), expected(&["p3", "p4"], None));
+    assert_eq!(page(3, Some("p1")), expected(&["p2", "p3", "p4"], None));
+    assert_eq!(page(2, Some("p9")), expected(&[], None));
+
+    // the same sorted sample of a count, whatever the order of the patients
+    let sample = Patients::new(&Mode::Sample(2), ptids());
+    assert!(matches!(&sample, Patients::Sample { count: 4, sample } if sample.len() == 2 && sample.is_sorted()));
+    assert_eq!(Patients::new(&Mode::Sample(2), strings(&["p1", "p2", "p3", "p4"])), sample);
+    assert_eq!(Patients::new(&Mode::Sample(10), ptids()), Patients::Sample { count: 4, sample: strings(&["p1", "p2", "p3", "p4"]) });
+  }
+
+  #[rocket::async_test]
+  async fn queries_run_on_any_backend() {
+    let datasets = datasets();
+    let db = <&State<Datasets>>::from(&datasets);
+
+    assert_eq!(list(Query::lists("53", "941").elii(db).await), vec!["p1", "p2", "p4"]);
+    assert_eq!(list(Query::lists("53", "941").rtq_telii(db).await), vec!["p1", "p2"]);
+    assert_eq!(sorted(rtqti_telii(db, "53", "941", 30, 365).await), vec!["p2"]);
+    assert_eq!(rtqti_telii(db, "941", "53", -10, 365).await.err(), Some(Status::BadRequest));
+    assert_eq!(rtqti_telii(db, "53", "941", 30, 30).await.err(), Some(Status::BadRequest));
+    assert_eq!(sorted(rtq_absence_telii(db, "53", "941", None).await), vec!["p3", "p4"]);
+    assert_eq!(sorted(rtq_absence_telii(db, "53", "941", Some(30)).await), vec!["p2", "p3", "p4"]);
+    assert_eq!(list(Query { dataset: Some("study"), ..Query::lists("53", "941") }.rtq_telii(db).await), vec!["p1", "p2"]);
+    assert_eq!(Query { dataset: Some("unknown"), ..Query::lists("53", "941") }.elii(db).await.err(), Some(Status::NotFound));
+
+    // boolean expressions, NOT ranging over all subjects of the dataset
+    assert_eq!(list(Query::expr("53 AND NOT 941").elii(db).await), vec!["p3"]);
+    assert_eq!(list(Query { events: Some("g:941"), ..Query::expr("not g") }.elii(db).await), vec!["p3"]);
+    assert_eq!(list(Query::expr("atleast(2, [53, 941, 79])").elii(db).await), vec!["p1", "p2", "p4"]);
+    assert_eq!(Query::expr("53 AND g").elii(db).await.err(), Some(Status::BadRequest));
+    assert_eq!(Query { list1: Some("53"), ..Query::default() }.elii(db).await.err(), Some(Status::BadRequest));
+
+    // the mode reaches the patients of each path, see patients_take_the_shape_of_their_mode
+    assert_eq!(json(Query { mode: Some("count"), ..Query::lists("53", "941") }.elii(db).await), Ok(Patients::Count { count: 3 }));
+    assert_eq!(json(Query { mode: Some("count"), ..Query::lists("53", "941") }.rtq_telii(db).await), Ok(Patients::Count { count: 2 }));
+    let sample = json(Query { mode: Some("sample"), n: Some(2), ..Query::expr("53") }.elii(db).await).unwrap();
+    assert!(matches!(&sample, Patients::Sample { count: 4, sample } if sample.len() == 2));
+    assert_eq!(Query { mode: Some("all"), ..Query::lists("53", "941") }.rtq_telii(db).await.err(), Some(Status::BadRequest));
+  }
+
+  #[rocket::async_test]
+  async fn queries_save_and_restrict_to_cohorts() {
+    let datasets = datasets();
+    let db = <&State<Datasets>>::from(&datasets);
+    let both = |query: Query<'static>| Query { cohort: Some("both"), ..query };
+
+    let save = Query { mode: Some("count"), save: Some("no941"), uri: Some("/elii?expr=53%20AND%20NOT%20941&save=no941"), ..Query::expr("53 AND NOT 941") };
+    assert_eq!(json(save.elii(db).await), Ok(Patients::Count { count: 1 }));
+    assert_eq!(list(Query { save: Some("both"), ..Query::lists("53", "941") }.elii(db).await), vec!["p1", "p2", "p4"]);
+    assert_eq!(list(both(Query::lists("53", "941")).rtq_telii(db).await), vec!["p1", "p2"]);
+    assert_eq!(json(Query { mode: Some("count"), ..both(Query::lists("941", "53")) }.rtq_telii(db).await), Ok(Patients::Count { count: 1 }));
+    assert_eq!(list(both(Query::expr("NOT 53")).elii(db).await), Vec::<String>::new());
+    assert_eq!(Query { save: Some("both"), ..Query::lists("53", "941") }.elii(db).await.err(), Some(Status::Conflict));
+    assert_eq!(Query { cohort: Some("unknown"), ..Query::lists("53", "941") }.elii(db).await.err(), Some(Status::NotFound));
+
+    let cohort = datasets.backend(TELII_DATASET, Capability::Cohorts).unwrap().cohort(TELII_DATASET, "no941").unwrap().unwrap();
+    assert_eq!((cohort.subjects, cohort.query.as_str()), (vec!["p3".to_string()], "/elii?expr=53%20AND%20NOT%20941&save=no941"));
+    // cohorts belong to their dataset
+    assert_eq!(Query { dataset: Some("study"), ..both(Query::lists("53", "941")) }.rtq_telii(db).await.err(), Some(Status::NotFound));
+  }
+
+  #[rocket::async_test]
+  async fn queries_page_and_stream_patients() {
+    let datasets = datasets();
+    let db = <&State<Datasets>>::from(&datasets);
+    let page = |patients: &[&str], next: Option<&str>| Patients::Page { patients: strings(patients), next: next.map(str::to_string) };
+
+    // the ordered paths of the backend, elii over lists and expressions and rtq_telii
+    assert_eq!(json(Query { limit: Some(2), after: Some("p2"), ..Query::lists("53", "941") }.elii(db).await), Ok(page(&["p4"], None)));
+    assert_eq!(json(Query { limit: Some(3), after: Some("p1"), ..Query::expr("53") }.elii(db).await), Ok(page(&["p2", "p3", "p4"], None)));
+    assert_eq!(json(Query { limit: Some(1), ..Query::lists("53", "941") }.rtq_telii(db).await), Ok(page(&["p1"], Some("p1"))));
+
+    let lines = match (Query { mode: Some("stream"), after: Some("p1"), ..Query::lists("53", "941") }).elii(db).await {
+      Ok(PatientsResponse::Stream(lines)) => lines.0.collect::<Vec<Vec<u8>>>().await.concat(),
+      _ => panic!("expected a stream"),
+    };
+    assert_eq!(String::from_utf8(lines).unwrap(), "\"p2\"\n\"p4\"\n");
+    assert_eq!(Query { mode: Some("stream"), save: Some("all"), ..Query::lists("53", "941") }.rtq_telii(db).await.err(), Some(Status::BadRequest));
+  }
+
+  #[rocket::async_test]
+  async fn cooccurrence_of_events() {
+    let datasets = datasets();
+    let db = <&State<Datasets>>::from(&datasets);
+
+    let matrix = match elii_cooccurrence(db, "53,941,53", None).await {
+      Ok(MatrixResponse::Json(matrix)) => matrix.0,
+      _ => panic!("expected json"),
+    };
+    assert_eq!((matrix.events, matrix.subjects, matrix.counts), (vec![53, 941], Some(4), vec![vec![4, 3], vec![3, 3]]));
+    assert_eq!((matrix.lift[0][1], matrix.jaccard[0][1]), (Some(1.0), Some(0.75)));
+    match elii_cooccurrence(db, "53,941", Some("csv")).await {
+      Ok(MatrixResponse::Csv(csv)) => assert_eq!(csv.lines().nth(2), Some("53,941,3,1,0.75,")),
+      _ => panic!("expected csv"),
+    }
+    assert_eq!(elii_cooccurrence(db, "53", Some("xml")).await.err(), Some(Status::BadRequest));
+    assert_eq!(elii_cooccurrence(db, "", None).await.err(), Some(Status::BadRequest));
+  }
+
+  #[rocket::async_test]
+  async fn neighbors_of_events() {
+    let datasets = datasets();
+    let db = <&State<Datasets>>::from(&datasets);
+    let ranked = |response: Result<Json<Vec<Document>>, Status>| -> Vec<(i32, i64)> {
+      response.unwrap().0.iter().map(|d| (d.get_i32("event_id").unwrap(), d.get_i64("count").unwrap())).collect()
+    };
+
+    assert_eq!(ranked(telii_neighbors(db, 53, "after", None, None).await), vec![(941, 2)]);
+    assert_eq!(ranked(telii_neighbors(db, 53, "before", None, None).await), vec![(941, 1)]);
+    assert_eq!(ranked(telii_neighbors(db, 53, "after", None, Some(3)).await), vec![]);
+    assert_eq!(telii_neighbors(db, 53, "around", None, None).await.err(), Some(Status::BadRequest));
+  }
+
+  #[rocket::async_test]
+  async fn absence_is_the_same_on_every_path() {
+    // the first 53 of p5 is followed by 941, the second is not; p6 has 941 on the date of 53,
+    // which follows it although they make no TELII pair
+    let day = 24 * 60 * 60 * 1000;
+    let timelines: Timelines = hashmap!{
+      "p5".to_string() => hashmap!{ 53 => vec![(0, 0), (200 * day, 200 * day)], 941 => vec![(30 * day, 30 * day)] },
+      "p6".to_string() => hashmap!{ 53 => vec![(10 * day, 10 * day)], 941 => vec![(10 * day, 10 * day)] },
+    };
+    let datasets = datasets_of(timelines, &[TELII_DATASET, EEG_DATASET]);
+    let db = <&State<Datasets>>::from(&datasets);
+
+    assert_eq!(sorted(rtq_absence_telii(db, "53", "941", None).await), vec!["p5"]);
+    assert_eq!(sorted(rtq_absence_telii(db, "53", "941", Some(60)).await), vec!["p5"]);
+    let native = eeg_absence_query(db, "53", "941", None, None, None).await.unwrap().0;
+    assert_eq!(native.get_array("results").unwrap().len(), 1);
+  }
+
+  // a subject dictionary of none of the subjects, as read from a missing collection
+  struct StaleDictionary(MemoryBackend);
+
+  impl Backend for StaleDictionary {
+    fn elii_subjects(&self, event_ids: &[i32]) -> Result<HashSet<String>, BackendError> {
+      self.0.elii_subjects(event_ids)
+    }
+
+    fn subject_dictionary(&self) -> Result<Arc<SubjectDictionary>, BackendError> {
+      Ok(Arc::new(SubjectDictionary::new()))
+    }
+  }
+
+  #[rocket::async_test]
+  async fn elii_falls_back_without_the_subjects_in_the_dictionary() {
+    let mut datasets = Datasets::new();
+    datasets.insert(TELII_DATASET, BTreeSet::from([Capability::Elii]), Box::new(StaleDictionary(MemoryBackend::new(timelines()))));
+    let db = <&State<Datasets>>::from(&datasets);
+
+    assert_eq!(list(Query::lists("53", "941").elii(db).await), vec!["p1", "p2", "p4"]);
+    assert_eq!(list(Query::expr("53 AND NOT 941").elii(db).await), vec!["p3"]);
+  }
+}
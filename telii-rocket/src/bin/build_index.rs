@@ -0,0 +1,77 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::process;
+
+use telii_rocket::database::builder::{index_data, manifest, read_raw_events, CollectionNames};
+use telii_rocket::database::embedded::write_index;
+use telii_rocket::database::mongodb::{database, write_collections};
+
+const USAGE: &str = "usage: build_index <events.csv> --version <n> [--suffix <suffix>] [--db <database>] [--dir <index dir>] [--replace]";
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+// build the elii, telii, timeline and event collections of a dataset from a raw event table,
+// into a mongodb database (--db), an index directory (--dir) or both
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut source = None;
+    let (mut version, mut suffix, mut db_name, mut dir, mut replace) = (None, None, None, None, false);
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match args[i].as_str() {
+            "--version" => version = value.and_then(|v| v.parse::<u32>().ok()),
+            "--suffix" => suffix = value,
+            "--db" => db_name = value,
+            "--dir" => dir = value,
+            "--replace" => {
+                replace = true;
+                i += 1;
+                continue;
+            }
+            arg if source.is_none() && !arg.starts_with("--") => {
+                source = Some(arg.to_string());
+                i += 1;
+                continue;
+            }
+            _ => fail(USAGE.to_string()),
+        }
+        i += 2;
+    }
+    let (source, version) = match (source, version) {
+        (Some(source), Some(version)) if db_name.is_some() || dir.is_some() => (source, version),
+        _ => fail(USAGE.to_string()),
+    };
+
+    let file = File::open(&source).unwrap_or_else(|e| fail(format!("Error opening {}: {}", source, e)));
+    let raw = read_raw_events(BufReader::new(file)).unwrap_or_else(|e| fail(format!("Error reading {}: {}", source, e)));
+    let data = index_data(&raw);
+    let names = CollectionNames::new(version, suffix.as_deref());
+    let manifest = manifest(&names, version, suffix.as_deref(), &source, &raw, &data);
+    println!("{} rows, {} subjects, {} events, source hash {}", raw.rows, data.timelines.len(), data.elii.len(), manifest.get_str("source_hash").unwrap_or_default());
+
+    if let Some(db_name) = db_name {
+        let db = database(&db_name).unwrap_or_else(|e| fail(format!("Error connecting to {}: {}", db_name, e)));
+        match write_collections(&db, &names, &data, manifest.clone(), replace) {
+            Ok(true) => println!("built {}: {}, {}, {}, {}, {}", names.build_id(), names.event, names.elii, names.subject, names.telii, names.timeline),
+            Ok(false) => println!("build {} is up to date", names.build_id()),
+            Err(e) => fail(format!("Error writing {}: {} (pass --replace to overwrite)", db_name, e)),
+        }
+    }
+    if let Some(dir) = dir {
+        let dir = Path::new(&dir);
+        if let Err(e) = write_index(dir, &data) {
+            fail(format!("Error writing index: {}", e));
+        }
+        let mut bytes = Vec::new();
+        if manifest.to_writer(&mut bytes).is_err() || fs::write(dir.join("manifest.bson"), bytes).is_err() {
+            fail("Error writing manifest".to_string());
+        }
+        println!("built {} in {}", names.build_id(), dir.display());
+    }
+}
@@ -0,0 +1,32 @@
+use std::env;
+use std::path::Path;
+use std::process;
+
+use telii_rocket::database::embedded::write_index;
+use telii_rocket::database::mongodb::{EegMongoRepo, MongoRepo};
+
+// copy a mongodb dataset into an index directory the server can run from,
+// see TELII_INDEX_DIR and EEG_INDEX_DIR
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 || !["telii", "eeg"].contains(&args[1].as_str()) {
+        eprintln!("usage: export_index <telii|eeg> <index dir>");
+        process::exit(2);
+    }
+    let data = match args[1].as_str() {
+        "telii" => MongoRepo::init().and_then(|repo| repo.export_index()),
+        _ => EegMongoRepo::init().and_then(|repo| repo.export_index()),
+    };
+    let data = match data {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Error reading dataset: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = write_index(Path::new(&args[2]), &data) {
+        eprintln!("Error writing index: {}", e);
+        process::exit(1);
+    }
+    println!("{} subjects, {} elii and {} telii postings written to {}", data.timelines.len(), data.elii.len(), data.telii.len(), args[2]);
+}
@@ -0,0 +1,73 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::process;
+
+use mongodb::bson::Bson;
+use rocket::serde::json::serde_json;
+use telii_rocket::database::builder::{read_raw_events, CollectionNames};
+use telii_rocket::database::mongodb::{database, update_collections};
+
+const USAGE: &str = "usage: update_index <events.csv> --version <n> --db <database> [--suffix <suffix>] [--replace] [--report <report.json>]";
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+// apply new or changed events of some subjects, in the raw event table format of build_index,
+// to a build in a mongodb database; with --replace the table holds the whole timeline of its
+// subjects. The report lists the subjects, timelines, elii postings and telii pairs touched.
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut source = None;
+    let (mut version, mut suffix, mut db_name, mut report_path, mut replace) = (None, None, None, None, false);
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match args[i].as_str() {
+            "--version" => version = value.and_then(|v| v.parse::<u32>().ok()),
+            "--suffix" => suffix = value,
+            "--db" => db_name = value,
+            "--report" => report_path = value,
+            "--replace" => {
+                replace = true;
+                i += 1;
+                continue;
+            }
+            arg if source.is_none() && !arg.starts_with("--") => {
+                source = Some(arg.to_string());
+                i += 1;
+                continue;
+            }
+            _ => fail(USAGE.to_string()),
+        }
+        i += 2;
+    }
+    let (source, version, db_name) = match (source, version, db_name) {
+        (Some(source), Some(version), Some(db_name)) => (source, version, db_name),
+        _ => fail(USAGE.to_string()),
+    };
+
+    let file = File::open(&source).unwrap_or_else(|e| fail(format!("Error opening {}: {}", source, e)));
+    let raw = read_raw_events(BufReader::new(file)).unwrap_or_else(|e| fail(format!("Error reading {}: {}", source, e)));
+    let names = CollectionNames::new(version, suffix.as_deref());
+    let db = database(&db_name).unwrap_or_else(|e| fail(format!("Error connecting to {}: {}", db_name, e)));
+    let report = update_collections(&db, &names, &raw.timelines, replace, &source)
+        .unwrap_or_else(|e| fail(format!("Error updating {}: {}", names.build_id(), e)));
+    println!("updated {}: {} subjects, {} timelines, elii +{} -{}, telii pairs +{} -{}",
+        names.build_id(), report.subjects.len(), report.timelines.len(),
+        report.events_added.len(), report.events_removed.len(),
+        report.pairs_added.len(), report.pairs_removed.len());
+
+    let report = Bson::Document(report.to_document()).into_relaxed_extjson();
+    let report = serde_json::to_string_pretty(&report).unwrap_or_default();
+    match report_path {
+        Some(path) => {
+            if let Err(e) = fs::write(&path, report) {
+                fail(format!("Error writing {}: {}", path, e));
+            }
+        }
+        None => println!("{}", report),
+    }
+}
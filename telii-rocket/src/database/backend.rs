@@ -0,0 +1,363 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use crate::database::embedded::before_pairs;
+
+use mongodb::bson::Document;
+use roaring::RoaringBitmap;
+use rocket::http::Status;
+
+use crate::database::registry::Capability;
+use crate::database::subjects::SubjectDictionary;
+use crate::models::cohort::{Cohort, SetOp};
+use crate::models::event::Event;
+use crate::tel::eval::{evaluate, evaluate_query, TelMatch, Timelines};
+use crate::tel::exp::{TelError, TelExp};
+use crate::tel::query::TelQuery;
+
+#[derive(Debug)]
+pub enum BackendError {
+    // the backend does not store this kind of index
+    Unsupported(&'static str),
+    UnknownDataset(String),
+    // the dataset failed its startup checks or could not be opened
+    Unavailable(String),
+    // a cohort of this name exists
+    Exists(String),
+    Query(TelError),
+    Database(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::Unsupported(what) => write!(f, "{} is not supported by this backend", what),
+            BackendError::UnknownDataset(name) => write!(f, "no dataset named {}", name),
+            BackendError::Unavailable(e) => write!(f, "dataset unavailable: {}", e),
+            BackendError::Exists(name) => write!(f, "a cohort named {} exists", name),
+            BackendError::Query(e) => write!(f, "invalid TEL expression: {}", e),
+            BackendError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<TelError> for BackendError {
+    fn from(e: TelError) -> Self {
+        BackendError::Query(e)
+    }
+}
+
+impl From<mongodb::error::Error> for BackendError {
+    fn from(e: mongodb::error::Error) -> Self {
+        BackendError::Database(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for BackendError {
+    fn from(e: std::io::Error) -> Self {
+        BackendError::Database(e.to_string())
+    }
+}
+
+impl From<BackendError> for Status {
+    fn from(e: BackendError) -> Self {
+        println!("Error querying backend: {}", e);
+        match e {
+            BackendError::Unsupported(_) => Status::NotImplemented,
+            BackendError::UnknownDataset(_) => Status::NotFound,
+            BackendError::Unavailable(_) => Status::ServiceUnavailable,
+            BackendError::Exists(_) => Status::Conflict,
+            BackendError::Query(_) => Status::BadRequest,
+            BackendError::Database(_) => Status::InternalServerError,
+        }
+    }
+}
+
+// the events of the TELII pairs of an event telii_neighbors ranks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Neighbors {
+    // the events before it
+    Predecessors,
+    // the events after it
+    Successors,
+}
+
+// events with their number of subjects, most first, those with at least min_support of them,
+// the k first
+pub fn top_neighbors(counts: impl IntoIterator<Item = (i32, u64)>, k: usize, min_support: u64) -> Vec<(i32, u64)> {
+    let mut counts: Vec<(i32, u64)> = counts.into_iter().filter(|(_, count)| *count >= min_support).collect();
+    counts.sort_by(|(event1, count1), (event2, count2)| count2.cmp(count1).then(event1.cmp(event2)));
+    counts.truncate(k);
+    counts
+}
+
+// the subjects as a bitmap, unsupported if the dictionary does not know them all, so callers
+// fall back to the subjects themselves rather than dropping some
+pub fn encode_all(dictionary: &SubjectDictionary, subjects: &HashSet<String>) -> Result<RoaringBitmap, BackendError> {
+    let bitmap = dictionary.encode(subjects);
+    if bitmap.len() < subjects.len() as u64 {
+        return Err(BackendError::Unsupported("subject dictionary"));
+    }
+    Ok(bitmap)
+}
+
+// the key of a cohort held in memory, names being per dataset
+pub fn cohort_key(dataset: &str, name: &str) -> (String, String) {
+    (dataset.to_string(), name.to_string())
+}
+
+// subjects in name order, read as the backend finds them where it can
+pub type Subjects<'a> = Box<dyn Iterator<Item = Result<String, BackendError>> + 'a>;
+
+// the subjects after the given one, sorted
+pub fn sorted_after(subjects: impl IntoIterator<Item = String>, after: Option<&str>) -> Subjects<'static> {
+    let mut subjects: Vec<String> = subjects.into_iter().filter(|subject| Some(subject.as_str()) > after).collect();
+    subjects.sort();
+    Box::new(subjects.into_iter().map(Ok))
+}
+
+// Run backend work on the blocking thread pool: the mongodb driver and the index files are
+// synchronous, and must not hold up the async workers serving other requests.
+pub async fn blocking<T: Send + 'static>(work: impl FnOnce() -> Result<T, Status> + Send + 'static) -> Result<T, Status> {
+    match rocket::tokio::task::spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(e) => {
+            println!("Error running backend task: {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+// problems found checking a backend: errors make it unable to serve a capability, warnings
+// only slow it down
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Check {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Check {
+    pub fn failed(error: String) -> Self {
+        Check { errors: vec![error], warnings: Vec::new() }
+    }
+
+    pub fn ready(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+// Storage of the indexes the query apis read: the event catalog, ELII postings (event -> subjects),
+// TELII pairs (event -> events before and after it, per subject) and subject timelines.
+// Every index is optional; timeline queries fall back to the native evaluator over timelines.
+pub trait Backend: Send + Sync {
+    // whether the backend is reachable and has the collections and indexes the capabilities read
+    fn check(&self, _capabilities: &BTreeSet<Capability>) -> Check {
+        Check::default()
+    }
+
+    fn get_event(&self, _id: i32) -> Result<Option<Event>, BackendError> {
+        Err(BackendError::Unsupported("event catalog"))
+    }
+
+    // terms of the corpus matching the term, case insensitive
+    fn search_corpus(&self, _term: &str) -> Result<Vec<String>, BackendError> {
+        Err(BackendError::Unsupported("term corpus"))
+    }
+
+    // subjects with any of the events
+    fn elii_subjects(&self, _event_ids: &[i32]) -> Result<HashSet<String>, BackendError> {
+        Err(BackendError::Unsupported("elii"))
+    }
+
+    // subjects with an event of list1 before an event of list2
+    fn telii_before(&self, _event_ids1: &[i32], _event_ids2: &[i32]) -> Result<HashSet<String>, BackendError> {
+        Err(BackendError::Unsupported("telii"))
+    }
+
+    // subjects with an event of each list, after the given one in name order, for the pages and
+    // streams of /elii
+    fn elii_ordered(&self, event_ids1: &[i32], event_ids2: &[i32], after: Option<&str>) -> Result<Subjects<'_>, BackendError> {
+        let subjects = SetOp::Intersection.apply(&[self.elii_subjects(event_ids1)?, self.elii_subjects(event_ids2)?]);
+        Ok(sorted_after(subjects, after))
+    }
+
+    // telii_before after the given subject in name order, for the pages and streams of /rtq_telii
+    fn telii_ordered(&self, event_ids1: &[i32], event_ids2: &[i32], after: Option<&str>) -> Result<Subjects<'_>, BackendError> {
+        Ok(sorted_after(self.telii_before(event_ids1, event_ids2)?, after))
+    }
+
+    // the events most often before or after the event, by the subjects of their TELII pair with
+    // it, see top_neighbors
+    fn telii_neighbors(&self, _event_id: i32, _neighbors: Neighbors, _k: usize, _min_support: u64) -> Result<Vec<(i32, u64)>, BackendError> {
+        Err(BackendError::Unsupported("telii"))
+    }
+
+    // the number of subjects telii_before finds, without listing them where the backend can
+    fn telii_count(&self, event_ids1: &[i32], event_ids2: &[i32]) -> Result<u64, BackendError> {
+        Ok(self.telii_before(event_ids1, event_ids2)?.len() as u64)
+    }
+
+    // the dictionary subject bitmaps are encoded with, see database::subjects
+    fn subject_dictionary(&self) -> Result<Arc<SubjectDictionary>, BackendError> {
+        Err(BackendError::Unsupported("subject dictionary"))
+    }
+
+    // elii_subjects as a bitmap
+    fn elii_bitmap(&self, event_ids: &[i32]) -> Result<RoaringBitmap, BackendError> {
+        let dictionary = self.subject_dictionary()?;
+        encode_all(&dictionary, &self.elii_subjects(event_ids)?)
+    }
+
+    // telii_before as a bitmap
+    fn telii_bitmap(&self, event_ids1: &[i32], event_ids2: &[i32]) -> Result<RoaringBitmap, BackendError> {
+        let dictionary = self.subject_dictionary()?;
+        encode_all(&dictionary, &self.telii_before(event_ids1, event_ids2)?)
+    }
+
+    // timelines of the given subjects, or of all subjects, restricted to the given events
+    fn timelines(&self, _subjects: Option<&[String]>, _event_ids: &[i32]) -> Result<Timelines, BackendError> {
+        Err(BackendError::Unsupported("timelines"))
+    }
+
+    // matches of a conjunction of TEL expressions, see construct_query
+    fn tel_matches(&self, events: &HashMap<&str, Vec<i32>>, ts: &HashMap<&str, &str>, exps: &[TelExp], subjects: Option<&[String]>) -> Result<Vec<TelMatch>, BackendError> {
+        let event_ids: Vec<i32> = events.values().flatten().copied().collect();
+        let timelines = self.timelines(subjects, &event_ids)?;
+        Ok(evaluate(&timelines, events, ts, exps)?)
+    }
+
+    // subjects for which a query tree holds, see construct_bool_query
+    fn query_subjects(&self, events: &HashMap<&str, Vec<i32>>, query: &TelQuery, subjects: Option<&[String]>) -> Result<Vec<String>, BackendError> {
+        let event_ids: Vec<i32> = events.values().flatten().copied().collect();
+        let timelines = self.timelines(subjects, &event_ids)?;
+        Ok(evaluate_query(&timelines, events, query)?)
+    }
+
+    // how tel_matches would run, as {pipeline, explain}
+    fn explain_matches(&self, _events: &HashMap<&str, Vec<i32>>, _ts: &HashMap<&str, &str>, _exps: &[TelExp], _subjects: Option<&[String]>) -> Result<Document, BackendError> {
+        Err(BackendError::Unsupported("explain"))
+    }
+
+    // how query_subjects would run, as {pipeline, explain}
+    fn explain_subjects(&self, _events: &HashMap<&str, Vec<i32>>, _query: &TelQuery) -> Result<Document, BackendError> {
+        Err(BackendError::Unsupported("explain"))
+    }
+
+    // saved cohorts of a dataset, see models::cohort
+    fn cohort(&self, _dataset: &str, _name: &str) -> Result<Option<Cohort>, BackendError> {
+        Err(BackendError::Unsupported("cohorts"))
+    }
+
+    fn cohorts(&self, _dataset: &str) -> Result<Vec<Cohort>, BackendError> {
+        Err(BackendError::Unsupported("cohorts"))
+    }
+
+    // Exists if the name is taken in the dataset of the cohort, a saved cohort is not replaced
+    fn save_cohort(&self, _cohort: &Cohort) -> Result<(), BackendError> {
+        Err(BackendError::Unsupported("cohorts"))
+    }
+
+    // whether there was a cohort of this name
+    fn delete_cohort(&self, _dataset: &str, _name: &str) -> Result<bool, BackendError> {
+        Err(BackendError::Unsupported("cohorts"))
+    }
+}
+
+// timelines held in memory, for testing the apis without a database;
+// the ELII and TELII lookups are derived from the timelines
+#[cfg(test)]
+pub struct MemoryBackend {
+    pub timelines: Timelines,
+    // by dataset and name
+    cohorts: Mutex<BTreeMap<(String, String), Cohort>>,
+}
+
+#[cfg(test)]
+impl MemoryBackend {
+    pub fn new(timelines: Timelines) -> Self {
+        MemoryBackend { timelines, cohorts: Mutex::default() }
+    }
+}
+
+#[cfg(test)]
+impl Backend for MemoryBackend {
+    fn cohort(&self, dataset: &str, name: &str) -> Result<Option<Cohort>, BackendError> {
+        Ok(self.cohorts.lock().unwrap().get(&cohort_key(dataset, name)).cloned())
+    }
+
+    fn cohorts(&self, dataset: &str) -> Result<Vec<Cohort>, BackendError> {
+        Ok(self.cohorts.lock().unwrap().values().filter(|cohort| cohort.dataset == dataset).cloned().collect())
+    }
+
+    fn save_cohort(&self, cohort: &Cohort) -> Result<(), BackendError> {
+        let mut cohorts = self.cohorts.lock().unwrap();
+        let key = cohort_key(&cohort.dataset, &cohort.name);
+        if cohorts.contains_key(&key) {
+            return Err(BackendError::Exists(cohort.name.clone()));
+        }
+        cohorts.insert(key, cohort.clone());
+        Ok(())
+    }
+
+    fn delete_cohort(&self, dataset: &str, name: &str) -> Result<bool, BackendError> {
+        Ok(self.cohorts.lock().unwrap().remove(&cohort_key(dataset, name)).is_some())
+    }
+
+    fn subject_dictionary(&self) -> Result<Arc<SubjectDictionary>, BackendError> {
+        let mut subjects: Vec<String> = self.timelines.keys().cloned().collect();
+        subjects.sort();
+        Ok(Arc::new(SubjectDictionary::from_names(subjects)))
+    }
+
+    fn elii_subjects(&self, event_ids: &[i32]) -> Result<HashSet<String>, BackendError> {
+        Ok(self.timelines.iter()
+            .filter(|(_, timeline)| event_ids.iter().any(|id| timeline.contains_key(id)))
+            .map(|(subjectid, _)| subjectid.clone())
+            .collect())
+    }
+
+    fn telii_neighbors(&self, event_id: i32, neighbors: Neighbors, k: usize, min_support: u64) -> Result<Vec<(i32, u64)>, BackendError> {
+        let mut counts: HashMap<i32, u64> = HashMap::new();
+        for timeline in self.timelines.values() {
+            for (before, after) in before_pairs(timeline) {
+                match neighbors {
+                    Neighbors::Predecessors if after == event_id => *counts.entry(before).or_default() += 1,
+                    Neighbors::Successors if before == event_id => *counts.entry(after).or_default() += 1,
+                    _ => {}
+                }
+            }
+        }
+        Ok(top_neighbors(counts, k, min_support))
+    }
+
+    fn telii_before(&self, event_ids1: &[i32], event_ids2: &[i32]) -> Result<HashSet<String>, BackendError> {
+        let starts = |timeline: &crate::tel::eval::Timeline, ids: &[i32]| -> Vec<i64> {
+            ids.iter().filter_map(|id| timeline.get(id)).flatten().map(|(start, _)| *start).collect()
+        };
+        Ok(self.timelines.iter()
+            .filter(|(_, timeline)| {
+                let starts2 = starts(timeline, event_ids2);
+                starts(timeline, event_ids1).iter().any(|s1| starts2.iter().any(|s2| s1 < s2))
+            })
+            .map(|(subjectid, _)| subjectid.clone())
+            .collect())
+    }
+
+    fn timelines(&self, subjects: Option<&[String]>, event_ids: &[i32]) -> Result<Timelines, BackendError> {
+        Ok(self.timelines.iter()
+            .filter(|(subjectid, _)| subjects.is_none_or(|subjects| subjects.contains(subjectid)))
+            .map(|(subjectid, timeline)| {
+                let timeline = timeline.iter()
+                    .filter(|(id, _)| event_ids.contains(id))
+                    .map(|(id, intervals)| (*id, intervals.clone()))
+                    .collect();
+                (subjectid.clone(), timeline)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::BufRead;
+
+use mongodb::bson::{doc, spec::BinarySubtype, Binary, DateTime, Document};
+use roaring::RoaringBitmap;
+
+use crate::database::embedded::{before_pairs, IndexData};
+use crate::database::subjects::{bitmap_bytes, SubjectDictionary};
+use crate::models::event::Event;
+use crate::tel::eval::{Timeline, Timelines};
+
+// Building the collections the query apis read from a raw event table, one occurrence per line:
+//   subjectid,event_id,start[,end]
+// start and end are RFC 3339 times, dates (YYYY-MM-DD) or milliseconds since the epoch; a point
+// event has no end. A header line and blank lines are skipped.
+
+// telii $group partitions, keep the ptid_list of rtq_telii below the document size limit
+pub const TELII_PARTITIONS: u64 = 1024;
+
+// names of a build: "<kind>_v<version>[_<suffix>]" as elii_v4 or pt_timeline_v4_diag_gall_7
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectionNames {
+    pub event: String,
+    pub elii: String,
+    pub subject: String,
+    pub telii: String,
+    pub timeline: String,
+}
+
+impl CollectionNames {
+    pub fn new(version: u32, suffix: Option<&str>) -> Self {
+        let name = |kind: &str| match suffix {
+            Some(suffix) => format!("{}_v{}_{}", kind, version, suffix),
+            None => format!("{}_v{}", kind, version),
+        };
+        // elii, its subject dictionary and the event catalog do not depend on the suffix, as
+        // elii_v4, subject_v4 and event_v4
+        CollectionNames {
+            event: format!("event_v{}", version),
+            elii: format!("elii_v{}", version),
+            subject: format!("subject_v{}", version),
+            telii: name("telii"),
+            timeline: name("pt_timeline"),
+        }
+    }
+
+    // manifest key of the build
+    pub fn build_id(&self) -> String {
+        self.timeline.trim_start_matches("pt_timeline_").to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// the parsed table and a fingerprint of its content
+#[derive(Debug, Default)]
+pub struct RawEvents {
+    pub timelines: Timelines,
+    pub rows: usize,
+    pub hash: u64,
+}
+
+// time in milliseconds since the epoch
+pub fn parse_time(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if let Ok(ms) = text.parse::<i64>() {
+        return Some(ms);
+    }
+    if text.len() == 10 {
+        return DateTime::parse_rfc3339_str(format!("{}T00:00:00Z", text)).ok().map(|t| t.timestamp_millis());
+    }
+    DateTime::parse_rfc3339_str(text).ok().map(|t| t.timestamp_millis())
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// 64-bit FNV-1a, stable across builds and platforms
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3))
+}
+
+pub fn read_raw_events(reader: impl BufRead) -> Result<RawEvents, BuildError> {
+    let mut raw = RawEvents { hash: FNV_OFFSET, ..RawEvents::default() };
+    for (i, line) in reader.lines().enumerate() {
+        let error = |message: String| BuildError { line: i + 1, message };
+        let line = line.map_err(|e| error(e.to_string()))?;
+        raw.hash = fnv1a(raw.hash, line.as_bytes());
+        raw.hash = fnv1a(raw.hash, b"\n");
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if !(3..=4).contains(&fields.len()) {
+            return Err(error(format!("expected subjectid,event_id,start[,end], found {} fields", fields.len())));
+        }
+        let event: i32 = match fields[1].parse() {
+            Ok(event) => event,
+            // a header line
+            Err(_) if i == 0 => continue,
+            Err(_) => return Err(error(format!("invalid event id '{}'", fields[1]))),
+        };
+        let start = parse_time(fields[2]).ok_or_else(|| error(format!("invalid time '{}'", fields[2])))?;
+        let end = match fields.get(3).filter(|f| !f.is_empty()) {
+            Some(end) => parse_time(end).ok_or_else(|| error(format!("invalid time '{}'", end)))?,
+            None => start,
+        };
+        if end < start {
+            return Err(error("interval ends before it starts".to_string()));
+        }
+        raw.timelines.entry(fields[0].to_string()).or_default().entry(event).or_default().push((start, end));
+        raw.rows += 1;
+    }
+    for timeline in raw.timelines.values_mut() {
+        for intervals in timeline.values_mut() {
+            intervals.sort();
+            intervals.dedup();
+        }
+    }
+    Ok(raw)
+}
+
+// index data of the raw events, with an event catalog counting the patients of each event
+pub fn index_data(raw: &RawEvents) -> IndexData {
+    let mut data = IndexData::from_timelines(raw.timelines.clone());
+    data.events = data.elii.iter()
+        .map(|(id, ptids)| Event { id: *id, cov_diag: None, cov_obs: None, cov_proc: None, num_of_patients: ptids.len() as i32 })
+        .collect();
+    data
+}
+
+// {subjectid, e, times: [[start, end], ...]} per subject and event
+pub fn timeline_documents(data: &IndexData) -> Vec<Document> {
+    let subjects: BTreeSet<&String> = data.timelines.keys().collect();
+    let mut documents = Vec::new();
+    for subjectid in subjects {
+        let timeline: BTreeMap<&i32, _> = data.timelines[subjectid].iter().collect();
+        for (e, intervals) in timeline {
+            documents.push(doc! {"subjectid": subjectid, "e": e, "times": times(intervals)});
+        }
+    }
+    documents
+}
+
+// [[start, end], ...] of a timeline document
+pub fn times(intervals: &[(i64, i64)]) -> Vec<Vec<DateTime>> {
+    intervals.iter()
+        .map(|(start, end)| vec![DateTime::from_millis(*start), DateTime::from_millis(*end)])
+        .collect()
+}
+
+// {id, num_of_patients} per event, the model of event_api
+pub fn event_documents(data: &IndexData) -> Vec<Document> {
+    data.events.iter()
+        .map(|event| doc! {"id": event.id, "num_of_patients": event.num_of_patients})
+        .collect()
+}
+
+// {id, subjectid} per subject of the dictionary
+pub fn subject_documents(dictionary: &SubjectDictionary) -> Vec<Document> {
+    (0..dictionary.len() as u32)
+        .filter_map(|id| dictionary.name(id).map(|subjectid| doc! {"id": id as i64, "subjectid": subjectid}))
+        .collect()
+}
+
+// {id, ptid_list, ptid_bitmap} per event, ptid_bitmap being the ptid_list encoded with the dictionary
+pub fn elii_documents(data: &IndexData, dictionary: &SubjectDictionary) -> Vec<Document> {
+    data.elii.iter()
+        .map(|(id, ptids)| doc! {"id": id, "ptid_list": ptids.iter().collect::<Vec<_>>(), "ptid_bitmap": bitmap_binary(&dictionary.encode(ptids))})
+        .collect()
+}
+
+pub fn bitmap_binary(bitmap: &RoaringBitmap) -> Binary {
+    Binary { subtype: BinarySubtype::Generic, bytes: bitmap_bytes(bitmap) }
+}
+
+// {PTID, pg, e, b, a} per subject and event, see subject_telii_documents
+pub fn telii_documents(data: &IndexData) -> Vec<Document> {
+    let subjects: BTreeSet<&String> = data.timelines.keys().collect();
+    subjects.into_iter()
+        .flat_map(|subjectid| subject_telii_documents(subjectid, &data.timelines[subjectid]))
+        .collect()
+}
+
+// partition of a subject's telii documents, stable so updates keep it
+pub fn telii_partition(subjectid: &str) -> i32 {
+    (subject_hash(subjectid) % TELII_PARTITIONS) as i32
+}
+
+// a stable hash of a subject id, spreading subjects independently of their ids' order
+pub fn subject_hash(subjectid: &str) -> u64 {
+    fnv1a(FNV_OFFSET, subjectid.as_bytes())
+}
+
+// every pair is stored once under its larger event id, b holding the smaller events before e
+// and a the smaller events after it
+pub fn subject_telii_documents(subjectid: &str, timeline: &Timeline) -> Vec<Document> {
+    let mut records: BTreeMap<i32, (Vec<i32>, Vec<i32>)> = BTreeMap::new();
+    for (before, after) in before_pairs(timeline) {
+        if before < after {
+            records.entry(after).or_default().0.push(before);
+        } else {
+            records.entry(before).or_default().1.push(after);
+        }
+    }
+    records.into_iter()
+        .map(|(e, (b, a))| doc! {"PTID": subjectid, "pg": telii_partition(subjectid), "e": e, "b": b, "a": a})
+        .collect()
+}
+
+// what an update changed, by subject
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UpdateReport {
+    // subjects whose timeline changed
+    pub subjects: BTreeSet<String>,
+    // (subject, event) of changed timeline documents
+    pub timelines: BTreeSet<(String, i32)>,
+    // (subject, event) added to or removed from the elii postings
+    pub events_added: BTreeSet<(String, i32)>,
+    pub events_removed: BTreeSet<(String, i32)>,
+    // (subject, before, after) added to or removed from the telii pairs
+    pub pairs_added: BTreeSet<(String, i32, i32)>,
+    pub pairs_removed: BTreeSet<(String, i32, i32)>,
+}
+
+impl UpdateReport {
+    // record the change of a subject's timeline from old to new
+    pub fn add(&mut self, subjectid: &str, old: &Timeline, new: &Timeline) {
+        if old == new {
+            return;
+        }
+        self.subjects.insert(subjectid.to_string());
+        let events: BTreeSet<&i32> = old.keys().chain(new.keys()).collect();
+        for e in events {
+            match (old.get(e), new.get(e)) {
+                (None, Some(_)) => {
+                    self.events_added.insert((subjectid.to_string(), *e));
+                }
+                (Some(_), None) => {
+                    self.events_removed.insert((subjectid.to_string(), *e));
+                }
+                (a, b) if a == b => continue,
+                _ => {}
+            }
+            self.timelines.insert((subjectid.to_string(), *e));
+        }
+        let (old_pairs, new_pairs) = (before_pairs(old), before_pairs(new));
+        for (before, after) in new_pairs.difference(&old_pairs) {
+            self.pairs_added.insert((subjectid.to_string(), *before, *after));
+        }
+        for (before, after) in old_pairs.difference(&new_pairs) {
+            self.pairs_removed.insert((subjectid.to_string(), *before, *after));
+        }
+    }
+
+    pub fn to_document(&self) -> Document {
+        let pairs = |pairs: &BTreeSet<(String, i32, i32)>| -> Vec<Document> {
+            pairs.iter().map(|(s, before, after)| doc! {"subjectid": s, "before": before, "after": after}).collect()
+        };
+        let events = |events: &BTreeSet<(String, i32)>| -> Vec<Document> {
+            events.iter().map(|(s, e)| doc! {"subjectid": s, "e": e}).collect()
+        };
+        doc! {
+            "subjects": self.subjects.iter().collect::<Vec<_>>(),
+            "timelines": events(&self.timelines),
+            "elii": {"added": events(&self.events_added), "removed": events(&self.events_removed)},
+            "telii": {"added": pairs(&self.pairs_added), "removed": pairs(&self.pairs_removed)},
+        }
+    }
+}
+
+// the timeline of a subject after an update: the occurrences of the delta added to the old
+// ones, or with replace the delta as the subject's whole timeline
+pub fn updated_timeline(old: &Timeline, delta: &Timeline, replace: bool) -> Timeline {
+    let mut new = if replace { Timeline::new() } else { old.clone() };
+    for (e, intervals) in delta {
+        let occurrences = new.entry(*e).or_default();
+        occurrences.extend(intervals);
+        occurrences.sort();
+        occurrences.dedup();
+    }
+    new
+}
+
+// description of a build, stored with its collections
+pub fn manifest(names: &CollectionNames, version: u32, suffix: Option<&str>, source: &str, raw: &RawEvents, data: &IndexData) -> Document {
+    doc! {
+        "_id": names.build_id(),
+        "version": version,
+        "suffix": suffix,
+        "collections": {"event": &names.event, "elii": &names.elii, "subject": &names.subject, "telii": &names.telii, "timeline": &names.timeline},
+        "source": source,
+        "source_hash": format!("{:016x}", raw.hash),
+        "rows": raw.rows as i64,
+        "subjects": data.timelines.len() as i64,
+        "events": data.elii.len() as i64,
+        "built_at": DateTime::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENTS: &str = "subjectid,event_id,start,end\n\
+        p1,53,2020-01-01,\n\
+        p1,941,2020-01-05T10:00:00Z,2020-01-06T10:00:00Z\n\
+        p2,941,1577836800000\n\
+        p2,53,2020-02-01\n\
+        p2,53,2020-02-01\n";
+
+    #[test]
+    fn builds_collections_from_raw_events() {
+        let raw = read_raw_events(EVENTS.as_bytes()).unwrap();
+        assert_eq!(raw.rows, 5);
+        assert_eq!(raw.timelines["p2"][&53], vec![(1580515200000, 1580515200000)]);
+        let data = index_data(&raw);
+
+        let dictionary = data.dictionary();
+        assert_eq!(subject_documents(&dictionary), vec![doc! {"id": 0_i64, "subjectid": "p1"}, doc! {"id": 1_i64, "subjectid": "p2"}]);
+        let elii = &elii_documents(&data, &dictionary)[0];
+        assert_eq!(elii.get_array("ptid_list").unwrap(), &vec!["p1".into(), "p2".into()]);
+        assert_eq!(elii.get_binary_generic("ptid_bitmap").unwrap(), &bitmap_bytes(&RoaringBitmap::from_iter([0, 1])));
+        assert_eq!(timeline_documents(&data).len(), 4);
+        // p1: 53 before 941 is stored under 941 in b, p2: 941 before 53 under 941 in a
+        let telii = telii_documents(&data);
+        assert_eq!(telii, vec![
+            doc! {"PTID": "p1", "pg": telii_partition("p1"), "e": 941, "b": [53], "a": []},
+            doc! {"PTID": "p2", "pg": telii_partition("p2"), "e": 941, "b": [], "a": [53]},
+        ]);
+        assert_eq!(data.events.iter().map(|e| e.num_of_patients).collect::<Vec<_>>(), vec![2, 2]);
+    }
+
+    #[test]
+    fn names_and_fingerprints_are_reproducible() {
+        let names = CollectionNames::new(4, Some("diag_gall_7"));
+        assert_eq!(names.telii, "telii_v4_diag_gall_7");
+        assert_eq!(names.timeline, "pt_timeline_v4_diag_gall_7");
+        assert_eq!(names.elii, "elii_v4");
+        assert_eq!(names.build_id(), "v4_diag_gall_7");
+
+        let hash = read_raw_events(EVENTS.as_bytes()).unwrap().hash;
+        assert_eq!(read_raw_events(EVENTS.as_bytes()).unwrap().hash, hash);
+        assert_ne!(read_raw_events(EVENTS.replace("p2,53", "p3,53").as_bytes()).unwrap().hash, hash);
+        assert_eq!(read_raw_events("p1,53,yesterday".as_bytes()).unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn updates_report_touched_subjects_and_pairs() {
+        use maplit::hashmap;
+        let old: Timeline = hashmap!{ 53 => vec![(0, 0)], 941 => vec![(10, 10)] };
+        let delta: Timeline = hashmap!{ 300 => vec![(5, 5)], 53 => vec![(0, 0)] };
+
+        let appended = updated_timeline(&old, &delta, false);
+        assert_eq!(appended[&53], vec![(0, 0)]);
+        let mut report = UpdateReport::default();
+        report.add("p1", &old, &appended);
+        assert_eq!(report.events_added, BTreeSet::from([("p1".to_string(), 300)]));
+        assert_eq!(report.timelines, BTreeSet::from([("p1".to_string(), 300)]));
+        assert_eq!(report.pairs_added, BTreeSet::from([("p1".to_string(), 53, 300), ("p1".to_string(), 300, 941)]));
+        assert!(report.pairs_removed.is_empty());
+
+        let replaced = updated_timeline(&old, &delta, true);
+        let mut report = UpdateReport::default();
+        report.add("p1", &old, &replaced);
+        assert_eq!(report.events_removed, BTreeSet::from([("p1".to_string(), 941)]));
+        assert_eq!(report.pairs_removed, BTreeSet::from([("p1".to_string(), 53, 941)]));
+
+        let mut report = UpdateReport::default();
+        report.add("p1", &old, &updated_timeline(&old, &old, false));
+        assert_eq!(report, UpdateReport::default());
+    }
+}
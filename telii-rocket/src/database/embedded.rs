@@ -0,0 +1,587 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use memmap2::Mmap;
+use mongodb::bson::{self, Document};
+use roaring::RoaringBitmap;
+
+use crate::database::backend::{cohort_key, top_neighbors, Backend, BackendError, Check, Neighbors};
+use crate::database::registry::Capability;
+use crate::database::subjects::{bitmap_bytes, bitmap_from_bytes, SubjectDictionary};
+use crate::models::cohort::Cohort;
+use crate::models::event::Event;
+use crate::tel::eval::{Timeline, Timelines};
+
+// File-based index directory, read through memory maps. All integers are little endian and
+// subjects are referred to by their position in the sorted subject table, their id in the
+// subject dictionary.
+//
+// subjects.bin   "TLSUB001" n:u32 offsets:[u32; n+1] utf8 names
+// elii.bin       "TLELI002" n:u32 [event:i32 start:u32 len:u32; n] serialized roaring bitmaps
+// telii.bin      "TLTEL001" n:u32 [before:i32 after:i32 start:u32 len:u32; n] postings:[u32]
+// timelines.bin  "TLTIM001" n:u32 [start:u32 len:u32; n] [event:i32 start:i64 end:i64]
+// events.bson    event catalog as concatenated documents, optional
+// corpus.bson    term corpus as concatenated documents, optional
+// cohorts.bson   saved cohorts as concatenated documents, written by the server
+//
+// Directory entries are sorted by key and postings by subject, timeline records by event. The
+// start and len of an elii entry are in bytes, those of the others in items.
+
+const SUBJECTS_MAGIC: &[u8; 8] = b"TLSUB001";
+const ELII_MAGIC: &[u8; 8] = b"TLELI002";
+const TELII_MAGIC: &[u8; 8] = b"TLTEL001";
+const TIMELINES_MAGIC: &[u8; 8] = b"TLTIM001";
+
+const HEADER_LEN: usize = 12;
+const ELII_ENTRY_LEN: usize = 12;
+const TELII_ENTRY_LEN: usize = 16;
+const TIMELINE_ENTRY_LEN: usize = 8;
+const RECORD_LEN: usize = 20;
+
+// contents of an index directory
+#[derive(Debug, Default)]
+pub struct IndexData {
+    // event -> subjects with the event
+    pub elii: BTreeMap<i32, BTreeSet<String>>,
+    // (before, after) -> subjects with an occurrence of before starting before one of after
+    pub telii: BTreeMap<(i32, i32), BTreeSet<String>>,
+    pub timelines: Timelines,
+    pub events: Vec<Event>,
+    pub corpus: Vec<Document>,
+}
+
+impl IndexData {
+    // ELII and TELII postings derived from the timelines
+    pub fn from_timelines(timelines: Timelines) -> Self {
+        let mut data = IndexData::default();
+        for (subjectid, timeline) in &timelines {
+            for event in timeline.keys() {
+                data.elii.entry(*event).or_default().insert(subjectid.clone());
+            }
+            for pair in before_pairs(timeline) {
+                data.telii.entry(pair).or_default().insert(subjectid.clone());
+            }
+        }
+        data.timelines = timelines;
+        data
+    }
+
+    // the subjects in sorted order, the dictionary of an index built from the data
+    pub fn dictionary(&self) -> SubjectDictionary {
+        let subjects: BTreeSet<&String> = self.elii.values().flatten()
+            .chain(self.telii.values().flatten())
+            .chain(self.timelines.keys())
+            .collect();
+        SubjectDictionary::from_names(subjects.into_iter().cloned())
+    }
+}
+
+// the TELII pairs of a timeline: (before, after) if an occurrence of before starts before one of after
+pub fn before_pairs(timeline: &Timeline) -> BTreeSet<(i32, i32)> {
+    let mut pairs = BTreeSet::new();
+    for (before, intervals1) in timeline {
+        let first = intervals1.iter().map(|(start, _)| *start).min();
+        for (after, intervals2) in timeline {
+            let last = intervals2.iter().map(|(start, _)| *start).max();
+            if before != after && matches!((first, last), (Some(first), Some(last)) if first < last) {
+                pairs.insert((*before, *after));
+            }
+        }
+    }
+    pairs
+}
+
+fn put_u32(out: &mut impl Write, value: usize) -> io::Result<()> {
+    let value = u32::try_from(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "index too large"))?;
+    out.write_all(&value.to_le_bytes())
+}
+
+// write the index files of data into dir, replacing existing ones
+pub fn write_index(dir: &Path, data: &IndexData) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let dictionary = data.dictionary();
+    let subjects: Vec<&str> = (0..dictionary.len() as u32).filter_map(|id| dictionary.name(id)).collect();
+
+    let mut out = BufWriter::new(File::create(dir.join("subjects.bin"))?);
+    out.write_all(SUBJECTS_MAGIC)?;
+    put_u32(&mut out, subjects.len())?;
+    let mut offset = 0;
+    put_u32(&mut out, offset)?;
+    for subject in &subjects {
+        offset += subject.len();
+        put_u32(&mut out, offset)?;
+    }
+    for subject in &subjects {
+        out.write_all(subject.as_bytes())?;
+    }
+    out.flush()?;
+
+    let bitmaps: Vec<Vec<u8>> = data.elii.values().map(|ptids| bitmap_bytes(&dictionary.encode(ptids))).collect();
+    let mut out = BufWriter::new(File::create(dir.join("elii.bin"))?);
+    out.write_all(ELII_MAGIC)?;
+    put_u32(&mut out, data.elii.len())?;
+    let mut start = 0;
+    for (event, bitmap) in data.elii.keys().zip(&bitmaps) {
+        out.write_all(&event.to_le_bytes())?;
+        put_u32(&mut out, start)?;
+        put_u32(&mut out, bitmap.len())?;
+        start += bitmap.len();
+    }
+    for bitmap in &bitmaps {
+        out.write_all(bitmap)?;
+    }
+    out.flush()?;
+
+    let mut out = BufWriter::new(File::create(dir.join("telii.bin"))?);
+    out.write_all(TELII_MAGIC)?;
+    put_u32(&mut out, data.telii.len())?;
+    let mut start = 0;
+    for ((before, after), ptids) in &data.telii {
+        out.write_all(&before.to_le_bytes())?;
+        out.write_all(&after.to_le_bytes())?;
+        put_u32(&mut out, start)?;
+        put_u32(&mut out, ptids.len())?;
+        start += ptids.len();
+    }
+    for ptids in data.telii.values() {
+        for id in dictionary.encode(ptids) {
+            put_u32(&mut out, id as usize)?;
+        }
+    }
+    out.flush()?;
+
+    let records: Vec<Vec<(i32, i64, i64)>> = subjects.iter().map(|subject| {
+        let mut records: Vec<(i32, i64, i64)> = data.timelines.get(*subject).into_iter()
+            .flatten()
+            .flat_map(|(event, intervals)| intervals.iter().map(move |(start, end)| (*event, *start, *end)))
+            .collect();
+        records.sort();
+        records
+    }).collect();
+    let mut out = BufWriter::new(File::create(dir.join("timelines.bin"))?);
+    out.write_all(TIMELINES_MAGIC)?;
+    put_u32(&mut out, subjects.len())?;
+    let mut start = 0;
+    for subject_records in &records {
+        put_u32(&mut out, start)?;
+        put_u32(&mut out, subject_records.len())?;
+        start += subject_records.len();
+    }
+    for (event, start, end) in records.iter().flatten() {
+        out.write_all(&event.to_le_bytes())?;
+        out.write_all(&start.to_le_bytes())?;
+        out.write_all(&end.to_le_bytes())?;
+    }
+    out.flush()?;
+
+    let to_io = |e: bson::ser::Error| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let mut out = BufWriter::new(File::create(dir.join("events.bson"))?);
+    for event in &data.events {
+        bson::to_document(event).map_err(to_io)?.to_writer(&mut out).map_err(to_io)?;
+    }
+    out.flush()?;
+    let mut out = BufWriter::new(File::create(dir.join("corpus.bson"))?);
+    for document in &data.corpus {
+        document.to_writer(&mut out).map_err(to_io)?;
+    }
+    out.flush()
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> usize {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize
+}
+
+fn read_i32(bytes: &[u8], at: usize) -> i32 {
+    i32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_i64(bytes: &[u8], at: usize) -> i64 {
+    i64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+// a memory mapped index file with its magic checked and its entry count read
+struct IndexFile {
+    map: Mmap,
+    len: usize,
+}
+
+impl IndexFile {
+    fn open(path: &Path, magic: &[u8; 8], entry_len: usize, item_len: usize) -> Result<Self, BackendError> {
+        let file = File::open(path)?;
+        // safety: index files are written once by write_index and not modified while served
+        let map = unsafe { Mmap::map(&file)? };
+        let corrupt = || BackendError::Database(format!("corrupt index file {}", path.display()));
+        if map.len() < HEADER_LEN || &map[..8] != magic {
+            return Err(corrupt());
+        }
+        let len = read_u32(&map, 8);
+        let items = map.len().checked_sub(HEADER_LEN + len * entry_len).ok_or_else(corrupt)?;
+        if item_len > 0 && items % item_len != 0 {
+            return Err(corrupt());
+        }
+        Ok(IndexFile { map, len })
+    }
+
+    fn entry(&self, i: usize, entry_len: usize) -> &[u8] {
+        let at = HEADER_LEN + i * entry_len;
+        &self.map[at..at + entry_len]
+    }
+
+    fn items(&self, entry_len: usize) -> &[u8] {
+        &self.map[HEADER_LEN + self.len * entry_len..]
+    }
+}
+
+pub struct EmbeddedBackend {
+    // the subject table, the ids of the subjects being their positions
+    dictionary: Arc<SubjectDictionary>,
+    elii: IndexFile,
+    telii: IndexFile,
+    timelines: IndexFile,
+    events: Option<HashMap<i32, Event>>,
+    corpus: Option<Vec<Document>>,
+    dir: PathBuf,
+    // by dataset and name
+    cohorts: Mutex<BTreeMap<(String, String), Cohort>>,
+}
+
+impl EmbeddedBackend {
+    pub fn open(dir: &Path) -> Result<Self, BackendError> {
+        let subjects = IndexFile::open(&dir.join("subjects.bin"), SUBJECTS_MAGIC, 4, 0)?;
+        let elii = IndexFile::open(&dir.join("elii.bin"), ELII_MAGIC, ELII_ENTRY_LEN, 0)?;
+        let telii = IndexFile::open(&dir.join("telii.bin"), TELII_MAGIC, TELII_ENTRY_LEN, 4)?;
+        let timelines = IndexFile::open(&dir.join("timelines.bin"), TIMELINES_MAGIC, TIMELINE_ENTRY_LEN, RECORD_LEN)?;
+        let corrupt = || BackendError::Database(format!("corrupt index directory {}", dir.display()));
+        // the offsets table has one more entry than there are subjects
+        if subjects.map.len() < HEADER_LEN + (subjects.len + 1) * 4 || timelines.len != subjects.len {
+            return Err(corrupt());
+        }
+        let names = &subjects.map[HEADER_LEN + (subjects.len + 1) * 4..];
+        let mut dictionary = SubjectDictionary::new();
+        for i in 0..subjects.len {
+            let (start, end) = (read_u32(&subjects.map, HEADER_LEN + i * 4), read_u32(&subjects.map, HEADER_LEN + (i + 1) * 4));
+            let name = names.get(start..end).and_then(|name| std::str::from_utf8(name).ok()).ok_or_else(corrupt)?;
+            dictionary.insert(name);
+        }
+        if dictionary.len() != subjects.len {
+            return Err(corrupt());
+        }
+        let events = read_documents(&dir.join("events.bson"))?
+            .map(|documents| documents.into_iter()
+                .filter_map(|document| bson::from_document::<Event>(document).ok())
+                .map(|event| (event.id, event))
+                .collect());
+        let corpus = read_documents(&dir.join("corpus.bson"))?;
+        let mut cohorts = BTreeMap::new();
+        for document in read_documents(&dir.join("cohorts.bson"))?.unwrap_or_default() {
+            let cohort: Cohort = bson::from_document(document)
+                .map_err(|e| BackendError::Database(format!("invalid cohort in {}: {}", dir.display(), e)))?;
+            cohorts.insert(cohort_key(&cohort.dataset, &cohort.name), cohort);
+        }
+        Ok(EmbeddedBackend {
+            dictionary: Arc::new(dictionary), elii, telii, timelines, events, corpus,
+            dir: dir.to_path_buf(),
+            cohorts: Mutex::new(cohorts),
+        })
+    }
+
+    // rewrite cohorts.bson, replacing it only once the new one is written
+    fn write_cohorts(&self, cohorts: &BTreeMap<(String, String), Cohort>) -> Result<(), BackendError> {
+        let path = self.dir.join("cohorts.bson");
+        let tmp = self.dir.join("cohorts.bson.tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        for cohort in cohorts.values() {
+            cohort.to_document().to_writer(&mut out).map_err(|e| BackendError::Database(e.to_string()))?;
+        }
+        out.flush()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    // start and len of the entry whose key, its first key_len bytes, compares equal
+    fn range(file: &IndexFile, entry_len: usize, key_len: usize, cmp: impl Fn(&[u8]) -> std::cmp::Ordering) -> (usize, usize) {
+        let (mut lo, mut hi) = (0, file.len);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match cmp(file.entry(mid, entry_len)) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => {
+                    let entry = file.entry(mid, entry_len);
+                    return (read_u32(entry, key_len), read_u32(entry, key_len + 4));
+                }
+            }
+        }
+        (0, 0)
+    }
+
+    fn subjects(&self, bitmap: &RoaringBitmap) -> HashSet<String> {
+        self.dictionary.decode(bitmap).into_iter().collect()
+    }
+}
+
+// concatenated bson documents, None if the file does not exist
+fn read_documents(path: &Path) -> Result<Option<Vec<Document>>, BackendError> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = bytes.as_slice();
+    let mut documents = Vec::new();
+    while !reader.is_empty() {
+        let document = Document::from_reader(&mut reader)
+            .map_err(|e| BackendError::Database(format!("corrupt documents in {}: {}", path.display(), e)))?;
+        documents.push(document);
+    }
+    Ok(Some(documents))
+}
+
+impl Backend for EmbeddedBackend {
+    // the index files are validated when opened, only the optional ones can be missing
+    fn check(&self, capabilities: &BTreeSet<Capability>) -> Check {
+        let mut check = Check::default();
+        if capabilities.contains(&Capability::Events) && self.events.is_none() {
+            check.errors.push("events.bson does not exist".to_string());
+        }
+        if capabilities.contains(&Capability::Corpus) && self.corpus.is_none() {
+            check.errors.push("corpus.bson does not exist".to_string());
+        }
+        check
+    }
+
+    fn cohort(&self, dataset: &str, name: &str) -> Result<Option<Cohort>, BackendError> {
+        Ok(self.cohorts.lock().unwrap().get(&cohort_key(dataset, name)).cloned())
+    }
+
+    fn cohorts(&self, dataset: &str) -> Result<Vec<Cohort>, BackendError> {
+        Ok(self.cohorts.lock().unwrap().values().filter(|cohort| cohort.dataset == dataset).cloned().collect())
+    }
+
+    fn save_cohort(&self, cohort: &Cohort) -> Result<(), BackendError> {
+        let mut cohorts = self.cohorts.lock().unwrap();
+        let key = cohort_key(&cohort.dataset, &cohort.name);
+        if cohorts.contains_key(&key) {
+            return Err(BackendError::Exists(cohort.name.clone()));
+        }
+        let mut saved = cohorts.clone();
+        saved.insert(key, cohort.clone());
+        self.write_cohorts(&saved)?;
+        *cohorts = saved;
+        Ok(())
+    }
+
+    fn delete_cohort(&self, dataset: &str, name: &str) -> Result<bool, BackendError> {
+        let mut cohorts = self.cohorts.lock().unwrap();
+        let key = cohort_key(dataset, name);
+        if !cohorts.contains_key(&key) {
+            return Ok(false);
+        }
+        let mut saved = cohorts.clone();
+        saved.remove(&key);
+        self.write_cohorts(&saved)?;
+        *cohorts = saved;
+        Ok(true)
+    }
+
+    fn get_event(&self, id: i32) -> Result<Option<Event>, BackendError> {
+        match &self.events {
+            Some(events) => Ok(events.get(&id).cloned()),
+            None => Err(BackendError::Unsupported("event catalog")),
+        }
+    }
+
+    // case insensitive substring match on the value of the terms, where mongodb takes a regex
+    fn search_corpus(&self, term: &str) -> Result<Vec<String>, BackendError> {
+        let corpus = self.corpus.as_ref().ok_or(BackendError::Unsupported("term corpus"))?;
+        let term = term.to_lowercase();
+        Ok(corpus.iter()
+            .filter(|document| document.get_str("value").is_ok_and(|value| value.to_lowercase().contains(&term)))
+            .map(|document| document.to_string())
+            .collect())
+    }
+
+    fn subject_dictionary(&self) -> Result<Arc<SubjectDictionary>, BackendError> {
+        Ok(self.dictionary.clone())
+    }
+
+    fn elii_bitmap(&self, event_ids: &[i32]) -> Result<RoaringBitmap, BackendError> {
+        let bitmaps = self.elii.items(ELII_ENTRY_LEN);
+        let mut subjects = RoaringBitmap::new();
+        for event in event_ids {
+            let (start, len) = Self::range(&self.elii, ELII_ENTRY_LEN, 4, |entry| read_i32(entry, 0).cmp(event));
+            if len > 0 {
+                let bytes = bitmaps.get(start..start + len).ok_or_else(|| BackendError::Database("corrupt index file elii.bin".to_string()))?;
+                subjects |= bitmap_from_bytes(bytes)?;
+            }
+        }
+        Ok(subjects)
+    }
+
+    fn telii_bitmap(&self, event_ids1: &[i32], event_ids2: &[i32]) -> Result<RoaringBitmap, BackendError> {
+        let postings = self.telii.items(TELII_ENTRY_LEN);
+        let mut subjects = RoaringBitmap::new();
+        for before in event_ids1 {
+            for after in event_ids2.iter().filter(|after| *after != before) {
+                let pair = (*before, *after);
+                let (start, len) = Self::range(&self.telii, TELII_ENTRY_LEN, 8, |entry| (read_i32(entry, 0), read_i32(entry, 4)).cmp(&pair));
+                let bytes = postings.get(start * 4..(start + len) * 4).ok_or_else(|| BackendError::Database("corrupt index file telii.bin".to_string()))?;
+                subjects.extend(bytes.chunks_exact(4).map(|posting| read_u32(posting, 0) as u32));
+            }
+        }
+        Ok(subjects)
+    }
+
+    fn telii_count(&self, event_ids1: &[i32], event_ids2: &[i32]) -> Result<u64, BackendError> {
+        Ok(self.telii_bitmap(event_ids1, event_ids2)?.len())
+    }
+
+    // a scan of the pair keys, each pair counted by the length of its postings
+    fn telii_neighbors(&self, event_id: i32, neighbors: Neighbors, k: usize, min_support: u64) -> Result<Vec<(i32, u64)>, BackendError> {
+        let counts = (0..self.telii.len).filter_map(|i| {
+            let entry = self.telii.entry(i, TELII_ENTRY_LEN);
+            let (before, after, len) = (read_i32(entry, 0), read_i32(entry, 4), read_u32(entry, 12) as u64);
+            match neighbors {
+                Neighbors::Predecessors if after == event_id => Some((before, len)),
+                Neighbors::Successors if before == event_id => Some((after, len)),
+                _ => None,
+            }
+        });
+        Ok(top_neighbors(counts, k, min_support))
+    }
+
+    fn elii_subjects(&self, event_ids: &[i32]) -> Result<HashSet<String>, BackendError> {
+        Ok(self.subjects(&self.elii_bitmap(event_ids)?))
+    }
+
+    fn telii_before(&self, event_ids1: &[i32], event_ids2: &[i32]) -> Result<HashSet<String>, BackendError> {
+        Ok(self.subjects(&self.telii_bitmap(event_ids1, event_ids2)?))
+    }
+
+    // without subjects only those with any of the events are read, found through the elii postings
+    fn timelines(&self, subjects: Option<&[String]>, event_ids: &[i32]) -> Result<Timelines, BackendError> {
+        let ids = match subjects {
+            Some(subjects) => self.dictionary.encode(subjects),
+            None => self.elii_bitmap(event_ids)?,
+        };
+        let records = self.timelines.items(TIMELINE_ENTRY_LEN);
+        let mut timelines = Timelines::new();
+        for id in ids.iter().filter(|id| (*id as usize) < self.timelines.len) {
+            let entry = self.timelines.entry(id as usize, TIMELINE_ENTRY_LEN);
+            let (start, len) = (read_u32(entry, 0), read_u32(entry, 4));
+            let subject_records = records.get(start * RECORD_LEN..(start + len) * RECORD_LEN)
+                .ok_or_else(|| BackendError::Database("corrupt index file timelines.bin".to_string()))?;
+            let event_at = |j: usize| read_i32(subject_records, j * RECORD_LEN);
+            for event in event_ids {
+                // records are sorted by event, find the run of this event
+                let first = partition_point(len, |j| event_at(j) < *event);
+                let last = partition_point(len, |j| event_at(j) <= *event);
+                if first == last {
+                    continue;
+                }
+                let occurrences = timelines.entry(self.dictionary.name(id).unwrap_or_default().to_string()).or_default().entry(*event).or_default();
+                for j in first..last {
+                    let at = j * RECORD_LEN;
+                    occurrences.push((read_i64(subject_records, at + 4), read_i64(subject_records, at + 12)));
+                }
+            }
+        }
+        Ok(timelines)
+    }
+}
+
+// first index in 0..len for which pred is false, pred being true on a prefix
+fn partition_point(len: usize, pred: impl Fn(usize) -> bool) -> usize {
+    let (mut lo, mut hi) = (0, len);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if pred(mid) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::backend::MemoryBackend;
+    use maplit::hashmap;
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let timelines: Timelines = hashmap!{
+            "p1".to_string() => hashmap!{ 53 => vec![(0, 10), (40, 50)], 941 => vec![(20, 30)] },
+            "p2".to_string() => hashmap!{ 941 => vec![(0, 10)], 53 => vec![(5, 5)] },
+            "p3".to_string() => hashmap!{ 79 => vec![(0, 0)] },
+        };
+        let dir = std::env::temp_dir().join(format!("telii-embedded-{}", std::process::id()));
+        write_index(&dir, &IndexData::from_timelines(timelines.clone())).unwrap();
+        let embedded = EmbeddedBackend::open(&dir).unwrap();
+        let memory = MemoryBackend::new(timelines);
+
+        for ids in [vec![53], vec![941, 79], vec![1]] {
+            assert_eq!(embedded.elii_subjects(&ids).unwrap(), memory.elii_subjects(&ids).unwrap());
+            assert_eq!(embedded.timelines(None, &ids).unwrap(), memory.timelines(None, &ids).unwrap()
+                .into_iter().filter(|(_, timeline)| !timeline.is_empty()).collect());
+        }
+        assert_eq!(embedded.telii_before(&[53], &[941]).unwrap(), memory.telii_before(&[53], &[941]).unwrap());
+        assert_eq!(embedded.telii_before(&[941], &[53]).unwrap(), memory.telii_before(&[941], &[53]).unwrap());
+        for neighbors in [Neighbors::Predecessors, Neighbors::Successors] {
+            assert_eq!(embedded.telii_neighbors(53, neighbors, 10, 1).unwrap(), memory.telii_neighbors(53, neighbors, 10, 1).unwrap());
+        }
+        let subjects = ["p1".to_string(), "p9".to_string()];
+        assert_eq!(embedded.timelines(Some(&subjects), &[53]).unwrap()["p1"][&53], vec![(0, 10), (40, 50)]);
+        assert!(matches!(embedded.get_event(53), Ok(None)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cohorts_are_per_dataset() {
+        let dir = std::env::temp_dir().join(format!("telii-embedded-cohorts-{}", std::process::id()));
+        write_index(&dir, &IndexData::default()).unwrap();
+        let embedded = EmbeddedBackend::open(&dir).unwrap();
+        let cohort = |dataset: &str, subjects: &[&str]| Cohort::new("a", dataset, "/elii", subjects.iter().map(|s| s.to_string()).collect());
+        embedded.save_cohort(&cohort("telii", &["p1"])).unwrap();
+        embedded.save_cohort(&cohort("eeg", &["p2", "p3"])).unwrap();
+        assert!(matches!(embedded.save_cohort(&cohort("eeg", &["p4"])), Err(BackendError::Exists(_))));
+
+        // two datasets on one directory keep their cohorts apart, also once reopened
+        let reopened = EmbeddedBackend::open(&dir).unwrap();
+        assert_eq!(reopened.cohort("telii", "a").unwrap().unwrap().subjects, vec!["p1"]);
+        assert_eq!(reopened.cohorts("eeg").unwrap().iter().map(|c| c.subjects.len()).collect::<Vec<_>>(), vec![2]);
+        assert!(reopened.delete_cohort("eeg", "a").unwrap());
+        assert!(reopened.cohort("eeg", "a").unwrap().is_none());
+        assert!(reopened.cohort("telii", "a").unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_corrupt_files() {
+        let dir = std::env::temp_dir().join(format!("telii-embedded-corrupt-{}", std::process::id()));
+        write_index(&dir, &IndexData::default()).unwrap();
+        fs::write(dir.join("elii.bin"), b"TLELI002\x05\x00\x00\x00").unwrap();
+        assert!(EmbeddedBackend::open(&dir).is_err());
+
+        // entries pointing past the records or postings open, and fail the queries reading them
+        let timelines: Timelines = hashmap!{ "p1".to_string() => hashmap!{ 53 => vec![(0, 0)], 941 => vec![(10, 10)] } };
+        write_index(&dir, &IndexData::from_timelines(timelines)).unwrap();
+        let patch = |file: &str, at: usize, value: u32| {
+            let mut bytes = fs::read(dir.join(file)).unwrap();
+            bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            fs::write(dir.join(file), bytes).unwrap();
+        };
+        // the len of the first timeline entry, and of the first telii entry
+        patch("timelines.bin", HEADER_LEN + 4, 1000);
+        patch("telii.bin", HEADER_LEN + 12, 1000);
+        let embedded = EmbeddedBackend::open(&dir).unwrap();
+        assert!(matches!(embedded.timelines(None, &[53]), Err(BackendError::Database(_))));
+        assert!(matches!(embedded.telii_bitmap(&[53], &[941]), Err(BackendError::Database(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1 +1,7 @@
-pub mod mongodb;
\ No newline at end of file
+pub mod backend;
+pub mod builder;
+pub mod embedded;
+pub mod mongodb;
+pub mod pipeline;
+pub mod registry;
+pub mod subjects;
@@ -3,13 +3,30 @@ extern crate dotenv;
 
 use dotenv::dotenv;
 
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
 use mongodb::{
-    bson::{doc, extjson::de::Error, oid::ObjectId, Document},
-    results::{InsertOneResult, UpdateResult, DeleteResult},
+    bson::{self, doc, Bson, DateTime, Document, Regex},
+    error::{ErrorKind, WriteFailure},
+    options::{AggregateOptions, FindOptions, ReplaceOptions, UpdateOptions},
+    IndexModel,
     sync::{Client, Collection, Database},
 };
+use roaring::RoaringBitmap;
+use crate::database::backend::{encode_all, Backend, BackendError, Check, Neighbors, Subjects};
+use crate::database::builder::{bitmap_binary, elii_documents, event_documents, subject_documents, subject_telii_documents, telii_documents, timeline_documents, times, updated_timeline, CollectionNames, UpdateReport};
+use crate::database::embedded::IndexData;
+use crate::database::pipeline::{construct_bool_query, construct_query, match_subjects};
+use crate::database::registry::{Capability, DatasetConfig};
+use crate::database::subjects::{bitmap_from_bytes, SubjectDictionary};
+use crate::models::cohort::Cohort;
 use crate::models::event::Event;
+use crate::tel::eval::{TelMatch, Timelines};
+use crate::tel::exp::TelExp;
+use crate::tel::query::TelQuery;
 
+#[allow(dead_code)]
 pub struct MongoRepo {
     db: Database,
     event_col: Collection<Event>,
@@ -18,87 +35,857 @@ pub struct MongoRepo {
     pub telii_col: Collection<Document>,
     pub telii_common_col: Collection<Document>,
     pub timeline_col: Collection<Document>,
+    pub cohort_col: Collection<Document>,
+    // {id, subjectid} of the subject dictionary, when the dataset has one
+    pub subject_col: Option<Collection<Document>>,
+    // loaded on first use, again once the elii bitmaps have subjects it does not know
+    dictionary: Mutex<Option<Arc<SubjectDictionary>>>,
 }
 
+#[allow(dead_code)]
 pub struct EegMongoRepo {
     db: Database,
     event_col: Collection<Event>,
     pub timeline_col: Collection<Document>,
+    pub cohort_col: Collection<Document>,
 }
 
-impl MongoRepo {
-    pub fn init() -> Self {
-        dotenv().ok();
-        let uri = match env::var("MONGOURI") {
-            Ok(v) => v.to_string(),
-            Err(_) => format!("Error loading env variable"),
+// database of the mongodb server at MONGOURI; the client connects lazily, see check_database
+pub fn database(name: &str) -> Result<Database, BackendError> {
+    dotenv().ok();
+    let uri = env::var("MONGOURI").map_err(|_| BackendError::Database("MONGOURI is not set".to_string()))?;
+    let client = Client::with_uri_str(uri)?;
+    Ok(client.database(name))
+}
+
+// indexes of the collection a capability reads, as built by write_collections
+fn indexes(capability: Capability) -> Vec<Document> {
+    match capability {
+        Capability::Events | Capability::Elii => vec![doc! {"id": 1}],
+        // a and b for the events of the pairs of an event, see telii_neighbors
+        Capability::Telii => vec![doc! {"e": 1}, doc! {"b": 1}, doc! {"a": 1}],
+        Capability::Timeline => vec![doc! {"subjectid": 1, "e": 1}, doc! {"e": 1}],
+        // cohorts are found by their _id
+        Capability::Corpus | Capability::Cohorts => Vec::new(),
+    }
+}
+
+// the subject dictionary of a build, empty for builds written before it had one
+fn read_dictionary(subject_col: &Collection<Document>) -> Result<SubjectDictionary, BackendError> {
+    let find_options = FindOptions::builder().sort(doc! {"id": 1}).build();
+    let mut dictionary = SubjectDictionary::new();
+    for result in subject_col.find(None, find_options)? {
+        let document = result?;
+        let (Ok(id), Ok(subjectid)) = (document.get_i64("id"), document.get_str("subjectid")) else {
+            continue;
         };
-        let client = Client::with_uri_str(uri).unwrap();
-        let db = client.database("optum_covid19_telii_20220120");
-        let event_col: Collection<Event> = db.collection("event_v4");
-        let corpus_col: Collection<Document> = db.collection("term_corpus_v4");
-        let elii_col: Collection<Document> = db.collection("elii_v4");
-        let telii_col: Collection<Document> = db.collection("telii_v4_diag_gall_7");
-        let telii_common_col: Collection<Document> = db.collection("telii_common_v4_diag_gall_7");
-        let timeline_col: Collection<Document> = db.collection("pt_timeline_v4_diag_gall_7");
-        MongoRepo { db,event_col,corpus_col,elii_col,telii_col,telii_common_col,timeline_col }
-    }
-    pub fn get_event(&self, id: &str) -> Result<Event, Error> {
-        let id = id.parse::<i32>().unwrap();
-        print!("id: {}", id);
-        let filter = doc! {"id": id};
-        let event_detail: Option<Event> = self
-          .event_col
-          .find_one(filter, None)
-          .ok()
-          .expect("Error getting event's detail");
-        Ok(event_detail.unwrap())
+        if dictionary.insert(subjectid) as i64 != id {
+            return Err(BackendError::Database(format!("subject ids of {} are not dense", subject_col.name())));
+        }
+    }
+    Ok(dictionary)
+}
+
+// ping the server, then check that the collection of each capability exists and has the
+// indexes the queries use; an index whose keys start with the expected ones will do
+fn check_database(db: &Database, collections: &[(Capability, &str)], capabilities: &BTreeSet<Capability>) -> Check {
+    if let Err(e) = db.run_command(doc! {"ping": 1}, None) {
+        return Check::failed(format!("ping failed: {}", e));
+    }
+    let names = match db.list_collection_names(None) {
+        Ok(names) => names,
+        Err(e) => return Check::failed(format!("listing collections failed: {}", e)),
+    };
+    let mut check = Check::default();
+    for (capability, name) in collections.iter().filter(|(c, _)| capabilities.contains(c)) {
+        // the first save creates the cohort collection
+        if !names.iter().any(|n| n == name) && *capability != Capability::Cohorts {
+            check.errors.push(format!("collection {} of the {} does not exist", name, capability.name()));
+            continue;
+        }
+        let existing: Vec<Vec<String>> = match db.collection::<Document>(name).list_indexes(None) {
+            Ok(cursor) => cursor.filter_map(|index| index.ok()).map(|index| index.keys.keys().cloned().collect()).collect(),
+            Err(e) => {
+                check.warnings.push(format!("listing the indexes of {} failed: {}", name, e));
+                continue;
+            }
+        };
+        for keys in indexes(*capability) {
+            let keys: Vec<String> = keys.keys().cloned().collect();
+            if !existing.iter().any(|index| index.starts_with(&keys)) {
+                check.warnings.push(format!("collection {} has no index on {}", name, keys.join(", ")));
+            }
+        }
+    }
+    check
+}
+
+// Write a build into the database with the indexes the queries use, recording its manifest in
+// index_manifest. Returns false if the same source was already built under these names; a
+// different source replaces an existing build only with replace.
+pub fn write_collections(db: &Database, names: &CollectionNames, data: &IndexData, manifest: Document, replace: bool) -> Result<bool, BackendError> {
+    let manifests: Collection<Document> = db.collection("index_manifest");
+    let build_id = names.build_id();
+    if let Some(existing) = manifests.find_one(doc! {"_id": &build_id}, None)? {
+        if existing.get_str("source_hash").ok() == manifest.get_str("source_hash").ok() {
+            return Ok(false);
+        }
+        if !replace {
+            return Err(BackendError::Database(format!("build {} exists with a different source", build_id)));
+        }
+    }
+    let dictionary = data.dictionary();
+    let collections = [
+        (&names.event, event_documents(data), indexes(Capability::Events)),
+        (&names.elii, elii_documents(data, &dictionary), indexes(Capability::Elii)),
+        (&names.subject, subject_documents(&dictionary), vec![doc! {"id": 1}, doc! {"subjectid": 1}]),
+        (&names.telii, telii_documents(data), indexes(Capability::Telii)),
+        (&names.timeline, timeline_documents(data), indexes(Capability::Timeline)),
+    ];
+    for (name, documents, indexes) in collections {
+        let col: Collection<Document> = db.collection(name);
+        col.drop(None)?;
+        if !documents.is_empty() {
+            col.insert_many(documents, None)?;
+        }
+        for keys in indexes {
+            let model = IndexModel::builder().keys(keys).build();
+            col.create_index(model, None)?;
+        }
     }
+    manifests.replace_one(doc! {"_id": &build_id}, manifest, ReplaceOptions::builder().upsert(true).build())?;
+    Ok(true)
+}
+
+// Apply new or changed events of some subjects to a build: with replace the delta is the whole
+// timeline of its subjects, otherwise its occurrences are added to theirs. Each subject's
+// timeline documents, elii postings, event counts and telii documents are rewritten from its
+// updated timeline, timeline documents last, so an interrupted update is completed by running
+// it again. New subjects get the next ids of the subject dictionary, the elii bitmaps of the
+// events touched are encoded again from their ptid_list.
+pub fn update_collections(db: &Database, names: &CollectionNames, delta: &Timelines, replace: bool, source: &str) -> Result<UpdateReport, BackendError> {
+    let manifests: Collection<Document> = db.collection("index_manifest");
+    let build_id = names.build_id();
+    if manifests.find_one(doc! {"_id": &build_id}, None)?.is_none() {
+        return Err(BackendError::Database(format!("build {} does not exist", build_id)));
+    }
+    let event_col: Collection<Document> = db.collection(&names.event);
+    let elii_col: Collection<Document> = db.collection(&names.elii);
+    let telii_col: Collection<Document> = db.collection(&names.telii);
+    let timeline_col: Collection<Document> = db.collection(&names.timeline);
+    let subject_col: Collection<Document> = db.collection(&names.subject);
+    let upsert = || UpdateOptions::builder().upsert(true).build();
+    // builds without a dictionary keep only the ptid_list
+    let mut dictionary = read_dictionary(&subject_col)?;
+    let encode = !dictionary.is_empty();
+
+    let mut report = UpdateReport::default();
+    let subjects: BTreeMap<&String, _> = delta.iter().collect();
+    for (subjectid, changes) in subjects {
+        let old = read_timelines(&timeline_col, doc! {"subjectid": subjectid})?.remove(subjectid).unwrap_or_default();
+        let new = updated_timeline(&old, changes, replace);
+        let mut subject = UpdateReport::default();
+        subject.add(subjectid, &old, &new);
+        if subject.subjects.is_empty() {
+            continue;
+        }
+        if encode && dictionary.id(subjectid).is_none() {
+            let id = dictionary.insert(subjectid);
+            subject_col.insert_one(doc! {"id": id as i64, "subjectid": subjectid}, None)?;
+        }
+        for (_, e) in &subject.events_added {
+            elii_col.update_one(doc! {"id": e}, doc! {"$addToSet": {"ptid_list": subjectid}}, upsert())?;
+        }
+        for (_, e) in &subject.events_removed {
+            elii_col.update_one(doc! {"id": e}, doc! {"$pull": {"ptid_list": subjectid}}, None)?;
+        }
+        for (_, e) in subject.events_added.iter().chain(&subject.events_removed) {
+            let ptids: Vec<String> = elii_col.find_one(doc! {"id": e}, None)?
+                .and_then(|document| document.get_array("ptid_list").ok().map(|ptids| {
+                    ptids.iter().filter_map(|ptid| ptid.as_str().map(str::to_string)).collect()
+                }))
+                .unwrap_or_default();
+            event_col.update_one(doc! {"id": e}, doc! {"$set": {"num_of_patients": ptids.len() as i32}}, upsert())?;
+            if encode {
+                elii_col.update_one(doc! {"id": e}, doc! {"$set": {"ptid_bitmap": bitmap_binary(&dictionary.encode(&ptids))}}, None)?;
+            }
+        }
+        if !subject.pairs_added.is_empty() || !subject.pairs_removed.is_empty() {
+            telii_col.delete_many(doc! {"PTID": subjectid}, None)?;
+            let documents = subject_telii_documents(subjectid, &new);
+            if !documents.is_empty() {
+                telii_col.insert_many(documents, None)?;
+            }
+        }
+        for (_, e) in &subject.timelines {
+            let filter = doc! {"subjectid": subjectid, "e": e};
+            match new.get(e) {
+                Some(intervals) => {
+                    let document = doc! {"subjectid": subjectid, "e": e, "times": times(intervals)};
+                    timeline_col.replace_one(filter, document, ReplaceOptions::builder().upsert(true).build())?;
+                }
+                None => {
+                    timeline_col.delete_one(filter, None)?;
+                }
+            }
+        }
+        report.subjects.extend(subject.subjects);
+        report.timelines.extend(subject.timelines);
+        report.events_added.extend(subject.events_added);
+        report.events_removed.extend(subject.events_removed);
+        report.pairs_added.extend(subject.pairs_added);
+        report.pairs_removed.extend(subject.pairs_removed);
+    }
+    let update = doc! {
+        "source": source,
+        "replace": replace,
+        "subjects": report.subjects.len() as i64,
+        "updated_at": DateTime::now(),
+    };
+    manifests.update_one(doc! {"_id": &build_id}, doc! {"$push": {"updates": update}}, None)?;
+    Ok(report)
+}
 
-    pub fn search_icd10_diag_of_event_ids(&self, codes: &Vec<String>) -> Result<Vec<i32>, mongodb::error::Error> {
+impl MongoRepo {
+    pub fn init() -> Result<Self, BackendError> {
+        let names = DatasetConfig {
+            event: Some("event_v4".to_string()),
+            corpus: Some("term_corpus_v4".to_string()),
+            elii: Some("elii_v4".to_string()),
+            telii: Some("telii_v4_diag_gall_7".to_string()),
+            timeline: Some("pt_timeline_v4_diag_gall_7".to_string()),
+            cohort: Some("cohort".to_string()),
+            ..DatasetConfig::default()
+        };
+        Ok(MongoRepo::open(database("optum_covid19_telii_20220120")?, &names))
+    }
+    // the collections of a configured dataset; those its capabilities do not use may be unnamed,
+    // as may the subject dictionary
+    pub fn open(db: Database, names: &DatasetConfig) -> Self {
+        let name = |name: &Option<String>| name.clone().unwrap_or_default();
+        let event_col: Collection<Event> = db.collection(&name(&names.event));
+        let corpus_col: Collection<Document> = db.collection(&name(&names.corpus));
+        let elii_col: Collection<Document> = db.collection(&name(&names.elii));
+        let telii_col: Collection<Document> = db.collection(&name(&names.telii));
+        let telii_common_col: Collection<Document> = db.collection(&name(&names.telii).replacen("telii", "telii_common", 1));
+        let timeline_col: Collection<Document> = db.collection(&name(&names.timeline));
+        let cohort_col: Collection<Document> = db.collection(&name(&names.cohort));
+        let subject_col = names.subject.as_ref().map(|subject| db.collection(subject));
+        MongoRepo { db,event_col,corpus_col,elii_col,telii_col,telii_common_col,timeline_col,cohort_col,subject_col,dictionary: Mutex::new(None) }
+    }
+    #[allow(dead_code)]
+    pub fn search_icd10_diag_of_event_ids(&self, codes: &[String]) -> Result<Vec<i32>, mongodb::error::Error> {
         let filter = doc! {"cov_diag.DIAGNOSIS_CD": {"$in": codes}, "cov_diag.DIAGNOSIS_STATUS": "Diagnosis of", "cov_diag.DIAGNOSIS_CD_TYPE": "ICD10"};
-        let mut cursor = self
+        let cursor = self
           .event_col
           .find(filter, None)
-          .ok()
           .expect("Error getting event's detail");
         // get id list
         let mut results: Vec<i32> = Vec::new();
-        while let Some(result) = cursor.next() {
+        for result in cursor {
             match result {
                 Ok(document) => {
                     results.push(document.id);
                 }
                 Err(e) => {
                     println!("Error getting event's detail");
-                    return Err(e.into());
+                    return Err(e);
                 }
             }
         }
 
         Ok(results)
     }
-    pub fn relative_temporal_query_telii<T>(&self, event_id_list1: &Vec<i32>, event_id_list2: &Vec<i32>) -> Result<Vec<String>, mongodb::error::Error> {
-        let temporal_relation_col: Collection<T> = self.db.collection("tree_v3_g89__1");
+    #[allow(dead_code)]
+    pub fn relative_temporal_query_telii<T>(&self, _event_id_list1: &[i32], _event_id_list2: &[i32]) -> Result<Vec<String>, mongodb::error::Error> {
+        let _temporal_relation_col: Collection<T> = self.db.collection("tree_v3_g89__1");
 
-        let mut results: Vec<String> = Vec::new();
+        let results: Vec<String> = Vec::new();
         Ok(results)
     }
 
 }
 
 impl EegMongoRepo {
-    pub fn init() -> Self {
-        dotenv().ok();
-        let uri = match env::var("MONGOURI") {
-            Ok(v) => v.to_string(),
-            Err(_) => format!("Error loading env variable"),
-        };
-        let client = Client::with_uri_str(uri).unwrap();
-        let db = client.database("eegdb_telii_amia2024");
+    pub fn init() -> Result<Self, BackendError> {
+        let db = database("eegdb_telii_amia2024")?;
         let event_col: Collection<Event> = db.collection("event_v4");
         let timeline_col: Collection<Document> = db.collection("pt_timeline_eeg_v4_7");
-        EegMongoRepo { db,event_col,timeline_col }
+        let cohort_col: Collection<Document> = db.collection("cohort");
+        Ok(EegMongoRepo { db,event_col,timeline_col,cohort_col })
+    }
+}
+
+impl MongoRepo {
+    // the whole dataset in the form of an index directory, see database::embedded
+    pub fn export_index(&self) -> Result<IndexData, BackendError> {
+        let mut data = IndexData {
+            timelines: read_timelines(&self.timeline_col, doc! {})?,
+            events: read_events(&self.event_col)?,
+            ..IndexData::default()
+        };
+        for result in self.elii_col.find(None, None)? {
+            let document = result?;
+            let ptids = data.elii.entry(document.get_i32("id").unwrap_or_default()).or_default();
+            for ptid in document.get_array("ptid_list").map(|l| l.to_vec()).unwrap_or_default() {
+                if let Bson::String(ptid) = ptid {
+                    ptids.insert(ptid);
+                }
+            }
+        }
+        // the events of b come before e, those of a after it
+        for result in self.telii_col.find(None, None)? {
+            let document = result?;
+            let (Ok(ptid), Ok(e)) = (document.get_str("PTID"), document.get_i32("e")) else {
+                continue;
+            };
+            let events = |key: &str| -> Vec<i32> {
+                document.get_array(key).map(|l| l.iter().filter_map(|x| x.as_i32()).collect()).unwrap_or_default()
+            };
+            for before in events("b") {
+                data.telii.entry((before, e)).or_default().insert(ptid.to_string());
+            }
+            for after in events("a") {
+                data.telii.entry((e, after)).or_default().insert(ptid.to_string());
+            }
+        }
+        let find_options = FindOptions::builder().projection(doc! {"_id": 0}).build();
+        for result in self.corpus_col.find(None, find_options)? {
+            data.corpus.push(result?);
+        }
+        Ok(data)
+    }
+}
+
+impl EegMongoRepo {
+    // the timelines and event catalog as an index directory, ELII and TELII derived from the timelines
+    pub fn export_index(&self) -> Result<IndexData, BackendError> {
+        let mut data = IndexData::from_timelines(read_timelines(&self.timeline_col, doc! {})?);
+        data.events = read_events(&self.event_col)?;
+        Ok(data)
+    }
+}
+
+fn read_events(event_col: &Collection<Event>) -> Result<Vec<Event>, BackendError> {
+    let mut events = Vec::new();
+    for result in event_col.find(None, None)? {
+        events.push(result?);
+    }
+    Ok(events)
+}
+
+impl Backend for MongoRepo {
+    fn check(&self, capabilities: &BTreeSet<Capability>) -> Check {
+        let collections = [
+            (Capability::Events, self.event_col.name()),
+            (Capability::Corpus, self.corpus_col.name()),
+            (Capability::Elii, self.elii_col.name()),
+            (Capability::Telii, self.telii_col.name()),
+            (Capability::Timeline, self.timeline_col.name()),
+            (Capability::Cohorts, self.cohort_col.name()),
+        ];
+        let mut check = check_database(&self.db, &collections, capabilities);
+        // without its dictionary elii queries fall back to the ptid_list
+        if let Some(subject_col) = self.subject_col.as_ref().filter(|_| check.ready()) {
+            if let Ok(names) = self.db.list_collection_names(doc! {"name": subject_col.name()}) {
+                if names.is_empty() {
+                    check.warnings.push(format!("collection {} of the subject dictionary does not exist", subject_col.name()));
+                } else if subject_col.estimated_document_count(None).is_ok_and(|count| count == 0) {
+                    check.warnings.push(format!("collection {} of the subject dictionary is empty", subject_col.name()));
+                }
+            }
+        }
+        check
+    }
+
+    fn get_event(&self, id: i32) -> Result<Option<Event>, BackendError> {
+        Ok(self.event_col.find_one(doc! {"id": id}, None)?)
+    }
+
+    fn search_corpus(&self, term: &str) -> Result<Vec<String>, BackendError> {
+        let filter = doc![
+            "value": {
+                "$regex": Regex {
+                    pattern: String::from(term),
+                    options: String::from("i"),
+                }
+            }
+        ];
+        let find_options = FindOptions::builder().projection(doc! {"_id": 0}).build();
+        let cursor = self.corpus_col.find(filter, find_options)?;
+        let mut results: Vec<String> = Vec::new();
+        for result in cursor {
+            results.push(result?.to_string());
+        }
+        Ok(results)
+    }
+
+    fn subject_dictionary(&self) -> Result<Arc<SubjectDictionary>, BackendError> {
+        let subject_col = self.subject_col.as_ref().ok_or(BackendError::Unsupported("subject dictionary"))?;
+        let mut cached = self.dictionary.lock().unwrap();
+        if let Some(dictionary) = cached.as_ref() {
+            return Ok(dictionary.clone());
+        }
+        let dictionary = Arc::new(read_dictionary(subject_col)?);
+        // a missing or empty collection is no dictionary, the bitmaps would have no subjects
+        if dictionary.is_empty() {
+            return Err(BackendError::Unsupported("subject dictionary"));
+        }
+        *cached = Some(dictionary.clone());
+        Ok(dictionary)
+    }
+
+    // the stored ptid_bitmap, or the ptid_list encoded for documents written without one, only
+    // those documents sending it; unsupported if the dictionary does not know all of its subjects
+    fn elii_bitmap(&self, event_ids: &[i32]) -> Result<RoaringBitmap, BackendError> {
+        let dictionary = self.subject_dictionary()?;
+        let mut subjects = RoaringBitmap::new();
+        let mut without_bitmap: Vec<Bson> = Vec::new();
+        let options = FindOptions::builder().projection(doc! {"ptid_bitmap": 1}).build();
+        for result in self.elii_col.find(doc! {"id": {"$in": event_ids}}, options)? {
+            let document = result?;
+            match document.get_binary_generic("ptid_bitmap") {
+                Ok(bytes) => subjects |= bitmap_from_bytes(bytes)?,
+                Err(_) => without_bitmap.extend(document.get("_id").cloned()),
+            }
+        }
+        if !without_bitmap.is_empty() {
+            let options = FindOptions::builder().projection(doc! {"ptid_list": 1}).build();
+            for result in self.elii_col.find(doc! {"_id": {"$in": without_bitmap}}, options)? {
+                let ptids: HashSet<String> = result?.get_array("ptid_list").map(|l| l.iter().filter_map(|ptid| ptid.as_str().map(str::to_string)).collect()).unwrap_or_default();
+                match encode_all(&dictionary, &ptids) {
+                    Ok(bitmap) => subjects |= bitmap,
+                    Err(e) => {
+                        *self.dictionary.lock().unwrap() = None;
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        // an update added subjects since the dictionary was loaded, the next use reloads it
+        if dictionary.is_behind(&subjects) {
+            *self.dictionary.lock().unwrap() = None;
+        }
+        Ok(subjects)
+    }
+
+    fn elii_subjects(&self, event_ids: &[i32]) -> Result<HashSet<String>, BackendError> {
+        let cursor = self.elii_col.find(doc! {"id": {"$in": event_ids}}, None)?;
+        let mut ptids: HashSet<String> = HashSet::new();
+        for result in cursor {
+            let document = result?;
+            for ptid in document.get_array("ptid_list").map(|l| l.to_vec()).unwrap_or_default() {
+                if let Bson::String(ptid) = ptid {
+                    ptids.insert(ptid);
+                }
+            }
+        }
+        Ok(ptids)
+    }
+
+    // a telii document {PTID, e, b, a} lists the events before (b) and after (a) event e of a
+    // patient, a pair is stored once under its larger event id
+    fn telii_before(&self, event_ids1: &[i32], event_ids2: &[i32]) -> Result<HashSet<String>, BackendError> {
+        let Some(filter) = telii_filter(event_ids1, event_ids2) else {
+            return Ok(HashSet::new());
+        };
+        let pipeline = vec![
+            doc! {"$match": filter},
+            doc! {"$group": {"_id": "$pg", "ptid_list": {"$addToSet": "$PTID"}}},
+        ];
+        let cursor = self.telii_col.aggregate(pipeline, None)?;
+        let mut ptids: HashSet<String> = HashSet::new();
+        for result in cursor {
+            let document = result?;
+            for ptid in document.get_array("ptid_list").map(|l| l.to_vec()).unwrap_or_default() {
+                if let Bson::String(ptid) = ptid {
+                    ptids.insert(ptid);
+                }
+            }
+        }
+        Ok(ptids)
+    }
+
+    // grouped and sorted by the server, the patients are read off the cursor as they are sent
+    fn elii_ordered(&self, event_ids1: &[i32], event_ids2: &[i32], after: Option<&str>) -> Result<Subjects<'_>, BackendError> {
+        let event_ids: Vec<i32> = event_ids1.iter().chain(event_ids2).copied().collect();
+        let mut both = doc! {"in1": true, "in2": true};
+        if let Some(after) = after {
+            both.insert("_id", doc! {"$gt": after});
+        }
+        let pipeline = vec![
+            doc! {"$match": {"id": {"$in": event_ids}}},
+            doc! {"$project": {"ptid_list": 1, "in1": {"$in": ["$id", event_ids1]}, "in2": {"$in": ["$id", event_ids2]}}},
+            doc! {"$unwind": "$ptid_list"},
+            doc! {"$group": {"_id": "$ptid_list", "in1": {"$max": "$in1"}, "in2": {"$max": "$in2"}}},
+            doc! {"$match": both},
+            doc! {"$sort": {"_id": 1}},
+        ];
+        ordered_subjects(&self.elii_col, pipeline)
+    }
+
+    fn telii_ordered(&self, event_ids1: &[i32], event_ids2: &[i32], after: Option<&str>) -> Result<Subjects<'_>, BackendError> {
+        let Some(filter) = telii_filter(event_ids1, event_ids2) else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        let mut pipeline = vec![
+            doc! {"$match": filter},
+            doc! {"$group": {"_id": "$PTID"}},
+        ];
+        if let Some(after) = after {
+            pipeline.push(doc! {"$match": {"_id": {"$gt": after}}});
+        }
+        pipeline.push(doc! {"$sort": {"_id": 1}});
+        ordered_subjects(&self.telii_col, pipeline)
+    }
+
+    // the pairs of an event are in its own document, those with a smaller event, and in those of
+    // larger events listing it; ranked by the server
+    fn telii_neighbors(&self, event_id: i32, neighbors: Neighbors, k: usize, min_support: u64) -> Result<Vec<(i32, u64)>, BackendError> {
+        let (listed_in, own) = match neighbors {
+            Neighbors::Predecessors => ("a", "$b"),
+            Neighbors::Successors => ("b", "$a"),
+        };
+        let mut larger = Document::new();
+        larger.insert(listed_in, event_id);
+        let pipeline = vec![
+            doc! {"$match": {"$or": [{"e": event_id}, larger]}},
+            doc! {"$project": {"PTID": 1, "events": {"$cond": [{"$eq": ["$e", event_id]}, own, ["$e"]]}}},
+            doc! {"$unwind": "$events"},
+            doc! {"$group": {"_id": {"event": "$events", "PTID": "$PTID"}}},
+            doc! {"$group": {"_id": "$_id.event", "count": {"$sum": 1}}},
+            doc! {"$match": {"count": {"$gte": min_support as i64}}},
+            doc! {"$sort": {"count": -1, "_id": 1}},
+            doc! {"$limit": k as i64},
+        ];
+        let options = AggregateOptions::builder().allow_disk_use(true).build();
+        let number = |value: Option<&Bson>| match value {
+            Some(Bson::Int32(value)) => Some(*value as i64),
+            Some(Bson::Int64(value)) => Some(*value),
+            Some(Bson::Double(value)) => Some(*value as i64),
+            _ => None,
+        };
+        let mut ranked = Vec::new();
+        for result in self.telii_col.aggregate(pipeline, options)? {
+            let document = result?;
+            if let (Some(event), Some(count)) = (number(document.get("_id")), number(document.get("count"))) {
+                ranked.push((event as i32, count as u64));
+            }
+        }
+        Ok(ranked)
+    }
+
+    // counted by the server, the patients are not sent
+    fn telii_count(&self, event_ids1: &[i32], event_ids2: &[i32]) -> Result<u64, BackendError> {
+        let Some(filter) = telii_filter(event_ids1, event_ids2) else {
+            return Ok(0);
+        };
+        let pipeline = vec![
+            doc! {"$match": filter},
+            doc! {"$group": {"_id": "$PTID"}},
+            doc! {"$count": "count"},
+        ];
+        let mut cursor = self.telii_col.aggregate(pipeline, None)?;
+        match cursor.next() {
+            Some(result) => match result?.get("count") {
+                Some(Bson::Int32(count)) => Ok(*count as u64),
+                Some(Bson::Int64(count)) => Ok(*count as u64),
+                _ => Ok(0),
+            },
+            None => Ok(0),
+        }
+    }
+
+    fn timelines(&self, subjects: Option<&[String]>, event_ids: &[i32]) -> Result<Timelines, BackendError> {
+        load_timelines(&self.timeline_col, subjects, event_ids)
+    }
+
+    fn tel_matches(&self, events: &HashMap<&str, Vec<i32>>, ts: &HashMap<&str, &str>, exps: &[TelExp], subjects: Option<&[String]>) -> Result<Vec<TelMatch>, BackendError> {
+        find_matches(&self.timeline_col, events, ts, exps, subjects)
+    }
+
+    fn query_subjects(&self, events: &HashMap<&str, Vec<i32>>, query: &TelQuery, subjects: Option<&[String]>) -> Result<Vec<String>, BackendError> {
+        find_subjects(&self.timeline_col, events, query, subjects)
+    }
+
+    fn explain_matches(&self, events: &HashMap<&str, Vec<i32>>, ts: &HashMap<&str, &str>, exps: &[TelExp], subjects: Option<&[String]>) -> Result<Document, BackendError> {
+        let mut pipeline = construct_query(events.clone(), ts.clone(), exps.to_vec());
+        if let Some(subjects) = subjects {
+            match_subjects(&mut pipeline, subjects);
+        }
+        explain(&self.db, &self.timeline_col, pipeline)
+    }
+
+    fn explain_subjects(&self, events: &HashMap<&str, Vec<i32>>, query: &TelQuery) -> Result<Document, BackendError> {
+        explain(&self.db, &self.timeline_col, construct_bool_query(events.clone(), query))
+    }
+
+    fn cohort(&self, dataset: &str, name: &str) -> Result<Option<Cohort>, BackendError> {
+        find_cohort(&self.db, &self.cohort_col, dataset, name)
+    }
+
+    fn cohorts(&self, dataset: &str) -> Result<Vec<Cohort>, BackendError> {
+        list_cohorts(&self.db, &self.cohort_col, dataset)
+    }
+
+    fn save_cohort(&self, cohort: &Cohort) -> Result<(), BackendError> {
+        insert_cohort(&self.db, &self.cohort_col, cohort)
+    }
+
+    fn delete_cohort(&self, dataset: &str, name: &str) -> Result<bool, BackendError> {
+        remove_cohort(&self.db, &self.cohort_col, dataset, name)
     }
 }
 
+impl Backend for EegMongoRepo {
+    fn check(&self, capabilities: &BTreeSet<Capability>) -> Check {
+        let collections = [
+            (Capability::Events, self.event_col.name()),
+            (Capability::Timeline, self.timeline_col.name()),
+            (Capability::Cohorts, self.cohort_col.name()),
+        ];
+        check_database(&self.db, &collections, capabilities)
+    }
+
+    fn get_event(&self, id: i32) -> Result<Option<Event>, BackendError> {
+        Ok(self.event_col.find_one(doc! {"id": id}, None)?)
+    }
+
+    fn timelines(&self, subjects: Option<&[String]>, event_ids: &[i32]) -> Result<Timelines, BackendError> {
+        load_timelines(&self.timeline_col, subjects, event_ids)
+    }
+
+    fn tel_matches(&self, events: &HashMap<&str, Vec<i32>>, ts: &HashMap<&str, &str>, exps: &[TelExp], subjects: Option<&[String]>) -> Result<Vec<TelMatch>, BackendError> {
+        find_matches(&self.timeline_col, events, ts, exps, subjects)
+    }
+
+    fn query_subjects(&self, events: &HashMap<&str, Vec<i32>>, query: &TelQuery, subjects: Option<&[String]>) -> Result<Vec<String>, BackendError> {
+        find_subjects(&self.timeline_col, events, query, subjects)
+    }
+
+    fn explain_matches(&self, events: &HashMap<&str, Vec<i32>>, ts: &HashMap<&str, &str>, exps: &[TelExp], subjects: Option<&[String]>) -> Result<Document, BackendError> {
+        let mut pipeline = construct_query(events.clone(), ts.clone(), exps.to_vec());
+        if let Some(subjects) = subjects {
+            match_subjects(&mut pipeline, subjects);
+        }
+        explain(&self.db, &self.timeline_col, pipeline)
+    }
+
+    fn explain_subjects(&self, events: &HashMap<&str, Vec<i32>>, query: &TelQuery) -> Result<Document, BackendError> {
+        explain(&self.db, &self.timeline_col, construct_bool_query(events.clone(), query))
+    }
+
+    fn cohort(&self, dataset: &str, name: &str) -> Result<Option<Cohort>, BackendError> {
+        find_cohort(&self.db, &self.cohort_col, dataset, name)
+    }
+
+    fn cohorts(&self, dataset: &str) -> Result<Vec<Cohort>, BackendError> {
+        list_cohorts(&self.db, &self.cohort_col, dataset)
+    }
+
+    fn save_cohort(&self, cohort: &Cohort) -> Result<(), BackendError> {
+        insert_cohort(&self.db, &self.cohort_col, cohort)
+    }
+
+    fn delete_cohort(&self, dataset: &str, name: &str) -> Result<bool, BackendError> {
+        remove_cohort(&self.db, &self.cohort_col, dataset, name)
+    }
+}
+
+// the _id of the documents of a pipeline sorting subjects, spilling the sort to disk if it must
+fn ordered_subjects(col: &Collection<Document>, pipeline: Vec<Document>) -> Result<Subjects<'static>, BackendError> {
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+    let cursor = col.aggregate(pipeline, options)?;
+    Ok(Box::new(cursor.map(|result| match result?.get("_id") {
+        Some(Bson::String(subject)) => Ok(subject.clone()),
+        _ => Err(BackendError::Database("subject without a name".to_string())),
+    })))
+}
+
+// telii documents of the pairs of an event of list1 before one of list2, None without any pair
+fn telii_filter(event_ids1: &[i32], event_ids2: &[i32]) -> Option<Document> {
+    let mut or_stmt: Vec<Document> = Vec::new();
+    for event_id2 in event_ids2 {
+        let _tmp_event_id1s: Vec<i32> = event_ids1.iter().copied().filter(|event_id1| event_id1 < event_id2).collect();
+        if !_tmp_event_id1s.is_empty() {
+            or_stmt.push(doc! {"e": event_id2, "b": { "$in": _tmp_event_id1s}});
+        }
+    }
+    for event_id1 in event_ids1 {
+        let _tmp_event_id2s: Vec<i32> = event_ids2.iter().copied().filter(|event_id2| event_id2 < event_id1).collect();
+        if !_tmp_event_id2s.is_empty() {
+            or_stmt.push(doc! {"e": event_id1, "a": { "$in": _tmp_event_id2s}});
+        }
+    }
+    if or_stmt.is_empty() {
+        return None;
+    }
+    Some(doc! {"$or": or_stmt})
+}
+
+// timelines of the given subjects restricted to the given events, for the native TEL evaluator
+fn load_timelines(timeline_col: &Collection<Document>, subjects: Option<&[String]>, event_ids: &[i32]) -> Result<Timelines, BackendError> {
+    let mut filter = doc! {"e": {"$in": event_ids}};
+    if let Some(subjects) = subjects {
+        filter.insert("subjectid", doc! {"$in": subjects});
+    }
+    read_timelines(timeline_col, filter)
+}
+
+fn read_timelines(timeline_col: &Collection<Document>, filter: Document) -> Result<Timelines, BackendError> {
+    let cursor = timeline_col.find(filter, None)?;
+    let mut timelines = Timelines::new();
+    for result in cursor {
+        let document = result?;
+        let subjectid = document.get_str("subjectid").unwrap_or_default().to_string();
+        let e = document.get_i32("e").unwrap_or_default();
+        let times = document.get_array("times").map(|t| t.to_vec()).unwrap_or_default();
+        let occurrences = timelines.entry(subjectid).or_default().entry(e).or_default();
+        for time in times {
+            if let Bson::Array(interval) = time {
+                if let (Some(Bson::DateTime(start)), Some(Bson::DateTime(end))) = (interval.first(), interval.last()) {
+                    occurrences.push((start.timestamp_millis(), end.timestamp_millis()));
+                }
+            }
+        }
+    }
+    Ok(timelines)
+}
+
+fn find_matches(timeline_col: &Collection<Document>, events: &HashMap<&str, Vec<i32>>, ts: &HashMap<&str, &str>, exps: &[TelExp], subjects: Option<&[String]>) -> Result<Vec<TelMatch>, BackendError> {
+    let mut pipeline = construct_query(events.clone(), ts.clone(), exps.to_vec());
+    if let Some(subjects) = subjects {
+        match_subjects(&mut pipeline, subjects);
+    }
+    let cursor = timeline_col.aggregate(pipeline, None)?;
+    let mut matches = Vec::new();
+    for result in cursor {
+        let document = result?;
+        if let Some(tel_match) = document.get_document("_id").ok().and_then(|id| match_from_document(id, events.keys())) {
+            matches.push(tel_match);
+        }
+    }
+    Ok(matches)
+}
+
+// the _id {subjectid, min_<group>, max_<group>} of a construct_query result
+fn match_from_document<'a>(document: &Document, groups: impl Iterator<Item = &'a &'a str>) -> Option<TelMatch> {
+    let mut intervals = BTreeMap::new();
+    for group in groups {
+        let start = document.get_datetime(format!("min_{}", group)).ok()?.timestamp_millis();
+        let end = document.get_datetime(format!("max_{}", group)).ok()?.timestamp_millis();
+        intervals.insert(group.to_string(), (start, end));
+    }
+    Some(TelMatch { subjectid: document.get_str("subjectid").ok()?.to_string(), intervals })
+}
+
+fn find_subjects(timeline_col: &Collection<Document>, events: &HashMap<&str, Vec<i32>>, query: &TelQuery, subjects: Option<&[String]>) -> Result<Vec<String>, BackendError> {
+    let mut pipeline = construct_bool_query(events.clone(), query);
+    if let Some(subjects) = subjects {
+        match_subjects(&mut pipeline, subjects);
+    }
+    let cursor = timeline_col.aggregate(pipeline, None)?;
+    let mut results = Vec::new();
+    for result in cursor {
+        let document = result?;
+        if let Ok(subjectid) = document.get_document("_id").and_then(|id| id.get_str("subjectid")) {
+            results.push(subjectid.to_string());
+        }
+    }
+    Ok(results)
+}
+
+// the subjects of a cohort per chunk of them, in the collection of the cohorts suffixed with
+// _subjects: a cohort of millions of subjects is over the size of one document
+const COHORT_CHUNK: usize = 100_000;
+
+fn chunk_col(db: &Database, cohort_col: &Collection<Document>) -> Collection<Document> {
+    db.collection(&format!("{}_subjects", cohort_col.name()))
+}
+
+fn find_cohort(db: &Database, cohort_col: &Collection<Document>, dataset: &str, name: &str) -> Result<Option<Cohort>, BackendError> {
+    match cohort_col.find_one(doc! {"_id": Cohort::key(dataset, name)}, None)? {
+        Some(document) => Ok(Some(read_cohort(db, cohort_col, document)?)),
+        None => Ok(None),
+    }
+}
+
+fn list_cohorts(db: &Database, cohort_col: &Collection<Document>, dataset: &str) -> Result<Vec<Cohort>, BackendError> {
+    let find_options = FindOptions::builder().sort(doc! {"name": 1}).build();
+    let mut cohorts = Vec::new();
+    for result in cohort_col.find(doc! {"dataset": dataset}, find_options)? {
+        cohorts.push(read_cohort(db, cohort_col, result?)?);
+    }
+    Ok(cohorts)
+}
+
+// the cohort of a stored document with the subjects of its chunks
+fn read_cohort(db: &Database, cohort_col: &Collection<Document>, mut document: Document) -> Result<Cohort, BackendError> {
+    let invalid = |e: String| BackendError::Database(format!("invalid cohort: {}", e));
+    let key = document.remove("_id").unwrap_or(Bson::Null);
+    let chunks = document.remove("chunks").and_then(|chunks| chunks.as_i64()).unwrap_or_default();
+    let find_options = FindOptions::builder().sort(doc! {"chunk": 1}).projection(doc! {"subjects": 1}).build();
+    let mut subjects = Vec::new();
+    let mut read = 0;
+    for result in chunk_col(db, cohort_col).find(doc! {"cohort": key}, find_options)? {
+        let chunk = result?;
+        let chunk = chunk.get_array("subjects").map_err(|e| invalid(e.to_string()))?;
+        subjects.extend(chunk.iter().filter_map(|subject| subject.as_str().map(str::to_string)));
+        read += 1;
+    }
+    if read != chunks {
+        return Err(invalid(format!("{} of {} chunks of subjects", read, chunks)));
+    }
+    document.insert("subjects", Vec::<String>::new());
+    let cohort: Cohort = bson::from_document(document).map_err(|e| invalid(e.to_string()))?;
+    Ok(Cohort { subjects, ..cohort })
+}
+
+// the dataset and name are the _id, a second save of them fails on the duplicate key; the
+// cohort is removed again if its subjects cannot all be written
+fn insert_cohort(db: &Database, cohort_col: &Collection<Document>, cohort: &Cohort) -> Result<(), BackendError> {
+    let chunks: Vec<&[String]> = cohort.subjects.chunks(COHORT_CHUNK).collect();
+    let mut document = cohort.to_document();
+    document.remove("subjects");
+    document.insert("chunks", chunks.len() as i64);
+    if let Err(e) = cohort_col.insert_one(document, None) {
+        return match *e.kind {
+            ErrorKind::Write(WriteFailure::WriteError(ref error)) if error.code == 11000 => Err(BackendError::Exists(cohort.name.clone())),
+            _ => Err(e.into()),
+        };
+    }
+    let key = Cohort::key(&cohort.dataset, &cohort.name);
+    let chunk_col = chunk_col(db, cohort_col);
+    let written = chunk_col.create_index(IndexModel::builder().keys(doc! {"cohort": 1, "chunk": 1}).build(), None)
+        .and_then(|_| chunk_col.delete_many(doc! {"cohort": &key}, None))
+        .and_then(|_| match chunks.is_empty() {
+            true => Ok(()),
+            false => {
+                let documents = chunks.iter().enumerate().map(|(i, subjects)| doc! {"cohort": &key, "chunk": i as i64, "subjects": subjects.to_vec()});
+                chunk_col.insert_many(documents, None).map(|_| ())
+            }
+        });
+    if let Err(e) = written {
+        remove_cohort(db, cohort_col, &cohort.dataset, &cohort.name)?;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+// whether there was a cohort of this dataset and name, its subjects removed with it
+fn remove_cohort(db: &Database, cohort_col: &Collection<Document>, dataset: &str, name: &str) -> Result<bool, BackendError> {
+    let key = Cohort::key(dataset, name);
+    let deleted = cohort_col.delete_one(doc! {"_id": &key}, None)?.deleted_count > 0;
+    chunk_col(db, cohort_col).delete_many(doc! {"cohort": &key}, None)?;
+    Ok(deleted)
+}
+
+// query plan of an aggregation on the timeline collection, without running it
+fn explain(db: &Database, timeline_col: &Collection<Document>, pipeline: Vec<Document>) -> Result<Document, BackendError> {
+    let command = doc! {
+        "explain": {"aggregate": timeline_col.name(), "pipeline": &pipeline, "cursor": {}},
+        "verbosity": "queryPlanner",
+    };
+    let plan = db.run_command(command, None)?;
+    Ok(doc! {"pipeline": pipeline, "explain": plan})
+}
@@ -0,0 +1,234 @@
+use crate::tel::allen::Anchor;
+use crate::tel::exp::{TelExp, TelOperator};
+use crate::tel::query::{Absence, TelFormula, TelQuery};
+use mongodb::bson::{doc, Document, Bson};
+use std::collections::HashSet;
+use std::collections::HashMap;
+
+// aggregation pipelines over a timeline collection {subjectid, e, times: [[start, end], ...]}
+
+// one document per subject with the distinct intervals of each event group, groups may be empty
+fn construct_group_stages(events: &HashMap<&str,Vec<i32>>) -> Vec<Document> {
+	// get all event ids from values of events
+	let mut event_ids: HashSet<i32> = HashSet::new();
+	let mut group_stmt = doc!{"_id": "$subjectid"};
+	let mut filter_none_time_stmt = doc!{ "_id":1};
+
+	for (_k,_v) in events.iter() {
+		let event_name = _k.to_string(); // Clone the value of event_name
+
+		event_ids.extend(_v);
+		group_stmt.insert(*_k, doc!{ "$addToSet": { "$cond": [ { "$in": [ "$e", _v ] }, "$times", None::<i32> ] } });
+		filter_none_time_stmt.insert(event_name.clone(), doc!{"$setDifference": [ format!("${}", event_name), [None::<i32>]]}); 
+	}
+	let event_ids: Vec<i32> = event_ids.into_iter().collect();
+
+	vec![
+		doc!{"$match": {"e": {"$in": event_ids}}},
+		doc!{"$project": {"_id": 0, "subjectid": 1, "e": 1, "times": 1}},
+		doc!{"$unwind": "$times"},
+		doc!{"$group": group_stmt},
+		doc!{"$project": filter_none_time_stmt},
+	]
+}
+
+// restrict a pipeline of construct_query or construct_bool_query to the given subjects, a
+// $match on subjectid alongside that on e, so the {subjectid, e} index serves both
+pub fn match_subjects(pipeline: &mut [Document], subjects: &[String]) {
+	if let Some(Ok(stage)) = pipeline.first_mut().map(|stage| stage.get_document_mut("$match")) {
+		stage.insert("subjectid", doc!{"$in": subjects});
+	}
+}
+
+pub fn construct_query(events: HashMap<&str,Vec<i32>>,ts:HashMap<&str,&str>,exps:Vec<TelExp>) -> Vec<Document> {
+	let mut filter = Vec::<Document>::new();
+	for _k in events.keys() {
+		filter.push(doc!{"$gt": [ {"$size": format!("${}", _k)}, 0]});
+	}
+
+	// get tel conditions
+	let tel_cond_stmt = construct_tel_cond(exps);
+	// print!("{:?}", tel_cond_stmt);
+
+	let mut mongo_stmt = construct_group_stages(&events);
+	mongo_stmt.push(doc!{"$match": { "$expr": { "$and": filter } }});
+	let mut project_stmt = doc!{"_id": 1};
+	for (_k,_v) in ts.iter() {
+		project_stmt.insert(_k.to_string(), format!("${}", _v));
+	}
+	for _k in events.keys() {
+		mongo_stmt.push(doc!{"$unwind": format!("${}", _k)});
+		project_stmt.insert(format!("min_{}", _k),doc!{"$arrayElemAt": [format!("${}", _k), 0]});
+		project_stmt.insert(format!("max_{}", _k),doc!{"$arrayElemAt": [format!("${}", _k), -1]});
+	}
+	mongo_stmt.push(doc!{"$project": project_stmt});
+	for _k in ts.keys() {
+		mongo_stmt.push( doc!{"$unwind": format!("${}", _k)} );
+	}
+	mongo_stmt.push(doc!{"$addFields": {"tel_cond": tel_cond_stmt}});
+	mongo_stmt.push(doc!{"$match": {"tel_cond": true}});
+	let mut group_fields = doc!{"subjectid": "$_id"};
+	for _k in events.keys() {
+		group_fields.insert(format!("min_{}", _k), format!("$min_{}", _k));
+		group_fields.insert(format!("max_{}", _k), format!("$max_{}", _k));
+	}
+	mongo_stmt.push(doc!{"$group": {"_id": group_fields}});
+
+	mongo_stmt
+}
+
+// Pipeline of a boolean query: the per-subject documents of construct_group_stages are kept
+// when the query tree holds, every formula being decided inside the same expression.
+pub fn construct_bool_query(events: HashMap<&str,Vec<i32>>, query: &TelQuery) -> Vec<Document> {
+	let mut mongo_stmt = construct_group_stages(&events);
+	mongo_stmt.push(doc!{"$addFields": {"tel_cond": {"$cond": [construct_query_cond(query), true, false]}}});
+	mongo_stmt.push(doc!{"$match": {"tel_cond": true}});
+	mongo_stmt.push(doc!{"$project": {"_id": {"subjectid": "$_id"}}});
+	mongo_stmt
+}
+
+pub fn construct_query_cond(query: &TelQuery) -> Document {
+	match query {
+		TelQuery::Formula(formula) => construct_formula_cond(formula),
+		TelQuery::Absence(absence) => construct_absence_cond(absence),
+		TelQuery::And(queries) => doc!{"$and": queries.iter().map(construct_query_cond).collect::<Vec<Document>>()},
+		TelQuery::Or(queries) => doc!{"$or": queries.iter().map(construct_query_cond).collect::<Vec<Document>>()},
+		TelQuery::Not(query) => doc!{"$not": [construct_query_cond(query)]},
+	}
+}
+
+// Some interval u_<g> of every group and some endpoint w_<t> of each bound interval satisfy
+// construct_tel_cond, its min_/max_ and time fields rebound to these variables.
+fn construct_formula_cond(formula: &TelFormula) -> Document {
+	let tel_cond = construct_tel_cond(formula.exps.clone());
+	let mut cond = match tel_cond.get_array("$cond").unwrap().first() {
+		Some(Bson::Document(and_stmt)) => rebind_fields(and_stmt),
+		_ => doc!{},
+	};
+	for (t, group) in formula.bindings.iter().rev() {
+		cond = doc!{"$anyElementTrue": [{"$map": {"input": format!("$$u_{}", group), "as": format!("w_{}", t), "in": cond}}]};
+	}
+	let groups = formula.groups();
+	let mut vars = doc!{};
+	for group in &groups {
+		vars.insert(format!("v_min_{}", group), doc!{"$arrayElemAt": [format!("$$u_{}", group), 0]});
+		vars.insert(format!("v_max_{}", group), doc!{"$arrayElemAt": [format!("$$u_{}", group), -1]});
+	}
+	cond = doc!{"$let": {"vars": vars, "in": cond}};
+	for group in groups.iter().rev() {
+		cond = doc!{"$anyElementTrue": [{"$map": {"input": format!("${}", group), "as": format!("u_{}", group), "in": cond}}]};
+	}
+	cond
+}
+
+// some interval u_<a> whose anchor v_from is not followed by the start of an interval of b
+fn construct_absence_cond(absence: &Absence) -> Document {
+	let (a, b) = (absence.a.as_str(), absence.b.as_str());
+	let anchor = match absence.from {
+		Anchor::Start => 0,
+		Anchor::End => -1,
+	};
+	let start = doc!{"$arrayElemAt": [format!("$$u_{}", b), 0]};
+	let mut follows = vec![doc!{"$gte": [start.clone(), "$$v_from"]}];
+	if let Some(window) = absence.window {
+		follows.push(doc!{"$lte": [start, {"$add": ["$$v_from", window]}]});
+	}
+	let followed = doc!{"$anyElementTrue": [{"$map": {"input": format!("${}", b), "as": format!("u_{}", b), "in": {"$and": follows}}}]};
+	doc!{"$anyElementTrue": [{"$map": {"input": format!("${}", a), "as": format!("u_{}", a), "in": {
+		"$let": {"vars": {"v_from": {"$arrayElemAt": [format!("$$u_{}", a), anchor]}}, "in": {"$not": [followed]}}
+	}}}]}
+}
+
+// "$min_x"/"$max_x" -> "$$v_min_x"/"$$v_max_x", any other "$t" -> "$$w_t"
+fn rebind_fields(stmt: &Document) -> Document {
+	fn rebind(value: &Bson) -> Bson {
+		match value {
+			Bson::String(field) if field.starts_with("$min_") || field.starts_with("$max_") => Bson::String(format!("$$v_{}", &field[1..])),
+			Bson::String(field) if field.starts_with('$') => Bson::String(format!("$$w_{}", &field[1..])),
+			Bson::Document(stmt) => Bson::Document(rebind_fields(stmt)),
+			Bson::Array(values) => Bson::Array(values.iter().map(rebind).collect()),
+			other => other.clone(),
+		}
+	}
+	stmt.iter().map(|(k, v)| (k.clone(), rebind(v))).collect()
+}
+
+pub fn construct_tel_cond(exps:Vec<TelExp>) -> Document {
+	let mut and_stmt: Vec<Document> = Vec::new();
+	for exp in exps {
+		let mongo_exp = match exp.operator {
+			TelOperator::BoxTPhi => box_t_phi(exp),
+			TelOperator::BoxTNegPhi => box_t_neg_phi(exp),
+			TelOperator::BoxPhiT => box_phi_t(exp),
+			TelOperator::BoxNegPhiT => box_neg_phi_t(exp),
+			TelOperator::DiamondTPhi => diamond_t_phi(exp),
+			TelOperator::DiamondTNegPhi => diamond_t_neg_phi(exp),
+			TelOperator::DiamondPhiT => diamond_phi_t(exp),
+			TelOperator::DiamondNegPhiT => diamond_neg_phi_t(exp),
+		};
+		and_stmt.push(mongo_exp);
+	}
+	doc!{ "$cond": [{"$and": and_stmt}, true, false] }
+}
+
+// start of the span: the explicit bound s, or the earliest start of the events
+fn span_start(exp: &TelExp) -> Bson {
+	match &exp.s {
+		Some(s) => Bson::String(format!("${}", s)),
+		None => {
+			let s_vec: Vec<String> = exp.events.iter().map(|x| format!("$min_{}", x)).collect();
+			Bson::Document(doc!{ "$min": s_vec })
+		}
+	}
+}
+
+// end of the span: the explicit bound e, or the latest end of the events
+fn span_end(exp: &TelExp) -> Bson {
+	match &exp.e {
+		Some(e) => Bson::String(format!("${}", e)),
+		None => {
+			let e_vec: Vec<String> = exp.events.iter().map(|x| format!("$max_{}", x)).collect();
+			Bson::Document(doc!{ "$max": e_vec })
+		}
+	}
+}
+
+pub fn box_t_phi(exp: TelExp) -> Document {
+	let s = span_start(&exp);
+	doc!{ "$and": [ { "$gte": [ s, format!("$min_{}", exp.event) ]}, { "$lte": [ { "$add": [ format!("${}", exp.t), exp.delta ] }, format!("$max_{}", exp.event) ] } ] }
+}
+
+pub fn box_t_neg_phi(exp: TelExp) -> Document {
+	let s = span_start(&exp);
+	doc!{ "$or": [ { "$gte": [ format!("$min_{}", exp.event), { "$add": [ format!("${}", exp.t) , exp.delta ] }] }, { "$lte": [ format!("$max_{}", exp.event), s] } ] }
+}
+
+pub fn box_phi_t(exp: TelExp) -> Document {
+	let e = span_end(&exp);
+	doc!{ "$and": [ { "$gte": [ { "$add": [ format!("${}", exp.t), exp.delta ] }, format!("$min_{}", exp.event) ] }, { "$gte": [ format!("$max_{}", exp.event), e ] } ] }
+}
+
+pub fn box_neg_phi_t(exp: TelExp) -> Document {
+	let e = span_end(&exp);
+	doc!{ "$or": [ { "$gte": [ format!("$min_{}", exp.event), e ] }, { "$lte": [ format!("$max_{}", exp.event), { "$add": [ format!("${}", exp.t), exp.delta ] }] } ] }
+}
+
+pub fn diamond_t_phi(exp: TelExp) -> Document {
+	let s = span_start(&exp);
+	doc!{ "$and": [ { "$gt": [ { "$add": [ format!("${}", exp.t), exp.delta ] },  format!("$min_{}", exp.event) ] }, { "$gt": [ format!("$max_{}", exp.event), s ] } ] }
+}
+
+pub fn diamond_t_neg_phi(exp: TelExp) -> Document {
+	let s = span_start(&exp);
+	doc!{ "$or": [ { "$gt": [ format!("$min_{}", exp.event), s ] }, { "$lt": [ format!("$max_{}", exp.event), { "$add": [ format!("${}", exp.t), exp.delta ] } ] } ] }
+}
+
+pub fn diamond_phi_t(exp: TelExp) -> Document {
+	let e = span_end(&exp);
+	doc!{ "$and": [ { "$gt": [ e, format!("$min_{}", exp.event)] }, { "$gt": [ format!("$max_{}", exp.event),  { "$add": [ format!("${}", exp.t), exp.delta ] } ] } ] }
+}
+
+pub fn diamond_neg_phi_t(exp: TelExp) -> Document {
+	let e = span_end(&exp);
+	doc!{ "$and": [{ "$gt": [ e.clone(), format!("${}", exp.t)]}, { "$or": [ { "$gt": [ format!("$min_{}", exp.event),  { "$add": [ format!("${}", exp.t), exp.delta ] } ] }, { "$lt": [ format!("$max_{}", exp.event), e ] }] }]}
+}
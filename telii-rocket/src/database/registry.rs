@@ -0,0 +1,206 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::backend::{Backend, BackendError, Check};
+use crate::database::embedded::EmbeddedBackend;
+use crate::database::mongodb::{database, MongoRepo};
+
+// Datasets the server queries, declared under `datasets` in the rocket configuration (Rocket.toml
+// or ROCKET_DATASETS), each either a mongodb database with its collection names or an index
+// directory, see Rocket.toml.example. The routes of a dataset are under /datasets/<name>.
+
+// datasets of the routes outside /datasets, kept for the existing clients
+pub const TELII_DATASET: &str = "telii";
+pub const EEG_DATASET: &str = "eeg";
+
+// kinds of index a dataset serves, each endpoint requires one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    // the event catalog, /event
+    Events,
+    // the term corpus, /corpus_search
+    Corpus,
+    Elii,
+    Telii,
+    // subject timelines, the TEL queries
+    Timeline,
+    // saved cohorts, /cohorts and the cohort and save parameters of the queries
+    Cohorts,
+}
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Events => "event catalog",
+            Capability::Corpus => "term corpus",
+            Capability::Elii => "elii",
+            Capability::Telii => "telii",
+            Capability::Timeline => "timelines",
+            Capability::Cohorts => "cohorts",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DatasetConfig {
+    // mongodb database name, or
+    pub db: Option<String>,
+    // index directory written by build_index or export_index
+    pub index_dir: Option<String>,
+    pub capabilities: BTreeSet<Capability>,
+    // collection names of a mongodb dataset, required by the capabilities using them
+    pub event: Option<String>,
+    pub corpus: Option<String>,
+    pub elii: Option<String>,
+    pub telii: Option<String>,
+    pub timeline: Option<String>,
+    // the subject dictionary of the elii bitmaps, as subject_v4; without it elii queries
+    // intersect the ptid_list
+    pub subject: Option<String>,
+    // saved cohorts, created by the first save; an index directory keeps them in cohorts.bson
+    pub cohort: Option<String>,
+}
+
+impl DatasetConfig {
+    // the collection a capability reads
+    fn collection(&self, capability: Capability) -> Option<&String> {
+        match capability {
+            Capability::Events => self.event.as_ref(),
+            Capability::Corpus => self.corpus.as_ref(),
+            Capability::Elii => self.elii.as_ref(),
+            Capability::Telii => self.telii.as_ref(),
+            Capability::Timeline => self.timeline.as_ref(),
+            Capability::Cohorts => self.cohort.as_ref(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.db, &self.index_dir) {
+            (Some(_), Some(_)) | (None, None) => Err("expected one of db and index_dir".to_string()),
+            (Some(_), None) => match self.capabilities.iter().find(|c| self.collection(**c).is_none()) {
+                Some(capability) => Err(format!("{} needs a collection name", capability.name())),
+                None => Ok(()),
+            },
+            (None, Some(_)) => Ok(()),
+        }
+    }
+
+    pub fn open(&self) -> Result<Box<dyn Backend>, String> {
+        self.validate()?;
+        if let Some(db) = &self.db {
+            let db = database(db).map_err(|e| e.to_string())?;
+            return Ok(Box::new(MongoRepo::open(db, self)));
+        }
+        let dir = self.index_dir.as_deref().unwrap_or_default();
+        match EmbeddedBackend::open(Path::new(dir)) {
+            Ok(backend) => Ok(Box::new(backend)),
+            Err(e) => Err(format!("error opening index directory {}: {}", dir, e)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Dataset {
+    pub capabilities: BTreeSet<Capability>,
+    // shared with the blocking tasks running the queries, or why it could not be opened
+    pub backend: Result<Arc<dyn Backend>, String>,
+}
+
+impl Dataset {
+    pub fn check(&self) -> Check {
+        match &self.backend {
+            Ok(backend) => backend.check(&self.capabilities),
+            Err(e) => Check::failed(e.clone()),
+        }
+    }
+}
+
+// the datasets by name, the managed state of the query apis, with the last check of each
+#[derive(Default)]
+pub struct Datasets {
+    datasets: BTreeMap<String, Dataset>,
+    checks: RwLock<BTreeMap<String, Check>>,
+}
+
+impl Datasets {
+    pub fn new() -> Self {
+        Datasets::default()
+    }
+
+    // a dataset that cannot be opened is kept, unavailable, so the others can still be served
+    pub fn open(configs: &BTreeMap<String, DatasetConfig>) -> Self {
+        let mut datasets = Datasets::new();
+        for (name, config) in configs {
+            datasets.insert_dataset(name, Dataset { capabilities: config.capabilities.clone(), backend: config.open().map(Arc::from) });
+        }
+        datasets
+    }
+
+    pub fn insert(&mut self, name: &str, capabilities: BTreeSet<Capability>, backend: Box<dyn Backend>) {
+        self.insert_dataset(name, Dataset { capabilities, backend: Ok(Arc::from(backend)) });
+    }
+
+    pub fn insert_dataset(&mut self, name: &str, dataset: Dataset) {
+        self.datasets.insert(name.to_string(), dataset);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Dataset)> {
+        self.datasets.iter()
+    }
+
+    // the backend of a dataset for an endpoint requiring the capability
+    pub fn backend(&self, name: &str, capability: Capability) -> Result<Arc<dyn Backend>, BackendError> {
+        let dataset = self.datasets.get(name).ok_or_else(|| BackendError::UnknownDataset(name.to_string()))?;
+        if !dataset.capabilities.contains(&capability) {
+            return Err(BackendError::Unsupported(capability.name()));
+        }
+        if let Some(check) = self.checks.read().unwrap().get(name).filter(|check| !check.ready()) {
+            return Err(BackendError::Unavailable(check.errors.join("; ")));
+        }
+        dataset.backend.clone().map_err(BackendError::Unavailable)
+    }
+
+    // check every dataset, see Backend::check
+    pub fn check(&self) -> BTreeMap<String, Check> {
+        let checks: BTreeMap<String, Check> = self.datasets.iter().map(|(name, dataset)| (name.clone(), dataset.check())).collect();
+        self.record(checks.clone());
+        checks
+    }
+
+    pub fn record(&self, checks: BTreeMap<String, Check>) {
+        self.checks.write().unwrap().extend(checks);
+    }
+
+    pub fn checks(&self) -> BTreeMap<String, Check> {
+        self.checks.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::backend::MemoryBackend;
+
+    #[test]
+    fn checks_dataset_capabilities() {
+        let config = DatasetConfig {
+            db: Some("eegdb".to_string()),
+            capabilities: BTreeSet::from([Capability::Events, Capability::Timeline]),
+            event: Some("event_v4".to_string()),
+            ..DatasetConfig::default()
+        };
+        assert_eq!(config.validate(), Err("timelines needs a collection name".to_string()));
+        let config = DatasetConfig { index_dir: Some("index".to_string()), ..config };
+        assert!(config.validate().is_err());
+
+        let mut datasets = Datasets::new();
+        datasets.insert("eeg", BTreeSet::from([Capability::Timeline]), Box::new(MemoryBackend::new(Default::default())));
+        assert!(datasets.backend("eeg", Capability::Timeline).is_ok());
+        assert!(matches!(datasets.backend("eeg", Capability::Telii), Err(BackendError::Unsupported("telii"))));
+        assert!(matches!(datasets.backend("optum", Capability::Timeline), Err(BackendError::UnknownDataset(_))));
+    }
+}
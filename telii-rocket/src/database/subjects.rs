@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use roaring::RoaringBitmap;
+
+use crate::database::backend::BackendError;
+
+// Subject ids dictionary-encoded to dense integers, so that sets of subjects are roaring
+// bitmaps: the ELII postings, the subjects of TELII and timeline queries and cohorts. Ids are
+// only ever appended, an index keeps the ids of its subjects across incremental updates.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubjectDictionary {
+    names: Vec<String>,
+    ids: HashMap<String, u32>,
+}
+
+impl SubjectDictionary {
+    pub fn new() -> Self {
+        SubjectDictionary::default()
+    }
+
+    // the subjects in id order, repeated ones keep their first id
+    pub fn from_names(names: impl IntoIterator<Item = String>) -> Self {
+        let mut dictionary = SubjectDictionary::new();
+        for name in names {
+            dictionary.insert(&name);
+        }
+        dictionary
+    }
+
+    // the id of a subject, the next free one if it is new
+    pub fn insert(&mut self, name: &str) -> u32 {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.names.len() as u32;
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    pub fn id(&self, name: &str) -> Option<u32> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: u32) -> Option<&str> {
+        self.names.get(id as usize).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    // subjects missing from the dictionary are left out
+    pub fn encode<'a>(&self, names: impl IntoIterator<Item = &'a String>) -> RoaringBitmap {
+        names.into_iter().filter_map(|name| self.id(name)).collect()
+    }
+
+    // ids beyond the dictionary are left out
+    pub fn decode(&self, bitmap: &RoaringBitmap) -> Vec<String> {
+        bitmap.iter().filter_map(|id| self.name(id)).map(str::to_string).collect()
+    }
+
+    // whether the bitmap has ids the dictionary does not know, as after an update of the index
+    pub fn is_behind(&self, bitmap: &RoaringBitmap) -> bool {
+        bitmap.max().is_some_and(|id| id as usize >= self.names.len())
+    }
+}
+
+// the portable roaring serialization, as stored in elii.bin and the ptid_bitmap of elii documents
+pub fn bitmap_bytes(bitmap: &RoaringBitmap) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(bitmap.serialized_size());
+    // writing into a vec does not fail
+    bitmap.serialize_into(&mut bytes).unwrap();
+    bytes
+}
+
+pub fn bitmap_from_bytes(bytes: &[u8]) -> Result<RoaringBitmap, BackendError> {
+    RoaringBitmap::deserialize_from(bytes).map_err(|e| BackendError::Database(format!("corrupt subject bitmap: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_subjects_as_bitmaps() {
+        let mut dictionary = SubjectDictionary::from_names(["p2", "p1", "p2"].map(String::from));
+        assert_eq!((dictionary.id("p2"), dictionary.id("p1"), dictionary.len()), (Some(0), Some(1), 2));
+        assert_eq!(dictionary.insert("p3"), 2);
+
+        let bitmap = dictionary.encode(&["p3".to_string(), "p9".to_string(), "p2".to_string()]);
+        assert_eq!(bitmap.iter().collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(dictionary.decode(&bitmap), vec!["p2", "p3"]);
+        assert_eq!(bitmap_from_bytes(&bitmap_bytes(&bitmap)).unwrap(), bitmap);
+        assert!(bitmap_from_bytes(b"not a bitmap").is_err());
+
+        let behind = SubjectDictionary::from_names(["p2".to_string()]);
+        assert!(behind.is_behind(&bitmap) && !dictionary.is_behind(&bitmap));
+    }
+}
@@ -0,0 +1,6 @@
+#[macro_use] extern crate rocket;
+
+pub mod models;
+pub mod database;
+pub mod api;
+pub mod tel;
@@ -1,27 +1,25 @@
 
 
-mod models;
-mod database;
-mod api;
-
 #[macro_use] extern crate rocket;
 use std::env;
-use rocket::{get, http::Status, serde::json::Json, State};
-use rocket::http::ContentType;
-use rocket::response::Responder;
-use rocket::Request;
-use rocket::response::Response;
-use std::io::Cursor;
+use rocket::{get, State};
 use std::time::Instant;
 use rocket::response::content::RawHtml;
 use rocket::form::Form;
-use rocket::local::blocking::Client;
 
-use api::event_api::{get_event, corpus_search};
-use api::query_api::{elii, rtq_telii};
-use api::eeg_query_api::{eeg_allen_query};
-use database::mongodb::{MongoRepo, EegMongoRepo};
-use mongodb::{bson::{doc, Document,Bson}};
+use std::collections::{BTreeMap, BTreeSet};
+use telii_rocket::api::event_api::{get_event, corpus_search, dataset_get_event, dataset_corpus_search, list_datasets};
+use telii_rocket::api::query_api::{elii, elii_cooccurrence, dataset_elii_cooccurrence, telii_neighbors, dataset_telii_neighbors, rtq_telii, telii_subjects, rtqti_telii, rtq_absence_telii, dataset_elii, dataset_rtq_telii, dataset_rtqti_telii, dataset_rtq_absence_telii};
+use telii_rocket::api::eeg_query_api::{eeg_allen_query, run_allen_query, eeg_tel_query, eeg_pattern_query, eeg_gap_query, eeg_absence_query, eeg_bool_query, eeg_tel_crosscheck, eeg_bool_crosscheck};
+use telii_rocket::api::eeg_query_api::{dataset_eeg_allen_query, dataset_eeg_tel_query, dataset_eeg_pattern_query, dataset_eeg_gap_query, dataset_eeg_absence_query, dataset_eeg_bool_query, dataset_eeg_tel_crosscheck, dataset_eeg_bool_crosscheck, GapQueryParams};
+use std::sync::Arc;
+use telii_rocket::api::health_api::{health, ready};
+use telii_rocket::api::cohort_api::{list_cohorts, get_cohort, delete_cohort, derive_cohort, CohortParams};
+use telii_rocket::database::backend::{Backend, BackendError};
+use telii_rocket::database::embedded::EmbeddedBackend;
+use telii_rocket::database::mongodb::{MongoRepo, EegMongoRepo};
+use telii_rocket::database::registry::{Capability, Dataset, DatasetConfig, Datasets, EEG_DATASET, TELII_DATASET};
+use mongodb::bson::doc;
 
 #[derive(FromForm)]
 struct SearchTerm {
@@ -57,15 +55,15 @@ fn index() -> RawHtml<String> {
 }
 
 #[post("/search", data = "<search_term>")]
-fn search(db: &State<MongoRepo>,search_term: Form<SearchTerm>) -> String {
+async fn search(db: &State<Datasets>,search_term: Form<SearchTerm>) -> String {
     let start = Instant::now();
-    let query_response = rtq_telii(db,&search_term.query1,&search_term.query2,None);
+    let query_response = telii_subjects(db,TELII_DATASET,&search_term.query1,&search_term.query2).await;
     let query_len = match &query_response {
-        Ok(val) => val.0.len(), // Get the length of Vec<String>
+        Ok(val) => val.len(), // Get the length of Vec<String>
         Err(_) => 0, // Handle error
     };
     let query_response = match query_response {
-        Ok(val) => format!("{:?}", val.0), // Convert Vec<String> to a single String
+        Ok(val) => format!("{:?}", val), // Convert Vec<String> to a single String
         Err(_) => String::from("Error occurred"), // Handle error
     };
     // get the elapsed time in seconds
@@ -91,9 +89,9 @@ fn event_explore() -> RawHtml<String> {
 }
 
 #[post("/event_search", data = "<search_term>")]
-fn event_search(db: &State<MongoRepo>,search_term: Form<CorpusSearchTerm>) -> String {
+async fn event_search(db: &State<Datasets>,search_term: Form<CorpusSearchTerm>) -> String {
     let start = Instant::now();
-    let query_response = corpus_search(db,&search_term.term);
+    let query_response = corpus_search(db,&search_term.term).await;
     let query_len = match &query_response {
         Ok(val) => val.0.len(), // Get the length of Vec<String>
         Err(_) => 0, // Handle error
@@ -131,15 +129,17 @@ fn eeg_before_query_page() -> RawHtml<String> {
 }
 
 #[post("/eeg_before_result", data = "<search_term>")]
-fn eeg_before_result(eegdb: &State<EegMongoRepo>,search_term: Form<SearchTerm>) -> String {
+async fn eeg_before_result(eegdb: &State<Datasets>,search_term: Form<SearchTerm>) -> String {
     let start = Instant::now();
-    let relation = "before";
-    let query_response = eeg_allen_query(eegdb,relation,&search_term.query1,&search_term.query2);
-    // create eeg_allen_query api query uri with server ip and port
+    // before, the second event starting within a minute of the end of the first
+    let max_gap = "60s";
+    let params = GapQueryParams::before_within(&search_term.query1, &search_term.query2, max_gap);
+    let query_response = dataset_eeg_gap_query(eegdb,EEG_DATASET,params).await;
+    // create eeg_gap_query api query uri with server ip and port
     let server_address = env::var("SERVER_ADDRESS");
     let server_port = env::var("SERVER_PORT");
     let query_uri = match (server_address, server_port) {
-        (Ok(address), Ok(port)) => format!("http://{}:{}/eeg_allen_query?relation={}&query1={}&query2={}",address,port,relation,&search_term.query1,&search_term.query2),
+        (Ok(address), Ok(port)) => format!("http://{}:{}/eeg_gap_query?event_id_list1={}&event_id_list2={}&max_gap={}&relation=before",address,port,&search_term.query1,&search_term.query2,max_gap),
         _ => String::from("Error getting server address and port"),
     };
 
@@ -157,7 +157,7 @@ fn eeg_before_result(eegdb: &State<EegMongoRepo>,search_term: Form<SearchTerm>)
     output.push_str(&format!("Latex: {}\n", latex));
     let mongo_query = query_response.get_document("tel_cond").unwrap().to_string();
     output.push_str(&format!("Mongo query: {}\n", mongo_query));
-    output.push_str(&format!("Results(up to 10):\n"));
+    output.push_str("Results(up to 10):\n");
     let mut pattern_n = 0;
     let mut subject_set = std::collections::HashSet::new();
     for _doc in query_response.get_array("results").unwrap().iter().map(|doc| doc.as_document().unwrap()) {
@@ -173,7 +173,7 @@ fn eeg_before_result(eegdb: &State<EegMongoRepo>,search_term: Form<SearchTerm>)
     output.push_str(&format!("Number of subjects: {}\n", subject_set.len()));
     output.push_str(&format!("Number of patterns: {}\n", pattern_n));
     output.push_str(&format!("See full API response: {}\n", query_uri));
-    return output;
+    output
 }
 
 #[get("/eeg_query_page")]
@@ -195,13 +195,19 @@ fn eeg_query_page() -> RawHtml<String> {
                     <option value="652">Ictal</option>
                 </select>
                 <select id="relation" name="relation">
-                    <option value="equal">Equal</option>
                     <option value="before" selected>Before</option>
-                    <option value="meet">Meet</option>
-                    <option value="contain">Contain</option>
-                    <option value="start">Start With</option>
-                    <option value="end">End With</option>
-                    <option value="overlap">Overlap</option>
+                    <option value="after">After</option>
+                    <option value="meets">Meets</option>
+                    <option value="met-by">Met By</option>
+                    <option value="overlaps">Overlaps</option>
+                    <option value="overlapped-by">Overlapped By</option>
+                    <option value="starts">Starts</option>
+                    <option value="started-by">Start With</option>
+                    <option value="during">During</option>
+                    <option value="contains">Contain</option>
+                    <option value="finishes">Finishes</option>
+                    <option value="finished-by">End With</option>
+                    <option value="equals">Equal</option>
                 </select>
                 <select id="event2" name="event2">
                     <option value="53">EEG Seizure</option>
@@ -223,9 +229,9 @@ fn eeg_query_page() -> RawHtml<String> {
 
 
 #[post("/eeg_query_result", data = "<eeg_search_params>")]
-fn eeg_query_result(eegdb: &State<EegMongoRepo>,eeg_search_params: Form<EegSearchParams>) -> String {
+async fn eeg_query_result(eegdb: &State<Datasets>,eeg_search_params: Form<EegSearchParams>) -> String {
     let start = Instant::now();
-    let query_response = eeg_allen_query(eegdb,&eeg_search_params.relation,&eeg_search_params.event1,&eeg_search_params.event2);
+    let query_response = run_allen_query(eegdb,EEG_DATASET,&eeg_search_params.relation,&eeg_search_params.event1,&eeg_search_params.event2,None,CohortParams::none()).await;
     // create eeg_allen_query api query uri with server ip and port
     let server_address = env::var("SERVER_ADDRESS");
     let server_port = env::var("SERVER_PORT");
@@ -248,7 +254,7 @@ fn eeg_query_result(eegdb: &State<EegMongoRepo>,eeg_search_params: Form<EegSearc
     output.push_str(&format!("Latex: {}\n", latex));
     let mongo_query = query_response.get_document("tel_cond").unwrap().to_string();
     output.push_str(&format!("Mongo query: {}\n", mongo_query));
-    output.push_str(&format!("Results(up to 10):\n"));
+    output.push_str("Results(up to 10):\n");
     let mut pattern_n = 0;
     let mut subject_set = std::collections::HashSet::new();
     for _doc in query_response.get_array("results").unwrap().iter().map(|doc| doc.as_document().unwrap()) {
@@ -264,16 +270,64 @@ fn eeg_query_result(eegdb: &State<EegMongoRepo>,eeg_search_params: Form<EegSearc
     output.push_str(&format!("Number of subjects: {}\n", subject_set.len()));
     output.push_str(&format!("Number of patterns: {}\n", pattern_n));
     output.push_str(&format!("See full API response: {}\n", query_uri));
-    return output;
+    output
+}
+
+// a dataset is served from the index directory named by the environment variable if set,
+// otherwise from mongodb
+fn backend(index_dir_var: &str, mongo: fn() -> Result<Box<dyn Backend>, BackendError>) -> Result<Arc<dyn Backend>, String> {
+    dotenv::dotenv().ok();
+    let backend: Box<dyn Backend> = match env::var(index_dir_var) {
+        Ok(dir) => match EmbeddedBackend::open(std::path::Path::new(&dir)) {
+            Ok(backend) => Box::new(backend),
+            Err(e) => return Err(format!("error opening index directory {}: {}", dir, e)),
+        },
+        Err(_) => mongo().map_err(|e| e.to_string())?,
+    };
+    Ok(Arc::from(backend))
+}
+
+// the datasets of the rocket configuration, or without any the TELII and EEG datasets
+fn datasets(figment: &rocket::figment::Figment) -> Datasets {
+    if figment.contains("datasets") {
+        match figment.extract_inner::<BTreeMap<String, DatasetConfig>>("datasets") {
+            Ok(configs) => return Datasets::open(&configs),
+            Err(e) => {
+                eprintln!("Error reading datasets: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut datasets = Datasets::new();
+    let telii = [Capability::Events, Capability::Corpus, Capability::Elii, Capability::Telii, Capability::Timeline, Capability::Cohorts];
+    let backend_telii = backend("TELII_INDEX_DIR", || Ok(Box::new(MongoRepo::init()?)));
+    datasets.insert_dataset(TELII_DATASET, Dataset { capabilities: BTreeSet::from(telii), backend: backend_telii });
+    let eeg = [Capability::Events, Capability::Timeline, Capability::Cohorts];
+    let backend_eeg = backend("EEG_INDEX_DIR", || Ok(Box::new(EegMongoRepo::init()?)));
+    datasets.insert_dataset(EEG_DATASET, Dataset { capabilities: BTreeSet::from(eeg), backend: backend_eeg });
+    datasets
+}
+
+// check the datasets before serving them; those failing stay unavailable until /ready passes
+fn check_datasets(datasets: &Datasets) {
+    for (name, check) in datasets.check() {
+        println!("dataset {}: {}", name, if check.ready() { "ready" } else { "unavailable" });
+        for error in &check.errors {
+            println!("  error: {}", error);
+        }
+        for warning in &check.warnings {
+            println!("  warning: {}", warning);
+        }
+    }
 }
 
 #[launch]
 fn rocket() -> _ {
-    let db = MongoRepo::init();
-    let eegdb: EegMongoRepo = EegMongoRepo::init();
-    rocket::build()
-        .manage(db)
-        .manage(eegdb)
-        .mount("/", routes![index, search, event_explore, event_search,  get_event, elii, rtq_telii, eeg_before_query_page, eeg_query_page, eeg_before_result, eeg_query_result, eeg_allen_query])
-
+    let rocket = rocket::build();
+    let datasets = datasets(rocket.figment());
+    check_datasets(&datasets);
+    rocket
+        .manage(datasets)
+        .mount("/", routes![index, search, event_explore, event_search,  get_event, elii, elii_cooccurrence, rtq_telii, telii_neighbors, rtqti_telii, rtq_absence_telii, eeg_before_query_page, eeg_query_page, eeg_before_result, eeg_query_result, eeg_allen_query, eeg_tel_query, eeg_pattern_query, eeg_gap_query, eeg_absence_query, eeg_bool_query, eeg_tel_crosscheck, eeg_bool_crosscheck])
+        .mount("/", routes![health, ready, list_datasets, list_cohorts, get_cohort, delete_cohort, derive_cohort, dataset_get_event, dataset_corpus_search, dataset_elii, dataset_elii_cooccurrence, dataset_rtq_telii, dataset_telii_neighbors, dataset_rtqti_telii, dataset_rtq_absence_telii, dataset_eeg_allen_query, dataset_eeg_tel_query, dataset_eeg_pattern_query, dataset_eeg_gap_query, dataset_eeg_absence_query, dataset_eeg_bool_query, dataset_eeg_tel_crosscheck, dataset_eeg_bool_crosscheck])
 }
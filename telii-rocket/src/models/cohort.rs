@@ -0,0 +1,140 @@
+use mongodb::bson::{self, doc, DateTime, Document};
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use std::hash::Hash;
+
+// the subjects of a query result saved under a name, per dataset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cohort {
+    pub name: String,
+    pub dataset: String,
+    // the request that found the subjects, as /datasets/telii/elii?event_id_list1=250&event_id_list2=300
+    pub query: String,
+    pub created_at: DateTime,
+    pub subjects: Vec<String>,
+    // how a cohort made of other cohorts was, none for a saved query result
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lineage: Option<Lineage>,
+}
+
+// the set operation of a derived cohort and the cohorts it took, in order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lineage {
+    pub operation: String,
+    pub inputs: Vec<String>,
+}
+
+// an operation over patient sets, of two or more of them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOp {
+    Union,
+    Intersection,
+    // the first set without the subjects of the others
+    Difference,
+    // the subjects in an odd number of the sets
+    SymmetricDifference,
+}
+
+impl SetOp {
+    pub fn parse(name: &str) -> Option<SetOp> {
+        match name {
+            "union" => Some(SetOp::Union),
+            "intersection" => Some(SetOp::Intersection),
+            "difference" => Some(SetOp::Difference),
+            "symmetric_difference" => Some(SetOp::SymmetricDifference),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SetOp::Union => "union",
+            SetOp::Intersection => "intersection",
+            SetOp::Difference => "difference",
+            SetOp::SymmetricDifference => "symmetric_difference",
+        }
+    }
+
+    pub fn apply<T: Eq + Hash + Clone>(&self, sets: &[HashSet<T>]) -> HashSet<T> {
+        let Some((first, rest)) = sets.split_first() else {
+            return HashSet::new();
+        };
+        rest.iter().fold(first.clone(), |result, set| match self {
+            SetOp::Union => &result | set,
+            SetOp::Intersection => &result & set,
+            SetOp::Difference => &result - set,
+            SetOp::SymmetricDifference => &result ^ set,
+        })
+    }
+}
+
+impl Cohort {
+    pub fn new(name: &str, dataset: &str, query: &str, mut subjects: Vec<String>) -> Self {
+        subjects.sort();
+        subjects.dedup();
+        Cohort { name: name.to_string(), dataset: dataset.to_string(), query: query.to_string(), created_at: DateTime::now(), subjects, lineage: None }
+    }
+
+    // the cohort of an operation over the given cohorts
+    pub fn derive(name: &str, dataset: &str, query: &str, operation: SetOp, inputs: &[Cohort]) -> Self {
+        let sets: Vec<HashSet<&String>> = inputs.iter().map(|cohort| cohort.subjects.iter().collect()).collect();
+        let subjects = operation.apply(&sets).into_iter().cloned().collect();
+        let lineage = Lineage {
+            operation: operation.name().to_string(),
+            inputs: inputs.iter().map(|cohort| cohort.name.clone()).collect(),
+        };
+        Cohort { lineage: Some(lineage), ..Cohort::new(name, dataset, query, subjects) }
+    }
+
+    // the key of a cohort, names being per dataset
+    pub fn key(dataset: &str, name: &str) -> Document {
+        doc! {"dataset": dataset, "name": name}
+    }
+
+    // the cohort as stored, keyed by its dataset and name
+    pub fn to_document(&self) -> Document {
+        let mut document = doc! {"_id": Cohort::key(&self.dataset, &self.name)};
+        document.extend(bson::to_document(self).unwrap_or_default());
+        document
+    }
+
+    // the api response, with the subjects or only their number
+    pub fn summary(&self, with_subjects: bool) -> Document {
+        let mut document = doc! {
+            "name": &self.name,
+            "dataset": &self.dataset,
+            "query": &self.query,
+            "created_at": self.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            "size": self.subjects.len() as i64,
+        };
+        if let Some(lineage) = &self.lineage {
+            document.insert("lineage", doc! {"operation": &lineage.operation, "inputs": &lineage.inputs});
+        }
+        if with_subjects {
+            document.insert("subjects", &self.subjects);
+        }
+        document
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_operations_derive_cohorts() {
+        let cohort = |name: &str, subjects: &[&str]| Cohort::new(name, "telii", "/elii", subjects.iter().map(|s| s.to_string()).collect());
+        let inputs = [cohort("a", &["p1", "p2", "p3"]), cohort("b", &["p2", "p4"]), cohort("c", &["p2", "p3", "p5"])];
+
+        let derived = |operation| Cohort::derive("d", "telii", "/cohorts", operation, &inputs).subjects;
+        assert_eq!(derived(SetOp::Union), vec!["p1", "p2", "p3", "p4", "p5"]);
+        assert_eq!(derived(SetOp::Intersection), vec!["p2"]);
+        assert_eq!(derived(SetOp::Difference), vec!["p1"]);
+        assert_eq!(derived(SetOp::SymmetricDifference), vec!["p1", "p2", "p4", "p5"]);
+
+        let lineage = Cohort::derive("d", "telii", "/cohorts", SetOp::Difference, &inputs).lineage;
+        assert_eq!(lineage, Some(Lineage { operation: "difference".to_string(), inputs: vec!["a".to_string(), "b".to_string(), "c".to_string()] }));
+        assert_eq!(SetOp::parse("symmetric_difference"), Some(SetOp::SymmetricDifference));
+        assert_eq!(SetOp::parse("xor"), None);
+    }
+}
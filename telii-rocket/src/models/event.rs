@@ -1,8 +1,7 @@
 use mongodb::bson;
-use mongodb::bson::oid::ObjectId;
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Event {
     pub id: i32,
     pub cov_diag: Option<bson::Document>,
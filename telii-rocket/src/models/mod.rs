@@ -1 +1,2 @@
-pub mod event;
\ No newline at end of file
+pub mod cohort;
+pub mod event;
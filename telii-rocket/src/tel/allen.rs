@@ -0,0 +1,362 @@
+use std::fmt;
+
+use crate::tel::exp::{TelExp, TelOperator};
+
+// The 13 Allen interval relations between two event groups a and b.
+// Inverse relations are encoded by swapping the roles of a and b.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AllenRelation {
+	Before,
+	After,
+	Meets,
+	MetBy,
+	Overlaps,
+	OverlappedBy,
+	Starts,
+	StartedBy,
+	During,
+	Contains,
+	Finishes,
+	FinishedBy,
+	Equals,
+}
+
+impl AllenRelation {
+	pub const ALL: [AllenRelation; 13] = [
+		AllenRelation::Before,
+		AllenRelation::After,
+		AllenRelation::Meets,
+		AllenRelation::MetBy,
+		AllenRelation::Overlaps,
+		AllenRelation::OverlappedBy,
+		AllenRelation::Starts,
+		AllenRelation::StartedBy,
+		AllenRelation::During,
+		AllenRelation::Contains,
+		AllenRelation::Finishes,
+		AllenRelation::FinishedBy,
+		AllenRelation::Equals,
+	];
+
+	// accepts the relation names, with '-' or '_', and the legacy names of eeg_allen_query
+	pub fn parse(name: &str) -> Option<Self> {
+		let name = name.to_lowercase().replace('_', "-");
+		match name.as_str() {
+			"meet" => return Some(AllenRelation::Meets),
+			"overlap" => return Some(AllenRelation::Overlaps),
+			"start" => return Some(AllenRelation::StartedBy),
+			"contain" => return Some(AllenRelation::Contains),
+			"end" => return Some(AllenRelation::FinishedBy),
+			"equal" => return Some(AllenRelation::Equals),
+			_ => {}
+		}
+		AllenRelation::ALL.iter().copied().find(|r| r.name() == name)
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			AllenRelation::Before => "before",
+			AllenRelation::After => "after",
+			AllenRelation::Meets => "meets",
+			AllenRelation::MetBy => "met-by",
+			AllenRelation::Overlaps => "overlaps",
+			AllenRelation::OverlappedBy => "overlapped-by",
+			AllenRelation::Starts => "starts",
+			AllenRelation::StartedBy => "started-by",
+			AllenRelation::During => "during",
+			AllenRelation::Contains => "contains",
+			AllenRelation::Finishes => "finishes",
+			AllenRelation::FinishedBy => "finished-by",
+			AllenRelation::Equals => "equals",
+		}
+	}
+
+	pub fn inverse(&self) -> Self {
+		match self {
+			AllenRelation::Before => AllenRelation::After,
+			AllenRelation::After => AllenRelation::Before,
+			AllenRelation::Meets => AllenRelation::MetBy,
+			AllenRelation::MetBy => AllenRelation::Meets,
+			AllenRelation::Overlaps => AllenRelation::OverlappedBy,
+			AllenRelation::OverlappedBy => AllenRelation::Overlaps,
+			AllenRelation::Starts => AllenRelation::StartedBy,
+			AllenRelation::StartedBy => AllenRelation::Starts,
+			AllenRelation::During => AllenRelation::Contains,
+			AllenRelation::Contains => AllenRelation::During,
+			AllenRelation::Finishes => AllenRelation::FinishedBy,
+			AllenRelation::FinishedBy => AllenRelation::Finishes,
+			AllenRelation::Equals => AllenRelation::Equals,
+		}
+	}
+
+	// TEL encoding of "a <relation> b" with time variable t;
+	// returns the event group t ranges over and the conjunction of expressions
+	pub fn encode<'a>(&self, t: &str, a: &'a str, b: &'a str) -> (&'a str, Vec<TelExp>) {
+		let events = Some(vec![a, b]);
+		let exp = |operator: TelOperator, event: &str| TelExp::init(operator, t, event, events.clone(), None, None, None);
+		match self {
+			AllenRelation::Before => (a, vec![
+				exp(TelOperator::BoxTPhi, a),
+				exp(TelOperator::BoxNegPhiT, a),
+				exp(TelOperator::BoxTNegPhi, b),
+				exp(TelOperator::DiamondNegPhiT, b),
+			]),
+			AllenRelation::Meets => (a, vec![
+				exp(TelOperator::BoxTPhi, a),
+				exp(TelOperator::BoxTNegPhi, b),
+				exp(TelOperator::BoxNegPhiT, a),
+				exp(TelOperator::BoxPhiT, b),
+			]),
+			AllenRelation::Overlaps => (a, vec![
+				exp(TelOperator::BoxTPhi, a),
+				exp(TelOperator::DiamondTPhi, b),
+				exp(TelOperator::DiamondTNegPhi, b),
+				exp(TelOperator::BoxPhiT, b),
+				exp(TelOperator::DiamondNegPhiT, a),
+			]),
+			AllenRelation::StartedBy => (b, vec![
+				exp(TelOperator::BoxTPhi, a),
+				exp(TelOperator::BoxTPhi, b),
+				exp(TelOperator::BoxPhiT, a),
+				exp(TelOperator::DiamondNegPhiT, b),
+			]),
+			AllenRelation::Contains => (b, vec![
+				exp(TelOperator::BoxTPhi, a),
+				exp(TelOperator::DiamondTNegPhi, b),
+				exp(TelOperator::BoxPhiT, a),
+				exp(TelOperator::DiamondNegPhiT, b),
+			]),
+			AllenRelation::FinishedBy => (b, vec![
+				exp(TelOperator::BoxTPhi, a),
+				exp(TelOperator::DiamondTNegPhi, b),
+				exp(TelOperator::BoxPhiT, a),
+				exp(TelOperator::BoxPhiT, b),
+			]),
+			AllenRelation::Equals => (a, vec![
+				exp(TelOperator::BoxTPhi, a),
+				exp(TelOperator::BoxTPhi, b),
+				exp(TelOperator::BoxPhiT, a),
+				exp(TelOperator::BoxPhiT, b),
+			]),
+			AllenRelation::After
+			| AllenRelation::MetBy
+			| AllenRelation::OverlappedBy
+			| AllenRelation::Starts
+			| AllenRelation::During
+			| AllenRelation::Finishes => self.inverse().encode(t, b, a),
+		}
+	}
+}
+
+// TEL encoding of a pattern of pairwise relations "a <relation> b" between event groups;
+// relation i gets its own time variable t<i>, returned with the event group it ranges over
+pub fn encode_pattern<'a>(relations: &[(&'a str, AllenRelation, &'a str)]) -> (Vec<(String, &'a str)>, Vec<TelExp>) {
+	let mut ts = Vec::new();
+	let mut exps = Vec::new();
+	for (i, (a, relation, b)) in relations.iter().enumerate() {
+		let t = format!("t{}", i + 1);
+		let (group, relation_exps) = relation.encode(&t, a, b);
+		ts.push((t, group));
+		exps.extend(relation_exps);
+	}
+	(ts, exps)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchor {
+	Start,
+	End,
+}
+
+impl Anchor {
+	pub fn parse(name: &str) -> Option<Self> {
+		match name.to_lowercase().as_str() {
+			"start" => Some(Anchor::Start),
+			"end" => Some(Anchor::End),
+			_ => None,
+		}
+	}
+}
+
+// bound on a gap in milliseconds; an open bound excludes the value itself
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GapBound {
+	pub value: f64,
+	pub open: bool,
+}
+
+// metric constraint "the `to` anchor of b lies between min and max after the `from` anchor of a",
+// e.g. b starts between 5 s and 10 min after a ends
+#[derive(Clone, Copy, Debug)]
+pub struct GapConstraint<'a> {
+	pub a: &'a str,
+	pub from: Anchor,
+	pub b: &'a str,
+	pub to: Anchor,
+	pub min: Option<GapBound>,
+	pub max: Option<GapBound>,
+}
+
+impl<'a> GapConstraint<'a> {
+	// TEL encoding with time variable t pinned to the anchor of a and u pinned to the anchor of b;
+	// returns both bindings and the conjunction of expressions.
+	// Only one of the open/closed forms of each bound has an exact encoding, so the other one
+	// is shifted by a millisecond, the resolution of the timeline dates.
+	pub fn encode(&self, t: &'a str, u: &'a str) -> (Vec<(&'a str, &'a str)>, Vec<TelExp>) {
+		let exp = |operator: TelOperator, t: &str, event: &str, delta: f64| TelExp::init(operator, t, event, Some(vec![event]), Some(delta), None, None);
+		let pin = |t: &str, event: &str, anchor: Anchor| match anchor {
+			Anchor::Start => exp(TelOperator::BoxTNegPhi, t, event, 0.0),
+			Anchor::End => exp(TelOperator::BoxNegPhiT, t, event, 0.0),
+		};
+		let mut exps = vec![pin(t, self.a, self.from), pin(u, self.b, self.to)];
+		// gap >= min: the anchor of a lies at or before u - min
+		if let Some(min) = self.min {
+			exps.push(match self.from {
+				Anchor::Start => exp(TelOperator::BoxPhiT, u, self.a, -(min.value + if min.open { 1.0 } else { 0.0 })),
+				Anchor::End => exp(TelOperator::DiamondTNegPhi, u, self.a, -(min.value - if min.open { 0.0 } else { 1.0 })),
+			});
+		}
+		// gap <= max: the anchor of b lies at or before t + max
+		if let Some(max) = self.max {
+			exps.push(match self.to {
+				Anchor::Start => exp(TelOperator::BoxPhiT, t, self.b, max.value - if max.open { 1.0 } else { 0.0 }),
+				Anchor::End => exp(TelOperator::DiamondTNegPhi, t, self.b, max.value + if max.open { 0.0 } else { 1.0 }),
+			});
+		}
+		(vec![(t, self.a), (u, self.b)], exps)
+	}
+}
+
+impl fmt::Display for AllenRelation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tel::eval::{evaluate, Interval, Timelines};
+	use maplit::hashmap;
+	use std::collections::HashMap;
+
+	// evaluate the expressions on a single subject with e1 at a and e2 at b
+	fn pair_holds(ts: HashMap<&str, &str>, exps: &[TelExp], a: Interval, b: Interval) -> bool {
+		let timelines: Timelines = hashmap!{ "p".to_string() => hashmap!{ 1 => vec![a], 2 => vec![b] } };
+		let events = hashmap!{ "e1" => vec![1], "e2" => vec![2] };
+		!evaluate(&timelines, &events, &ts, exps).unwrap().is_empty()
+	}
+
+	fn relation_holds(relation: AllenRelation, a: Interval, b: Interval) -> bool {
+		let (group, exps) = relation.encode("t", "e1", "e2");
+		pair_holds(hashmap!{ "t" => group }, &exps, a, b)
+	}
+
+	// one representative pair of intervals per relation
+	const TABLE: [(AllenRelation, Interval, Interval); 13] = [
+		(AllenRelation::Before, (0, 1), (2, 3)),
+		(AllenRelation::After, (2, 3), (0, 1)),
+		(AllenRelation::Meets, (0, 1), (1, 2)),
+		(AllenRelation::MetBy, (1, 2), (0, 1)),
+		(AllenRelation::Overlaps, (0, 2), (1, 3)),
+		(AllenRelation::OverlappedBy, (1, 3), (0, 2)),
+		(AllenRelation::Starts, (0, 1), (0, 2)),
+		(AllenRelation::StartedBy, (0, 2), (0, 1)),
+		(AllenRelation::During, (1, 2), (0, 3)),
+		(AllenRelation::Contains, (0, 3), (1, 2)),
+		(AllenRelation::Finishes, (1, 2), (0, 2)),
+		(AllenRelation::FinishedBy, (0, 2), (1, 2)),
+		(AllenRelation::Equals, (0, 1), (0, 1)),
+	];
+
+	#[test]
+	fn table_relations_hold_exclusively() {
+		for (expected, a, b) in TABLE {
+			for relation in AllenRelation::ALL {
+				assert_eq!(relation_holds(relation, a, b), relation == expected, "{} on {:?} {:?}", relation, a, b);
+			}
+		}
+	}
+
+	#[test]
+	fn relations_are_disjoint_and_exhaustive() {
+		// endpoints in 0..6 cover every ordering of the four endpoints
+		for a1 in 0..6 {
+			for a2 in a1 + 1..6 {
+				for b1 in 0..6 {
+					for b2 in b1 + 1..6 {
+						let holding: Vec<AllenRelation> = AllenRelation::ALL.iter().copied()
+							.filter(|&r| relation_holds(r, (a1, a2), (b1, b2)))
+							.collect();
+						assert_eq!(holding.len(), 1, "{:?} on {:?} {:?}", holding, (a1, a2), (b1, b2));
+					}
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn gap_constraint_bounds_are_exact() {
+		// millisecond endpoints, including zero-length events
+		let intervals: Vec<Interval> = (0..6).flat_map(|s| (s..6).map(move |e| (s, e))).collect();
+		let bounds: [Option<i32>; 5] = [None, Some(-2), Some(0), Some(1), Some(3)];
+		let anchor = |x: Interval, anchor: Anchor| if anchor == Anchor::Start { x.0 } else { x.1 };
+		for from in [Anchor::Start, Anchor::End] {
+			for to in [Anchor::Start, Anchor::End] {
+				for (min, max, open) in bounds.iter().flat_map(|&min| bounds.iter().flat_map(move |&max| [(min, max, false), (min, max, true)])) {
+					let constraint = GapConstraint {
+						a: "e1", from, b: "e2", to,
+						min: min.map(|value| GapBound { value: f64::from(value), open }),
+						max: max.map(|value| GapBound { value: f64::from(value), open: !open }),
+					};
+					let (bindings, exps) = constraint.encode("t", "u");
+					for &a in &intervals {
+						for &b in &intervals {
+							let gap = anchor(b, to) - anchor(a, from);
+							let (min, max) = (min.map(i64::from), max.map(i64::from));
+							let expected = min.is_none_or(|m| if open { gap > m } else { gap >= m })
+								&& max.is_none_or(|m| if open { gap <= m } else { gap < m });
+							let holds = pair_holds(bindings.iter().copied().collect(), &exps, a, b);
+							assert_eq!(holds, expected, "{:?} on {:?} {:?}", constraint, a, b);
+						}
+					}
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn parse_accepts_names_and_legacy_aliases() {
+		for relation in AllenRelation::ALL {
+			assert_eq!(AllenRelation::parse(relation.name()), Some(relation));
+			assert_eq!(relation.inverse().inverse(), relation);
+		}
+		assert_eq!(AllenRelation::parse("met_by"), Some(AllenRelation::MetBy));
+		assert_eq!(AllenRelation::parse("start"), Some(AllenRelation::StartedBy));
+		assert_eq!(AllenRelation::parse("end"), Some(AllenRelation::FinishedBy));
+		assert_eq!(AllenRelation::parse("sometime"), None);
+		// the names eeg_allen_query took before, served by the relations of the table above
+		for name in ["before", "after", "overlap", "contain", "start", "end", "meet", "equal"] {
+			assert!(AllenRelation::parse(name).is_some_and(|r| AllenRelation::ALL.contains(&r)), "{}", name);
+		}
+	}
+
+	#[test]
+	fn before_within_a_gap() {
+		// the former "before" of eeg_allen_query, b starting at most 60 s after a ends, as a gap query
+		let gap = GapConstraint { a: "e1", from: Anchor::End, b: "e2", to: Anchor::Start, min: None, max: Some(GapBound { value: 60.0 * 1000.0, open: false }) };
+		let holds = |a: Interval, b: Interval| {
+			let (bindings, mut exps) = gap.encode("t", "u");
+			let (group, before) = AllenRelation::Before.encode("r", "e1", "e2");
+			exps.extend(before);
+			let mut ts: HashMap<&str, &str> = bindings.into_iter().collect();
+			ts.insert("r", group);
+			pair_holds(ts, &exps, a, b)
+		};
+		let (a, near, far) = ((0, 1000), (30_000, 40_000), (120_000, 130_000));
+		assert!(holds(a, near));
+		assert!(!holds(a, far));
+		assert!(relation_holds(AllenRelation::Before, a, far));
+		assert!(relation_holds(AllenRelation::After, far, a));
+	}
+}
@@ -0,0 +1,82 @@
+use roaring::RoaringBitmap;
+use serde::Serialize;
+
+// Co-occurrence of events over their postings: cell (i, j) of a matrix is of the subjects with
+// both event i and event j, the diagonal of those with event i. Lift and odds ratio compare a
+// pair to its events being independent over all subjects, which only a subject dictionary knows.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Cooccurrence {
+	pub events: Vec<i32>,
+	// the subjects of the dataset, none without a subject dictionary
+	pub subjects: Option<u64>,
+	pub counts: Vec<Vec<u64>>,
+	pub lift: Vec<Vec<Option<f64>>>,
+	pub jaccard: Vec<Vec<Option<f64>>>,
+	// none where a pair has no subjects with only one of its events, or none with neither
+	pub odds_ratio: Vec<Vec<Option<f64>>>,
+}
+
+impl Cooccurrence {
+	// postings in the order of the events
+	pub fn new(events: Vec<i32>, postings: &[RoaringBitmap], subjects: Option<u64>) -> Self {
+		let counts: Vec<Vec<u64>> = postings.iter()
+			.map(|a| postings.iter().map(|b| a.intersection_len(b)).collect())
+			.collect();
+		let matrix = |cell: &dyn Fn(f64, f64, f64) -> Option<f64>| -> Vec<Vec<Option<f64>>> {
+			(0..events.len()).map(|i| (0..events.len()).map(|j| {
+				cell(counts[i][j] as f64, counts[i][i] as f64, counts[j][j] as f64)
+			}).collect()).collect()
+		};
+		let total = subjects.map(|n| n as f64);
+		let lift = matrix(&|both, a, b| ratio(both * total?, a * b));
+		let jaccard = matrix(&|both, a, b| ratio(both, a + b - both));
+		let odds_ratio = matrix(&|both, a, b| {
+			let neither = total? - a - b + both;
+			ratio(both * neither, (a - both) * (b - both))
+		});
+		Cooccurrence { events, subjects, counts, lift, jaccard, odds_ratio }
+	}
+
+	// a row per cell: event1,event2,count,lift,jaccard,odds_ratio, empty where undefined
+	pub fn to_csv(&self) -> String {
+		let value = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+		let mut csv = String::from("event1,event2,count,lift,jaccard,odds_ratio\n");
+		for (i, event1) in self.events.iter().enumerate() {
+			for (j, event2) in self.events.iter().enumerate() {
+				csv.push_str(&format!("{},{},{},{},{},{}\n", event1, event2, self.counts[i][j],
+					value(self.lift[i][j]), value(self.jaccard[i][j]), value(self.odds_ratio[i][j])));
+			}
+		}
+		csv
+	}
+}
+
+fn ratio(numerator: f64, denominator: f64) -> Option<f64> {
+	if denominator == 0.0 {
+		return None;
+	}
+	Some(numerator / denominator)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn counts_pairs_of_events() {
+		// subjects 0..10
+		let postings = [RoaringBitmap::from_iter([0, 1, 2, 3]), RoaringBitmap::from_iter([2, 3, 4]), RoaringBitmap::new()];
+		let matrix = Cooccurrence::new(vec![250, 300, 10], &postings, Some(10));
+
+		assert_eq!(matrix.counts, vec![vec![4, 2, 0], vec![2, 3, 0], vec![0, 0, 0]]);
+		assert_eq!(matrix.lift[0][1], Some(2.0 * 10.0 / 12.0));
+		assert_eq!(matrix.jaccard[0][1], Some(2.0 / 5.0));
+		// 2 with both, 2 with only 250, 1 with only 300, 5 with neither
+		assert_eq!(matrix.odds_ratio[0][1], Some(5.0));
+		assert_eq!((matrix.jaccard[0][0], matrix.odds_ratio[0][0], matrix.jaccard[2][2]), (Some(1.0), None, None));
+
+		let without_total = Cooccurrence::new(vec![250, 300, 10], &postings, None);
+		assert_eq!((without_total.lift[0][1], without_total.odds_ratio[0][1]), (None, None));
+		assert_eq!(without_total.to_csv().lines().nth(2), Some("250,300,2,,0.4,"));
+	}
+}
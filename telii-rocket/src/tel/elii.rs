@@ -0,0 +1,122 @@
+use std::collections::{BTreeSet, HashMap};
+
+use roaring::RoaringBitmap;
+
+// Boolean expression over events for elii, see parser::parse_elii, evaluated with set operations
+// on the postings of its events: a subject matches an event if it has an occurrence of it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EliiExpr {
+	Event(i32),
+	// a named event group, any of its events
+	Group(String),
+	And(Vec<EliiExpr>),
+	Or(Vec<EliiExpr>),
+	Not(Box<EliiExpr>),
+	// at least k of the expressions
+	AtLeast(usize, Vec<EliiExpr>),
+}
+
+impl EliiExpr {
+	// the expression with its groups replaced by any of their events
+	pub fn resolve(self, groups: &HashMap<&str, Vec<i32>>) -> Result<EliiExpr, String> {
+		let all = |exprs: Vec<EliiExpr>| exprs.into_iter().map(|e| e.resolve(groups)).collect::<Result<Vec<_>, _>>();
+		Ok(match self {
+			EliiExpr::Event(id) => EliiExpr::Event(id),
+			EliiExpr::Group(name) => match groups.get(name.as_str()) {
+				Some(ids) => EliiExpr::Or(ids.iter().map(|id| EliiExpr::Event(*id)).collect()),
+				None => return Err(format!("unknown event group '{}'", name)),
+			},
+			EliiExpr::And(exprs) => EliiExpr::And(all(exprs)?),
+			EliiExpr::Or(exprs) => EliiExpr::Or(all(exprs)?),
+			EliiExpr::Not(expr) => EliiExpr::Not(Box::new(expr.resolve(groups)?)),
+			EliiExpr::AtLeast(k, exprs) => EliiExpr::AtLeast(k, all(exprs)?),
+		})
+	}
+
+	// event ids the expression refers to
+	pub fn events(&self) -> BTreeSet<i32> {
+		match self {
+			EliiExpr::Event(id) => BTreeSet::from([*id]),
+			EliiExpr::Group(_) => BTreeSet::new(),
+			EliiExpr::And(exprs) | EliiExpr::Or(exprs) | EliiExpr::AtLeast(_, exprs) => exprs.iter().flat_map(|e| e.events()).collect(),
+			EliiExpr::Not(expr) => expr.events(),
+		}
+	}
+
+	// whether the expression holds for a subject with none of its events, as NOT 300 does; such a
+	// subject is only found with the whole population to negate against
+	pub fn matches_without_events(&self) -> bool {
+		match self {
+			EliiExpr::Event(_) | EliiExpr::Group(_) => false,
+			EliiExpr::And(exprs) => exprs.iter().all(|e| e.matches_without_events()),
+			EliiExpr::Or(exprs) => exprs.iter().any(|e| e.matches_without_events()),
+			EliiExpr::Not(expr) => !expr.matches_without_events(),
+			EliiExpr::AtLeast(k, exprs) => exprs.iter().filter(|e| e.matches_without_events()).count() >= *k,
+		}
+	}
+
+	// subjects matching the expression, from the postings of its events; NOT complements within
+	// the universe
+	pub fn eval(&self, postings: &HashMap<i32, RoaringBitmap>, universe: &RoaringBitmap) -> RoaringBitmap {
+		match self {
+			EliiExpr::Event(id) => postings.get(id).map(|bitmap| bitmap & universe).unwrap_or_default(),
+			// groups are resolved before evaluation
+			EliiExpr::Group(_) => RoaringBitmap::new(),
+			EliiExpr::And(exprs) => {
+				let mut subjects = universe.clone();
+				for expr in exprs {
+					subjects &= expr.eval(postings, universe);
+				}
+				subjects
+			}
+			EliiExpr::Or(exprs) => exprs.iter().map(|e| e.eval(postings, universe)).fold(RoaringBitmap::new(), |a, b| a | b),
+			EliiExpr::Not(expr) => universe - expr.eval(postings, universe),
+			EliiExpr::AtLeast(k, exprs) => {
+				// at_least[j]: subjects matching at least j of the expressions seen so far
+				let mut at_least = vec![RoaringBitmap::new(); k + 1];
+				at_least[0] = universe.clone();
+				for subjects in exprs.iter().map(|e| e.eval(postings, universe)) {
+					for j in (1..=*k).rev() {
+						let more = &at_least[j - 1] & &subjects;
+						at_least[j] |= more;
+					}
+				}
+				at_least.swap_remove(*k)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tel::parser::parse_elii;
+	use maplit::hashmap;
+
+	#[test]
+	fn evaluates_expressions_on_postings() {
+		// subjects 0..6
+		let postings = hashmap!{
+			250 => RoaringBitmap::from_iter([0, 1, 2]),
+			251 => RoaringBitmap::from_iter([3]),
+			300 => RoaringBitmap::from_iter([1]),
+			10 => RoaringBitmap::from_iter([0, 1, 3, 4]),
+			11 => RoaringBitmap::from_iter([0, 3]),
+			12 => RoaringBitmap::from_iter([1, 4, 5]),
+		};
+		let universe = RoaringBitmap::from_iter(0..6);
+		let eval = |input: &str| parse_elii(input).unwrap().eval(&postings, &universe).iter().collect::<Vec<_>>();
+
+		assert_eq!(eval("(250 OR 251) AND NOT 300 AND atleast(2, [10,11,12])"), vec![0, 3]);
+		assert_eq!(eval("[250, 251] and not 300"), vec![0, 2, 3]);
+		assert_eq!(eval("atleast(3, [10, 11, 12])"), Vec::<u32>::new());
+		assert_eq!(eval("NOT 10"), vec![2, 5]);
+
+		let expr = parse_elii("g AND NOT 300").unwrap();
+		assert!(!expr.matches_without_events() && parse_elii("not 300 or 10").unwrap().matches_without_events());
+		let expr = expr.resolve(&HashMap::from([("g", vec![250, 251])])).unwrap();
+		assert_eq!(expr.events(), BTreeSet::from([250, 251, 300]));
+		assert!(parse_elii("g").unwrap().resolve(&HashMap::new()).is_err());
+		assert_eq!(parse_elii("250 AND (251").unwrap_err().pos, 12);
+	}
+}
@@ -0,0 +1,283 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::tel::exp::{TelError, TelExp, TelOperator};
+use crate::tel::query::{Absence, TelFormula, TelQuery};
+
+// occurrence of an event as (start, end) in milliseconds
+pub type Interval = (i64, i64);
+// event id -> occurrences, the timeline of one subject
+pub type Timeline = HashMap<i32, Vec<Interval>>;
+// subjectid -> timeline
+pub type Timelines = HashMap<String, Timeline>;
+
+// one satisfying choice of intervals, the same fields as the final $group of construct_query
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TelMatch {
+	pub subjectid: String,
+	pub intervals: BTreeMap<String, Interval>,
+}
+
+// In-process counterpart of construct_query: every subject with an interval in each event group,
+// every combination of one interval per group and every binding of the time variables to an
+// endpoint of their group's interval is checked against the conjunction of expressions.
+pub fn evaluate(timelines: &Timelines, events: &HashMap<&str, Vec<i32>>, ts: &HashMap<&str, &str>, exps: &[TelExp]) -> Result<Vec<TelMatch>, TelError> {
+	for exp in exps {
+		exp.validate(events, ts)?;
+	}
+	for group in ts.values() {
+		if !events.contains_key(group) {
+			return Err(TelError::UnknownEvent(group.to_string()));
+		}
+	}
+	let mut groups: Vec<&str> = events.keys().copied().collect();
+	groups.sort();
+
+	let mut matches = BTreeSet::new();
+	for (subjectid, timeline) in timelines {
+		let occurrences: Vec<Vec<Interval>> = groups.iter().map(|group| group_intervals(timeline, &events[group])).collect();
+		if occurrences.iter().any(|o| o.is_empty()) {
+			continue;
+		}
+		// every choice is a match of its own, so the search never stops early
+		any_choice(&occurrences, &mut Vec::new(), &mut |choice| {
+			let intervals: HashMap<&str, Interval> = groups.iter().copied().zip(choice.iter().copied()).collect();
+			if holds(&intervals, ts, exps) {
+				matches.insert(TelMatch {
+					subjectid: subjectid.clone(),
+					intervals: intervals.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+				});
+			}
+			false
+		});
+	}
+	Ok(matches.into_iter().collect())
+}
+
+// In-process counterpart of construct_bool_query: the subjects for which the query tree holds,
+// a formula holding if some choice of intervals of its own groups satisfies it.
+pub fn evaluate_query(timelines: &Timelines, events: &HashMap<&str, Vec<i32>>, query: &TelQuery) -> Result<Vec<String>, TelError> {
+	for formula in query.formulas() {
+		let ts = formula.ts();
+		for exp in &formula.exps {
+			exp.validate(events, &ts)?;
+		}
+	}
+	for group in query.groups() {
+		if !events.contains_key(group) {
+			return Err(TelError::UnknownEvent(group.to_string()));
+		}
+	}
+	let ids: BTreeSet<i32> = events.values().flatten().copied().collect();
+	let subjects: BTreeSet<&String> = timelines.iter()
+		.filter(|(_, timeline)| timeline.keys().any(|id| ids.contains(id)))
+		.map(|(subjectid, _)| subjectid)
+		.collect();
+	Ok(subjects.into_iter()
+		.filter(|subjectid| query_holds(&timelines[*subjectid], events, query))
+		.cloned()
+		.collect())
+}
+
+fn query_holds(timeline: &Timeline, events: &HashMap<&str, Vec<i32>>, query: &TelQuery) -> bool {
+	match query {
+		TelQuery::Formula(formula) => formula_holds(timeline, events, formula),
+		TelQuery::Absence(absence) => absence_holds(timeline, events, absence),
+		TelQuery::And(queries) => queries.iter().all(|q| query_holds(timeline, events, q)),
+		TelQuery::Or(queries) => queries.iter().any(|q| query_holds(timeline, events, q)),
+		TelQuery::Not(query) => !query_holds(timeline, events, query),
+	}
+}
+
+fn formula_holds(timeline: &Timeline, events: &HashMap<&str, Vec<i32>>, formula: &TelFormula) -> bool {
+	let groups = formula.groups();
+	let occurrences: Vec<Vec<Interval>> = groups.iter().map(|group| group_intervals(timeline, &events[group])).collect();
+	let ts = formula.ts();
+	any_choice(&occurrences, &mut Vec::new(), &mut |choice| {
+		let intervals: HashMap<&str, Interval> = groups.iter().copied().zip(choice.iter().copied()).collect();
+		holds(&intervals, &ts, &formula.exps)
+	})
+}
+
+fn absence_holds(timeline: &Timeline, events: &HashMap<&str, Vec<i32>>, absence: &Absence) -> bool {
+	let followers = group_intervals(timeline, &events[absence.b.as_str()]);
+	group_intervals(timeline, &events[absence.a.as_str()]).iter().any(|&(min, max)| {
+		!followers.iter().any(|&(start, _)| absence.follows((min as f64, max as f64), start as f64))
+	})
+}
+
+// distinct occurrences of any event of a group, as collected by $addToSet
+fn group_intervals(timeline: &Timeline, event_ids: &[i32]) -> Vec<Interval> {
+	let intervals: BTreeSet<Interval> = event_ids.iter()
+		.filter_map(|id| timeline.get(id))
+		.flatten()
+		.copied()
+		.collect();
+	intervals.into_iter().collect()
+}
+
+// true if some choice of one element per list, extending the chosen prefix, satisfies f;
+// choices are visited depth first without building the product and the search stops at the first
+fn any_choice<T: Copy>(lists: &[Vec<T>], chosen: &mut Vec<T>, f: &mut dyn FnMut(&[T]) -> bool) -> bool {
+	let Some(list) = lists.get(chosen.len()) else {
+		return f(chosen);
+	};
+	for x in list {
+		chosen.push(*x);
+		let found = any_choice(lists, chosen, f);
+		chosen.pop();
+		if found {
+			return true;
+		}
+	}
+	false
+}
+
+// true if some binding of the time variables to endpoints satisfies all expressions
+fn holds(intervals: &HashMap<&str, Interval>, ts: &HashMap<&str, &str>, exps: &[TelExp]) -> bool {
+	let vars: Vec<&str> = ts.keys().copied().collect();
+	// a variable over a group without an interval has no endpoint to bind
+	let endpoints: Vec<Vec<i64>> = vars.iter()
+		.map(|t| intervals.get(ts[t]).map_or(vec![], |&(start, end)| vec![start, end]))
+		.collect();
+	any_choice(&endpoints, &mut Vec::new(), &mut |binding| {
+		let binding: HashMap<&str, i64> = vars.iter().copied().zip(binding.iter().copied()).collect();
+		exps.iter().all(|exp| exp_holds(exp, &binding, intervals))
+	})
+}
+
+// the comparisons of the $expr built by construct_tel_cond for one expression;
+// an expression with a reference unbound by binding or intervals does not hold
+pub fn exp_holds(exp: &TelExp, binding: &HashMap<&str, i64>, intervals: &HashMap<&str, Interval>) -> bool {
+	let span = || exp.events.iter().map(|x| intervals.get(x.as_str()).copied()).collect::<Option<Vec<Interval>>>();
+	let Some(t) = binding.get(exp.t.as_str()) else {
+		return false;
+	};
+	let s = match &exp.s {
+		Some(s) => binding.get(s.as_str()).copied(),
+		None => span().map(|span| span.iter().map(|x| x.0).min().unwrap_or(i64::MAX)),
+	};
+	let e = match &exp.e {
+		Some(e) => binding.get(e.as_str()).copied(),
+		None => span().map(|span| span.iter().map(|x| x.1).max().unwrap_or(i64::MIN)),
+	};
+	let (Some(s), Some(e), Some(&(min, max))) = (s, e, intervals.get(exp.event.as_str())) else {
+		return false;
+	};
+	let t = *t as f64;
+	let td = t + exp.delta;
+	let (s, e) = (s as f64, e as f64);
+	let (min, max) = (min as f64, max as f64);
+	match exp.operator {
+		TelOperator::BoxTPhi => s >= min && td <= max,
+		TelOperator::BoxTNegPhi => min >= td || max <= s,
+		TelOperator::BoxPhiT => td >= min && max >= e,
+		TelOperator::BoxNegPhiT => min >= e || max <= td,
+		TelOperator::DiamondTPhi => td > min && max > s,
+		TelOperator::DiamondTNegPhi => min > s || max < td,
+		TelOperator::DiamondPhiT => e > min && max > td,
+		TelOperator::DiamondNegPhiT => e > t && (min > td || max < e),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tel::allen::AllenRelation;
+	use crate::tel::parser::parse_query;
+	use maplit::hashmap;
+
+	#[test]
+	fn evaluates_groups_of_events_per_subject() {
+		let timelines: Timelines = hashmap!{
+			"p1".to_string() => hashmap!{ 53 => vec![(0, 10)], 941 => vec![(20, 30)] },
+			"p2".to_string() => hashmap!{ 53 => vec![(0, 10)], 79 => vec![(40, 50)], 941 => vec![(5, 30)] },
+			"p3".to_string() => hashmap!{ 53 => vec![(0, 10)] },
+		};
+		let events = hashmap!{ "e1" => vec![53, 79], "e2" => vec![941] };
+		let (group, exps) = AllenRelation::Before.encode("t", "e1", "e2");
+		let ts = hashmap!{ "t" => group };
+
+		let matches = evaluate(&timelines, &events, &ts, &exps).unwrap();
+		let subjects: Vec<&str> = matches.iter().map(|m| m.subjectid.as_str()).collect();
+		assert_eq!(subjects, vec!["p1"]);
+		assert_eq!(matches[0].intervals["e1"], (0, 10));
+
+		let (group, exps) = AllenRelation::After.encode("t", "e1", "e2");
+		let ts = hashmap!{ "t" => group };
+		let matches = evaluate(&timelines, &events, &ts, &exps).unwrap();
+		assert_eq!(matches.len(), 1);
+		assert_eq!(matches[0].intervals["e1"], (40, 50));
+	}
+
+	#[test]
+	fn evaluates_boolean_queries_per_subject() {
+		let timelines: Timelines = hashmap!{
+			"p1".to_string() => hashmap!{ 53 => vec![(0, 10)], 941 => vec![(20, 30)] },
+			"p2".to_string() => hashmap!{ 53 => vec![(0, 10)], 941 => vec![(20, 30)], 300 => vec![(5, 15)] },
+			"p3".to_string() => hashmap!{ 53 => vec![(0, 10)], 300 => vec![(12, 15)] },
+			"p4".to_string() => hashmap!{ 941 => vec![(0, 10)] },
+		};
+		let events = hashmap!{ "e1" => vec![53], "e2" => vec![941], "e3" => vec![300] };
+		let subjects = |query: &str| evaluate_query(&timelines, &events, &parse_query(query).unwrap()).unwrap();
+
+		assert_eq!(subjects("e1 before e2 and not e1 overlaps e3"), vec!["p1"]);
+		assert_eq!(subjects("e1 before e2 or e1 before e3"), vec!["p1", "p2", "p3"]);
+		assert_eq!(subjects("not (e1 before e2)"), vec!["p3", "p4"]);
+		// a formula needs an interval in each of its groups, p1 has no e3
+		assert_eq!(subjects("{exists t in e1: box[t] e1 and box[t] not e3} and not e3 met-by e1"), vec!["p2", "p3"]);
+	}
+
+	#[test]
+	fn evaluates_absence_within_a_window() {
+		let day = 24 * 60 * 60 * 1000;
+		let timelines: Timelines = hashmap!{
+			"p1".to_string() => hashmap!{ 53 => vec![(0, 0)] },
+			"p2".to_string() => hashmap!{ 53 => vec![(0, 0)], 941 => vec![(30 * day, 30 * day)] },
+			"p3".to_string() => hashmap!{ 53 => vec![(0, 0)], 941 => vec![(120 * day, 120 * day)] },
+			"p4".to_string() => hashmap!{ 53 => vec![(0, 0), (200 * day, 200 * day)], 941 => vec![(30 * day, 30 * day)] },
+			"p5".to_string() => hashmap!{ 941 => vec![(0, 0)] },
+		};
+		let events = hashmap!{ "e1" => vec![53], "e2" => vec![941] };
+		let subjects = |query: &str| evaluate_query(&timelines, &events, &parse_query(query).unwrap()).unwrap();
+
+		assert_eq!(subjects("e1 without e2"), vec!["p1", "p4"]);
+		assert_eq!(subjects("e1 without e2 within 90d"), vec!["p1", "p3", "p4"]);
+		assert_eq!(subjects("e1 without e2 within 90d after start and not e1 before e2"), vec!["p1"]);
+	}
+
+	#[test]
+	fn stops_at_the_first_satisfying_choice() {
+		let lists = vec![vec![1, 2, 3], vec![10, 20, 30], vec![100, 200]];
+		let mut visited = Vec::new();
+		assert!(any_choice(&lists, &mut Vec::new(), &mut |choice| {
+			visited.push(choice.to_vec());
+			choice == [1, 20, 200]
+		}));
+		assert_eq!(visited, vec![vec![1, 10, 100], vec![1, 10, 200], vec![1, 20, 100], vec![1, 20, 200]]);
+
+		let mut count = 0;
+		assert!(!any_choice(&lists, &mut Vec::new(), &mut |_| { count += 1; false }));
+		assert_eq!(count, 18);
+		assert!(!any_choice(&[vec![1], vec![]], &mut Vec::new(), &mut |_| true));
+	}
+
+	#[test]
+	fn unbound_references_do_not_hold() {
+		let intervals = hashmap!{ "e1" => (0, 10), "e2" => (20, 30) };
+		let binding = hashmap!{ "t" => 10 };
+		let exp = |t: &str, event: &str, events: Vec<&str>, s: Option<&str>| TelExp::init(TelOperator::BoxTNegPhi, t, event, Some(events), None, s, None);
+		assert!(exp_holds(&exp("t", "e2", vec!["e1", "e2"], None), &binding, &intervals));
+		assert!(!exp_holds(&exp("u", "e2", vec!["e1", "e2"], None), &binding, &intervals));
+		assert!(!exp_holds(&exp("t", "e3", vec!["e1", "e2"], None), &binding, &intervals));
+		assert!(!exp_holds(&exp("t", "e2", vec!["e1", "e3"], None), &binding, &intervals));
+		assert!(!exp_holds(&exp("t", "e2", vec![], Some("u")), &binding, &intervals));
+	}
+
+	#[test]
+	fn rejects_unbound_references() {
+		let events = hashmap!{ "e1" => vec![53] };
+		let (group, exps) = AllenRelation::Before.encode("t", "e1", "e2");
+		let ts = hashmap!{ "t" => group };
+		assert_eq!(evaluate(&Timelines::new(), &events, &ts, &exps), Err(TelError::UnknownEvent("e2".to_string())));
+	}
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fmt;
+
+// Temporal operators of TEL.
+// `*TPhi` operators range over the span from the start `s` up to `t`,
+// `*PhiT` operators over the span from `t` up to the end `e`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TelOperator {
+	BoxTPhi,
+	BoxTNegPhi,
+	BoxPhiT,
+	BoxNegPhiT,
+	DiamondTPhi,
+	DiamondTNegPhi,
+	DiamondPhiT,
+	DiamondNegPhiT,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Modality {
+	Box,
+	Diamond,
+}
+
+impl TelOperator {
+	pub fn modality(&self) -> Modality {
+		match self {
+			TelOperator::BoxTPhi | TelOperator::BoxTNegPhi | TelOperator::BoxPhiT | TelOperator::BoxNegPhiT => Modality::Box,
+			_ => Modality::Diamond,
+		}
+	}
+	pub fn is_negated(&self) -> bool {
+		matches!(self, TelOperator::BoxTNegPhi | TelOperator::BoxNegPhiT | TelOperator::DiamondTNegPhi | TelOperator::DiamondNegPhiT)
+	}
+	// true if the operator ranges over [s, t], false if over [t, e]
+	pub fn is_before_t(&self) -> bool {
+		matches!(self, TelOperator::BoxTPhi | TelOperator::BoxTNegPhi | TelOperator::DiamondTPhi | TelOperator::DiamondTNegPhi)
+	}
+	pub fn name(&self) -> &'static str {
+		match self {
+			TelOperator::BoxTPhi => "box_t_phi",
+			TelOperator::BoxTNegPhi => "box_t_neg_phi",
+			TelOperator::BoxPhiT => "box_phi_t",
+			TelOperator::BoxNegPhiT => "box_neg_phi_t",
+			TelOperator::DiamondTPhi => "diamond_t_phi",
+			TelOperator::DiamondTNegPhi => "diamond_t_neg_phi",
+			TelOperator::DiamondPhiT => "diamond_phi_t",
+			TelOperator::DiamondNegPhiT => "diamond_neg_phi_t",
+		}
+	}
+}
+
+impl fmt::Display for TelOperator {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+// name of an event group, e.g. "e1"; resolved to the min_/max_ fields of the pipeline
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EventRef(String);
+
+// name of a time variable, e.g. "t"; bound to the endpoints of an event group
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TimeRef(String);
+
+impl EventRef {
+	pub fn new(name: &str) -> Self {
+		EventRef(name.to_string())
+	}
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+impl TimeRef {
+	pub fn new(name: &str) -> Self {
+		TimeRef(name.to_string())
+	}
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+impl fmt::Display for EventRef {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+impl fmt::Display for TimeRef {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TelError {
+	UnknownEvent(String),
+	UnknownTime(String),
+	EmptySpan(String),
+}
+
+impl fmt::Display for TelError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			TelError::UnknownEvent(name) => write!(f, "unknown event group: {}", name),
+			TelError::UnknownTime(name) => write!(f, "unknown time variable: {}", name),
+			TelError::EmptySpan(op) => write!(f, "{} has neither a span bound nor events to derive it from", op),
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TelExp {
+	pub operator: TelOperator,
+	pub t: TimeRef,
+	pub event: EventRef,
+	pub events: Vec<EventRef>,
+	pub delta: f64,
+	pub s: Option<TimeRef>,
+	pub e: Option<TimeRef>,
+}
+
+impl TelExp {
+	// Constructor
+	pub fn init(operator: TelOperator, t: &str, event: &str, events: Option<Vec<&str>>, delta: Option<f64>, s: Option<&str>, e: Option<&str> ) -> Self {
+		TelExp {
+			operator,
+			t: TimeRef::new(t),
+			event: EventRef::new(event),
+			events: events.unwrap_or_default().into_iter().map(EventRef::new).collect(),
+			delta: delta.unwrap_or(0.0),
+			s: s.map(TimeRef::new),
+			e: e.map(TimeRef::new),
+		}
+	}
+
+	// check that every reference of the expression is bound by the query
+	pub fn validate(&self, events: &HashMap<&str, Vec<i32>>, ts: &HashMap<&str, &str>) -> Result<(), TelError> {
+		if !ts.contains_key(self.t.as_str()) {
+			return Err(TelError::UnknownTime(self.t.to_string()));
+		}
+		for event in std::iter::once(&self.event).chain(self.events.iter()) {
+			if !events.contains_key(event.as_str()) {
+				return Err(TelError::UnknownEvent(event.to_string()));
+			}
+		}
+		let bound = if self.operator.is_before_t() { &self.s } else { &self.e };
+		match bound {
+			Some(time) if !ts.contains_key(time.as_str()) => Err(TelError::UnknownTime(time.to_string())),
+			None if self.events.is_empty() => Err(TelError::EmptySpan(self.operator.to_string())),
+			_ => Ok(()),
+		}
+	}
+
+	pub fn latex(&self) -> String {
+		let op_str = match self.operator.modality() {
+			Modality::Box => " \\Box ",
+			Modality::Diamond => " \\Diamond ",
+		};
+		let t_str = if self.delta != 0.0 {
+			format!("{}+{}", self.t, self.delta)
+		} else {
+			self.t.to_string()
+		};
+		let neg_str = if self.operator.is_negated() { "\\neg " } else { "" };
+		if self.operator.is_before_t() {
+			format!("{}_{{{}}} {}{}", op_str, t_str, neg_str, self.event)
+		} else {
+			format!("({} {}{})_{{{}}}", op_str, neg_str, self.event, t_str)
+		}
+	}
+}
+
+impl fmt::Display for TelExp {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let events: Vec<&str> = self.events.iter().map(|x| x.as_str()).collect();
+		write!(f, "operator: {}, t: {}, event: {}, events: {:?}, delta: {}, s: {:?}, e: {:?}", self.operator, self.t, self.event, events, self.delta, self.s, self.e)
+	}
+}
@@ -0,0 +1,7 @@
+pub mod allen;
+pub mod cooccurrence;
+pub mod elii;
+pub mod eval;
+pub mod exp;
+pub mod parser;
+pub mod query;
@@ -0,0 +1,598 @@
+use std::fmt;
+
+use crate::tel::allen::{AllenRelation, Anchor};
+use crate::tel::elii::EliiExpr;
+use crate::tel::exp::{EventRef, TelExp, TelOperator, TimeRef};
+use crate::tel::query::{Absence, TelFormula, TelQuery};
+
+// Textual TEL formulas, e.g.
+//   exists t in e1: box[t] e1 and box[t] not e2 and (diamond not e2)[t+60s]
+//
+// formula  := "exists" binding ("," binding)* ":" atom ("and" atom)*
+// binding  := IDENT "in" IDENT
+// atom     := modality "[" time "]" ["not"] IDENT            -- over [s, t]
+//           | "(" modality ["not"] IDENT ")" "[" time "]"    -- over [t, e]
+// modality := "box" | "diamond"
+// time     := IDENT [("+" | "-") NUMBER unit]
+// unit     := "ms" | "s" | "min" | "h" | "d"
+//
+// Boolean queries over formulas and Allen relations, e.g.
+//   e1 before e2 and not (e1 overlaps e3 or {exists t in e3: box[t] e3 and box[t] not e1})
+//
+// query    := conj ("or" conj)*
+// conj     := unary ("and" unary)*
+// unary    := "not" unary | "(" query ")" | "{" formula "}" | IDENT relation IDENT | absence
+// relation := IDENT ("-" IDENT)*
+// absence  := IDENT "without" IDENT ["within" NUMBER unit] ["after" ("start" | "end")]
+//
+// Boolean expressions over events for elii, keywords in any case, e.g.
+//   (250 OR 251) AND NOT 300 AND atleast(2, [10,11,12])
+//
+// elii     := econj ("or" econj)*
+// econj    := eunary ("and" eunary)*
+// eunary   := "not" eunary | "(" elii ")" | NUMBER | IDENT | list
+//           | "atleast" "(" NUMBER "," list ")"
+// list     := "[" elii ("," elii)* "]"                        -- any of them, or the k of atleast
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	Ident(String),
+	Number(f64),
+	LParen,
+	RParen,
+	LBracket,
+	RBracket,
+	LBrace,
+	RBrace,
+	Colon,
+	Comma,
+	Plus,
+	Minus,
+}
+
+impl fmt::Display for Token {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Token::Ident(name) => write!(f, "{}", name),
+			Token::Number(n) => write!(f, "{}", n),
+			Token::LParen => write!(f, "("),
+			Token::RParen => write!(f, ")"),
+			Token::LBracket => write!(f, "["),
+			Token::RBracket => write!(f, "]"),
+			Token::LBrace => write!(f, "{{"),
+			Token::RBrace => write!(f, "}}"),
+			Token::Colon => write!(f, ":"),
+			Token::Comma => write!(f, ","),
+			Token::Plus => write!(f, "+"),
+			Token::Minus => write!(f, "-"),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+	pub pos: usize,
+	pub message: String,
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} at position {}", self.message, self.pos)
+	}
+}
+
+// milliseconds per time unit
+fn unit_ms(unit: &str) -> Option<f64> {
+	match unit {
+		"ms" => Some(1.0),
+		"s" => Some(1000.0),
+		"min" => Some(60.0 * 1000.0),
+		"h" => Some(60.0 * 60.0 * 1000.0),
+		"d" => Some(24.0 * 60.0 * 60.0 * 1000.0),
+		_ => None,
+	}
+}
+
+// parse a duration such as "5s", "-10min" or "1.5h" into milliseconds
+pub fn parse_duration(text: &str) -> Option<f64> {
+	let text = text.trim();
+	let split = text.find(|c: char| c.is_alphabetic())?;
+	let n: f64 = text[..split].trim().parse().ok()?;
+	Some(n * unit_ms(text[split..].trim())?)
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+	let chars: Vec<char> = input.chars().collect();
+	let mut tokens = Vec::new();
+	let mut i = 0;
+	while i < chars.len() {
+		let c = chars[i];
+		let token = match c {
+			'(' => Token::LParen,
+			')' => Token::RParen,
+			'[' => Token::LBracket,
+			']' => Token::RBracket,
+			'{' => Token::LBrace,
+			'}' => Token::RBrace,
+			':' => Token::Colon,
+			',' => Token::Comma,
+			'+' => Token::Plus,
+			'-' => Token::Minus,
+			_ if c.is_whitespace() => {
+				i += 1;
+				continue;
+			}
+			_ if c.is_ascii_digit() => {
+				let start = i;
+				while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+					i += 1;
+				}
+				let text: String = chars[start..i].iter().collect();
+				let n = text.parse::<f64>().map_err(|_| ParseError { pos: start, message: format!("invalid number '{}'", text) })?;
+				tokens.push((start, Token::Number(n)));
+				continue;
+			}
+			_ if c.is_alphabetic() || c == '_' => {
+				let start = i;
+				while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+					i += 1;
+				}
+				tokens.push((start, Token::Ident(chars[start..i].iter().collect())));
+				continue;
+			}
+			_ => return Err(ParseError { pos: i, message: format!("unexpected character '{}'", c) }),
+		};
+		tokens.push((i, token));
+		i += 1;
+	}
+	Ok(tokens)
+}
+
+struct Parser {
+	tokens: Vec<(usize, Token)>,
+	pos: usize,
+	end: usize,
+}
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.pos).map(|(_, t)| t)
+	}
+
+	fn offset(&self) -> usize {
+		self.tokens.get(self.pos).map(|(p, _)| *p).unwrap_or(self.end)
+	}
+
+	fn error<T>(&self, message: String) -> Result<T, ParseError> {
+		Err(ParseError { pos: self.offset(), message })
+	}
+
+	fn next(&mut self) -> Option<Token> {
+		let token = self.tokens.get(self.pos).map(|(_, t)| t.clone());
+		self.pos += 1;
+		token
+	}
+
+	fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+		match self.peek() {
+			Some(token) if *token == expected => {
+				self.pos += 1;
+				Ok(())
+			}
+			Some(token) => self.error(format!("expected '{}', found '{}'", expected, token)),
+			None => self.error(format!("expected '{}', found end of input", expected)),
+		}
+	}
+
+	fn ident(&mut self) -> Result<String, ParseError> {
+		match self.peek() {
+			Some(Token::Ident(name)) => {
+				let name = name.clone();
+				self.pos += 1;
+				Ok(name)
+			}
+			Some(token) => self.error(format!("expected identifier, found '{}'", token)),
+			None => self.error("expected identifier, found end of input".to_string()),
+		}
+	}
+
+	fn keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+		self.expect(Token::Ident(keyword.to_string()))
+	}
+
+	fn eat_keyword(&mut self, keyword: &str) -> bool {
+		if self.peek() == Some(&Token::Ident(keyword.to_string())) {
+			self.pos += 1;
+			return true;
+		}
+		false
+	}
+
+	// true for box, false for diamond
+	fn modality(&mut self) -> Result<bool, ParseError> {
+		match self.ident()?.as_str() {
+			"box" => Ok(true),
+			"diamond" => Ok(false),
+			other => {
+				self.pos -= 1;
+				self.error(format!("expected 'box' or 'diamond', found '{}'", other))
+			}
+		}
+	}
+
+	// time reference with an optional offset, delta in milliseconds
+	fn time(&mut self) -> Result<(String, f64), ParseError> {
+		let t = self.ident()?;
+		let sign = match self.peek() {
+			Some(Token::Plus) => 1.0,
+			Some(Token::Minus) => -1.0,
+			_ => return Ok((t, 0.0)),
+		};
+		self.pos += 1;
+		let n = match self.next() {
+			Some(Token::Number(n)) => n,
+			_ => {
+				self.pos -= 1;
+				return self.error("expected a number after the offset sign".to_string());
+			}
+		};
+		let unit = self.ident()?;
+		let scale = match unit_ms(&unit) {
+			Some(scale) => scale,
+			None => {
+				self.pos -= 1;
+				return self.error(format!("unknown time unit '{}'", unit));
+			}
+		};
+		Ok((t, sign * n * scale))
+	}
+
+	fn atom(&mut self) -> Result<(TelOperator, String, String, f64), ParseError> {
+		let before_t = self.peek() != Some(&Token::LParen);
+		let (is_box, negated, event, (t, delta)) = if before_t {
+			let is_box = self.modality()?;
+			self.expect(Token::LBracket)?;
+			let time = self.time()?;
+			self.expect(Token::RBracket)?;
+			let negated = self.eat_keyword("not");
+			(is_box, negated, self.ident()?, time)
+		} else {
+			self.expect(Token::LParen)?;
+			let is_box = self.modality()?;
+			let negated = self.eat_keyword("not");
+			let event = self.ident()?;
+			self.expect(Token::RParen)?;
+			self.expect(Token::LBracket)?;
+			let time = self.time()?;
+			self.expect(Token::RBracket)?;
+			(is_box, negated, event, time)
+		};
+		let operator = match (is_box, before_t, negated) {
+			(true, true, false) => TelOperator::BoxTPhi,
+			(true, true, true) => TelOperator::BoxTNegPhi,
+			(true, false, false) => TelOperator::BoxPhiT,
+			(true, false, true) => TelOperator::BoxNegPhiT,
+			(false, true, false) => TelOperator::DiamondTPhi,
+			(false, true, true) => TelOperator::DiamondTNegPhi,
+			(false, false, false) => TelOperator::DiamondPhiT,
+			(false, false, true) => TelOperator::DiamondNegPhiT,
+		};
+		Ok((operator, t, event, delta))
+	}
+
+	// the span of every expression covers all event groups of the formula
+	fn formula(&mut self) -> Result<TelFormula, ParseError> {
+		self.keyword("exists")?;
+		let mut bindings: Vec<(String, String)> = Vec::new();
+		loop {
+			let t = self.ident()?;
+			self.keyword("in")?;
+			let event = self.ident()?;
+			if bindings.iter().any(|(bound, _)| *bound == t) {
+				return self.error(format!("time variable '{}' is bound twice", t));
+			}
+			bindings.push((t, event));
+			if self.peek() != Some(&Token::Comma) {
+				break;
+			}
+			self.pos += 1;
+		}
+		self.expect(Token::Colon)?;
+
+		let mut atoms = Vec::new();
+		loop {
+			let offset = self.offset();
+			let atom = self.atom()?;
+			if !bindings.iter().any(|(bound, _)| *bound == atom.1) {
+				return Err(ParseError { pos: offset, message: format!("time variable '{}' is not bound", atom.1) });
+			}
+			atoms.push(atom);
+			if !self.eat_keyword("and") {
+				break;
+			}
+		}
+
+		// event groups in order of first appearance
+		let mut events: Vec<&str> = Vec::new();
+		for name in bindings.iter().map(|(_, e)| e.as_str()).chain(atoms.iter().map(|a| a.2.as_str())) {
+			if !events.contains(&name) {
+				events.push(name);
+			}
+		}
+		let exps = atoms.iter()
+			.map(|(operator, t, event, delta)| TelExp::init(*operator, t, event, Some(events.clone()), Some(*delta), None, None))
+			.collect();
+		let bindings = bindings.iter()
+			.map(|(t, e)| (TimeRef::new(t), EventRef::new(e)))
+			.collect();
+		Ok(TelFormula { bindings, exps })
+	}
+
+	fn query(&mut self) -> Result<TelQuery, ParseError> {
+		let mut terms = vec![self.conj()?];
+		while self.eat_keyword("or") {
+			terms.push(self.conj()?);
+		}
+		Ok(if terms.len() == 1 { terms.remove(0) } else { TelQuery::Or(terms) })
+	}
+
+	fn conj(&mut self) -> Result<TelQuery, ParseError> {
+		let mut terms = vec![self.unary()?];
+		while self.eat_keyword("and") {
+			terms.push(self.unary()?);
+		}
+		Ok(if terms.len() == 1 { terms.remove(0) } else { TelQuery::And(terms) })
+	}
+
+	fn unary(&mut self) -> Result<TelQuery, ParseError> {
+		if self.eat_keyword("not") {
+			return Ok(TelQuery::Not(Box::new(self.unary()?)));
+		}
+		match self.peek() {
+			Some(Token::LParen) => {
+				self.pos += 1;
+				let query = self.query()?;
+				self.expect(Token::RParen)?;
+				Ok(query)
+			}
+			Some(Token::LBrace) => {
+				self.pos += 1;
+				let formula = self.formula()?;
+				self.expect(Token::RBrace)?;
+				Ok(TelQuery::Formula(formula))
+			}
+			_ => {
+				let a = self.ident()?;
+				if self.eat_keyword("without") {
+					return self.absence(&a);
+				}
+				let offset = self.offset();
+				let mut name = self.ident()?;
+				while self.peek() == Some(&Token::Minus) {
+					self.pos += 1;
+					name = format!("{}-{}", name, self.ident()?);
+				}
+				let relation = match AllenRelation::parse(&name) {
+					Some(relation) => relation,
+					None => return Err(ParseError { pos: offset, message: format!("unknown relation '{}'", name) }),
+				};
+				let b = self.ident()?;
+				Ok(TelQuery::Formula(TelFormula::relation(relation, &a, &b)))
+			}
+		}
+	}
+
+	fn absence(&mut self, a: &str) -> Result<TelQuery, ParseError> {
+		let b = self.ident()?;
+		let mut absence = Absence::new(a, &b, None);
+		if self.eat_keyword("within") {
+			let n = match self.next() {
+				Some(Token::Number(n)) => n,
+				_ => {
+					self.pos -= 1;
+					return self.error("expected a number after 'within'".to_string());
+				}
+			};
+			let unit = self.ident()?;
+			match unit_ms(&unit) {
+				Some(scale) => absence.window = Some(n * scale),
+				None => {
+					self.pos -= 1;
+					return self.error(format!("unknown time unit '{}'", unit));
+				}
+			}
+		}
+		if self.eat_keyword("after") {
+			let anchor = self.ident()?;
+			match Anchor::parse(&anchor) {
+				Some(from) => absence.from = from,
+				None => {
+					self.pos -= 1;
+					return self.error(format!("expected 'start' or 'end', found '{}'", anchor));
+				}
+			}
+		}
+		Ok(TelQuery::Absence(absence))
+	}
+
+	fn eat_word(&mut self, word: &str) -> bool {
+		if matches!(self.peek(), Some(Token::Ident(name)) if name.eq_ignore_ascii_case(word)) {
+			self.pos += 1;
+			return true;
+		}
+		false
+	}
+
+	// a non negative integer, an event id or the k of atleast
+	fn integer(&mut self) -> Result<u32, ParseError> {
+		match self.peek() {
+			Some(Token::Number(n)) if n.fract() == 0.0 && *n <= f64::from(i32::MAX) => {
+				let n = *n as u32;
+				self.pos += 1;
+				Ok(n)
+			}
+			Some(token) => self.error(format!("expected an integer, found '{}'", token)),
+			None => self.error("expected an integer, found end of input".to_string()),
+		}
+	}
+
+	fn elii(&mut self) -> Result<EliiExpr, ParseError> {
+		let mut terms = vec![self.elii_conj()?];
+		while self.eat_word("or") {
+			terms.push(self.elii_conj()?);
+		}
+		Ok(if terms.len() == 1 { terms.remove(0) } else { EliiExpr::Or(terms) })
+	}
+
+	fn elii_conj(&mut self) -> Result<EliiExpr, ParseError> {
+		let mut terms = vec![self.elii_unary()?];
+		while self.eat_word("and") {
+			terms.push(self.elii_unary()?);
+		}
+		Ok(if terms.len() == 1 { terms.remove(0) } else { EliiExpr::And(terms) })
+	}
+
+	fn elii_unary(&mut self) -> Result<EliiExpr, ParseError> {
+		if self.eat_word("not") {
+			return Ok(EliiExpr::Not(Box::new(self.elii_unary()?)));
+		}
+		if self.eat_word("atleast") {
+			self.expect(Token::LParen)?;
+			let k = self.integer()? as usize;
+			self.expect(Token::Comma)?;
+			let exprs = self.elii_list()?;
+			self.expect(Token::RParen)?;
+			return Ok(EliiExpr::AtLeast(k, exprs));
+		}
+		match self.peek() {
+			Some(Token::LParen) => {
+				self.pos += 1;
+				let expr = self.elii()?;
+				self.expect(Token::RParen)?;
+				Ok(expr)
+			}
+			Some(Token::LBracket) => Ok(EliiExpr::Or(self.elii_list()?)),
+			Some(Token::Number(_)) => Ok(EliiExpr::Event(self.integer()? as i32)),
+			_ => Ok(EliiExpr::Group(self.ident()?)),
+		}
+	}
+
+	fn elii_list(&mut self) -> Result<Vec<EliiExpr>, ParseError> {
+		self.expect(Token::LBracket)?;
+		let mut exprs = vec![self.elii()?];
+		while self.peek() == Some(&Token::Comma) {
+			self.pos += 1;
+			exprs.push(self.elii()?);
+		}
+		self.expect(Token::RBracket)?;
+		Ok(exprs)
+	}
+
+	fn finish(&self) -> Result<(), ParseError> {
+		match self.peek() {
+			Some(token) => self.error(format!("unexpected '{}' after the input", token)),
+			None => Ok(()),
+		}
+	}
+}
+
+fn parser(input: &str) -> Result<Parser, ParseError> {
+	Ok(Parser { tokens: tokenize(input)?, pos: 0, end: input.chars().count() })
+}
+
+// parse a textual formula into time bindings and TEL expressions
+pub fn parse_formula(input: &str) -> Result<TelFormula, ParseError> {
+	let mut parser = parser(input)?;
+	let formula = parser.formula()?;
+	parser.finish()?;
+	Ok(formula)
+}
+
+// parse a boolean combination of formulas and Allen relations
+pub fn parse_query(input: &str) -> Result<TelQuery, ParseError> {
+	let mut parser = parser(input)?;
+	let query = parser.query()?;
+	parser.finish()?;
+	Ok(query)
+}
+
+
+// parse a boolean expression over events for elii
+pub fn parse_elii(input: &str) -> Result<EliiExpr, ParseError> {
+	let mut parser = parser(input)?;
+	let expr = parser.elii()?;
+	parser.finish()?;
+	Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	type Parse<T> = fn(&str) -> Result<T, ParseError>;
+
+	// the error of parsing input, with its position given as the substring it points at
+	fn error_at<T: fmt::Debug>(parse: Parse<T>, input: &str) -> (String, usize) {
+		let error = parse(input).unwrap_err();
+		(error.message, error.pos)
+	}
+
+	#[test]
+	fn parses_the_example_formula() {
+		let formula = parse_formula("exists t in e1: box[t] e1 and box[t] not e2 and (diamond not e2)[t+60s]").unwrap();
+		let events = Some(vec!["e1", "e2"]);
+		let exp = |operator: TelOperator, event: &str, delta: f64| TelExp::init(operator, "t", event, events.clone(), Some(delta), None, None);
+		assert_eq!(formula.bindings, vec![(TimeRef::new("t"), EventRef::new("e1"))]);
+		assert_eq!(formula.exps, vec![
+			exp(TelOperator::BoxTPhi, "e1", 0.0),
+			exp(TelOperator::BoxTNegPhi, "e2", 0.0),
+			exp(TelOperator::DiamondNegPhiT, "e2", 60.0 * 1000.0),
+		]);
+	}
+
+	#[test]
+	fn reports_errors_at_their_position() {
+		let cases = [
+			("exists t in e1: box[t] e1 & e2", "&", "unexpected character '&'"),
+			("exists t in e1: box[t+1.2.3s] e1", "1.2.3", "invalid number '1.2.3'"),
+			("exists t in e1 box[t] e1", "box", "expected ':', found 'box'"),
+			("exists t in e1: cube[t] e1", "cube", "expected 'box' or 'diamond', found 'cube'"),
+			("exists t in e1: box[t+s] e1", "s]", "expected a number after the offset sign"),
+			("exists t in e1: box[t+5y] e1", "y", "unknown time unit 'y'"),
+			("exists t in e1, t in e2: box[t] e1", ":", "time variable 't' is bound twice"),
+			("exists t in e1: box[u] e1", "box", "time variable 'u' is not bound"),
+			("exists t in e1: box[t] e1 e2", "e2", "unexpected 'e2' after the input"),
+		];
+		for (input, at, message) in cases {
+			assert_eq!(error_at(parse_formula, input), (message.to_string(), input.find(at).unwrap()), "{}", input);
+		}
+		let input = "exists t in e1: box[t] e1 and";
+		assert_eq!(error_at(parse_formula, input), ("expected identifier, found end of input".to_string(), input.len()));
+		assert_eq!(error_at(parse_query, "e1 sometime e2"), ("unknown relation 'sometime'".to_string(), 3));
+		assert_eq!(error_at(parse_query, "e1 without e2 within 3 weeks"), ("unknown time unit 'weeks'".to_string(), 23));
+		assert_eq!(error_at(parse_elii, "atleast(1.5, [1, 2])"), ("expected an integer, found '1.5'".to_string(), 8));
+	}
+
+	#[test]
+	fn parses_durations_in_every_unit() {
+		assert_eq!(parse_duration("5ms"), Some(5.0));
+		assert_eq!(parse_duration("5s"), Some(5000.0));
+		assert_eq!(parse_duration("-10min"), Some(-600_000.0));
+		assert_eq!(parse_duration("1.5h"), Some(5_400_000.0));
+		assert_eq!(parse_duration(" 2 d "), Some(172_800_000.0));
+		assert_eq!(parse_duration("5y"), None);
+		assert_eq!(parse_duration("5"), None);
+		assert_eq!(parse_duration("s"), None);
+	}
+
+	#[test]
+	fn tel_keywords_are_case_sensitive_and_elii_words_are_not() {
+		assert_eq!(error_at(parse_formula, "EXISTS t in e1: box[t] e1"), ("expected 'exists', found 'EXISTS'".to_string(), 0));
+		assert_eq!(error_at(parse_formula, "exists t in e1: BOX[t] e1"), ("expected 'box' or 'diamond', found 'BOX'".to_string(), 16));
+		assert_eq!(error_at(parse_formula, "exists t in e1: (Diamond e1)[t]"), ("expected 'box' or 'diamond', found 'Diamond'".to_string(), 17));
+		assert_eq!(error_at(parse_formula, "exists t in e1: box[t] e1 AND box[t] e2"), ("unexpected 'AND' after the input".to_string(), 26));
+		assert_eq!(error_at(parse_query, "e1 before e2 OR e2 meets e1"), ("unexpected 'OR' after the input".to_string(), 13));
+		assert_eq!(parse_elii("250 and NOT 300 Or AtLeast(1, [10])").unwrap(), parse_elii("250 AND not 300 OR atleast(1, [10])").unwrap());
+		assert_eq!(parse_elii("NOT 300").unwrap(), EliiExpr::Not(Box::new(EliiExpr::Event(300))));
+	}
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use crate::tel::allen::{AllenRelation, Anchor};
+use crate::tel::exp::{EventRef, TelExp, TimeRef};
+
+// parsed formula: time variable bindings and the conjunction of expressions
+#[derive(Debug, Clone)]
+pub struct TelFormula {
+	pub bindings: Vec<(TimeRef, EventRef)>,
+	pub exps: Vec<TelExp>,
+}
+
+impl TelFormula {
+	// "a <relation> b" as a formula with time variable t
+	pub fn relation(relation: AllenRelation, a: &str, b: &str) -> Self {
+		let (group, exps) = relation.encode("t", a, b);
+		TelFormula { bindings: vec![(TimeRef::new("t"), EventRef::new(group))], exps }
+	}
+
+	// bindings in the form expected by construct_query
+	pub fn ts(&self) -> HashMap<&str, &str> {
+		self.bindings.iter().map(|(t, e)| (t.as_str(), e.as_str())).collect()
+	}
+
+	// event groups the formula refers to, in order of first appearance
+	pub fn groups(&self) -> Vec<&str> {
+		let mut groups: Vec<&str> = Vec::new();
+		let referenced = self.bindings.iter().map(|(_, e)| e)
+			.chain(self.exps.iter().flat_map(|exp| std::iter::once(&exp.event).chain(exp.events.iter())));
+		for group in referenced {
+			if !groups.contains(&group.as_str()) {
+				groups.push(group.as_str());
+			}
+		}
+		groups
+	}
+}
+
+// Absence "a without b": some occurrence of a is not followed by any occurrence of b, that is
+// no b starts at or after the from anchor of that a, up to window milliseconds after it if bounded.
+// Unlike a formula it holds for subjects without any b.
+#[derive(Debug, Clone)]
+pub struct Absence {
+	pub a: EventRef,
+	pub from: Anchor,
+	pub b: EventRef,
+	pub window: Option<f64>,
+}
+
+impl Absence {
+	pub fn new(a: &str, b: &str, window: Option<f64>) -> Self {
+		Absence { a: EventRef::new(a), from: Anchor::End, b: EventRef::new(b), window }
+	}
+
+	// true if an occurrence of b starting at `start` follows the occurrence (min, max) of a
+	pub fn follows(&self, (min, max): (f64, f64), start: f64) -> bool {
+		let anchor = match self.from {
+			Anchor::Start => min,
+			Anchor::End => max,
+		};
+		start >= anchor && self.window.is_none_or(|window| start <= anchor + window)
+	}
+}
+
+// Boolean combination of formulas, evaluated per subject: a formula holds for a subject
+// if some choice of its intervals satisfies it, and and/or/not combine these per-subject values.
+#[derive(Debug, Clone)]
+pub enum TelQuery {
+	Formula(TelFormula),
+	Absence(Absence),
+	And(Vec<TelQuery>),
+	Or(Vec<TelQuery>),
+	Not(Box<TelQuery>),
+}
+
+impl TelQuery {
+	pub fn formulas(&self) -> Vec<&TelFormula> {
+		match self {
+			TelQuery::Formula(formula) => vec![formula],
+			TelQuery::Absence(_) => vec![],
+			TelQuery::And(queries) | TelQuery::Or(queries) => queries.iter().flat_map(|q| q.formulas()).collect(),
+			TelQuery::Not(query) => query.formulas(),
+		}
+	}
+
+	// event groups the query refers to, in order of first appearance
+	pub fn groups(&self) -> Vec<&str> {
+		let mut groups: Vec<&str> = Vec::new();
+		let referenced = match self {
+			TelQuery::Formula(formula) => formula.groups(),
+			TelQuery::Absence(absence) => vec![absence.a.as_str(), absence.b.as_str()],
+			TelQuery::And(queries) | TelQuery::Or(queries) => queries.iter().flat_map(|q| q.groups()).collect(),
+			TelQuery::Not(query) => query.groups(),
+		};
+		for group in referenced {
+			if !groups.contains(&group) {
+				groups.push(group);
+			}
+		}
+		groups
+	}
+}